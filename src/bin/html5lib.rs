@@ -0,0 +1,724 @@
+extern crate browser_engine;
+
+use browser_engine::dom;
+use browser_engine::html;
+use browser_engine::html::tokenizer::{self, Token, Tokenizer};
+use browser_engine::parser::{ParseError, ParseErrorKind, Parser};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Runs the html5lib-tests fixtures against the HTML parsers and reports how many pass
+// https://github.com/html5lib/html5lib-tests
+//
+// Usage: html5lib [--simple] [--verbose] <directory>
+//
+// Every `.dat` file under the directory is run as a tree construction test, and every `.test`
+// file as a tokenizer test. Tree construction tests use the WHATWG parser unless `--simple` is
+// given.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+	Passed,
+	Failed,
+	// The test needs a feature the parsers don't have, like scripting
+	Skipped,
+}
+
+#[derive(Default)]
+struct Counts {
+	passed: usize,
+	failed: usize,
+	skipped: usize,
+}
+
+impl Counts {
+	fn record(&mut self, outcome: Outcome) {
+		match outcome {
+			Outcome::Passed => self.passed += 1,
+			Outcome::Failed => self.failed += 1,
+			Outcome::Skipped => self.skipped += 1,
+		}
+	}
+
+	fn add(&mut self, other: &Counts) {
+		self.passed += other.passed;
+		self.failed += other.failed;
+		self.skipped += other.skipped;
+	}
+}
+
+struct Options {
+	simple: bool,
+	verbose: bool,
+}
+
+fn main() {
+	let mut options = Options {
+		simple: false,
+		verbose: false,
+	};
+	let mut directory = None;
+	for arg in env::args().skip(1) {
+		match arg.as_str() {
+			"--simple" => options.simple = true,
+			"--verbose" => options.verbose = true,
+			_ => directory = Some(PathBuf::from(arg)),
+		}
+	}
+	let directory = match directory {
+		Some(directory) => directory,
+		None => {
+			eprintln!("Usage: html5lib [--simple] [--verbose] <directory>");
+			process::exit(2);
+		}
+	};
+
+	let mut files = Vec::new();
+	if let Err(err) = find_fixtures(&directory, &mut files) {
+		eprintln!("Couldn't read {}: {}", directory.display(), err);
+		process::exit(2);
+	}
+	files.sort();
+
+	let mut total = Counts::default();
+	for file in &files {
+		let source = match fs::read_to_string(file) {
+			Ok(source) => source,
+			Err(err) => {
+				eprintln!("Couldn't read {}: {}", file.display(), err);
+				continue;
+			}
+		};
+		let counts = if file.extension().is_some_and(|ext| ext == "dat") {
+			run_tree_construction_file(&source, &options)
+		} else {
+			match run_tokenizer_file(&source, &options) {
+				Ok(counts) => counts,
+				Err(err) => {
+					eprintln!("Couldn't parse {}: {}", file.display(), err);
+					continue;
+				}
+			}
+		};
+		println!(
+			"{}: {} passed, {} failed, {} skipped",
+			file.strip_prefix(&directory).unwrap_or(file).display(),
+			counts.passed,
+			counts.failed,
+			counts.skipped
+		);
+		total.add(&counts);
+	}
+	println!(
+		"Total: {} passed, {} failed, {} skipped",
+		total.passed, total.failed, total.skipped
+	);
+}
+
+fn find_fixtures(directory: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+	for entry in fs::read_dir(directory)? {
+		let path = entry?.path();
+		if path.is_dir() {
+			find_fixtures(&path, files)?;
+		} else if path
+			.extension()
+			.is_some_and(|ext| ext == "dat" || ext == "test")
+		{
+			files.push(path);
+		}
+	}
+	Ok(())
+}
+
+// Tree construction tests
+
+#[derive(Debug, Default, PartialEq)]
+struct TreeTest {
+	data: String,
+	fragment_context: Option<String>,
+	scripting: Option<bool>,
+	document: String,
+}
+
+// Split a `.dat` file into its tests. Each test is a series of `#section` lines, each followed by
+// the section's contents, and tests are separated by a blank line.
+fn parse_dat(source: &str) -> Vec<TreeTest> {
+	let mut tests = Vec::new();
+	let mut test = TreeTest::default();
+	let mut section = String::new();
+	let mut lines: Vec<&str> = Vec::new();
+
+	let all_lines: Vec<&str> = source.lines().collect();
+	for (i, &line) in all_lines.iter().enumerate() {
+		let starts_test = line == "#data" && (i == 0 || all_lines[i - 1].is_empty());
+		if starts_test && i > 0 {
+			// The blank line before `#data` separates the tests
+			lines.pop();
+			finish_section(&mut test, &section, &mut lines);
+			tests.push(test);
+			test = TreeTest::default();
+		}
+		if starts_test || is_section(line) {
+			finish_section(&mut test, &section, &mut lines);
+			section = line.to_string();
+			match line {
+				"#script-on" => test.scripting = Some(true),
+				"#script-off" => test.scripting = Some(false),
+				_ => {}
+			}
+			continue;
+		}
+		lines.push(line);
+	}
+	if !section.is_empty() {
+		while lines.last() == Some(&"") {
+			lines.pop();
+		}
+		finish_section(&mut test, &section, &mut lines);
+		tests.push(test);
+	}
+	tests
+}
+
+fn finish_section(test: &mut TreeTest, section: &str, lines: &mut Vec<&str>) {
+	let contents = lines.join("\n");
+	match section {
+		"#data" => test.data = contents,
+		"#document-fragment" => test.fragment_context = Some(contents),
+		"#document" => test.document = contents,
+		_ => {}
+	}
+	lines.clear();
+}
+
+fn is_section(line: &str) -> bool {
+	matches!(
+		line,
+		"#errors"
+			| "#new-errors"
+			| "#document-fragment"
+			| "#script-on"
+			| "#script-off"
+			| "#document"
+	)
+}
+
+fn run_tree_construction_file(source: &str, options: &Options) -> Counts {
+	let mut counts = Counts::default();
+	for test in parse_dat(source) {
+		let outcome = run_tree_construction_test(&test, options);
+		counts.record(outcome);
+	}
+	counts
+}
+
+fn run_tree_construction_test(test: &TreeTest, options: &Options) -> Outcome {
+	// Scripts are never run, so the parsers always behave as if scripting is disabled
+	if test.scripting == Some(true) {
+		return Outcome::Skipped;
+	}
+
+	let actual = if let Some(ref context) = test.fragment_context {
+		// Only the WHATWG parser knows how to parse fragments
+		if options.simple {
+			return Outcome::Skipped;
+		}
+		// Contexts in other namespaces look like `svg path`
+		let (document, fragment) = match context.split_once(' ') {
+			Some(("svg", name)) => {
+				html::parse_fragment_ns(test.data.clone(), dom::SVG_NAMESPACE, name)
+			}
+			Some(("math", name)) => {
+				html::parse_fragment_ns(test.data.clone(), dom::MATHML_NAMESPACE, name)
+			}
+			_ => html::parse_fragment(test.data.clone(), context),
+		};
+		dump_nodes(&document, fragment)
+	} else if options.simple {
+		match html::parse_document(test.data.clone()) {
+			Ok((document, _)) => dump_document(&document),
+			Err(err) => format!("error: {}", err),
+		}
+	} else {
+		dump_document(&html::parse_whatwg(test.data.clone()).0)
+	};
+	if actual == test.document {
+		return Outcome::Passed;
+	}
+	if options.verbose {
+		println!(
+			"FAILED\n#data\n{}\n#expected\n{}\n#actual\n{}\n",
+			test.data, test.document, actual
+		);
+	}
+	Outcome::Failed
+}
+
+// Write out a document in the format the tree construction tests use
+fn dump_document(document: &dom::Document) -> String {
+	dump_nodes(document, document.document_node())
+}
+
+// Write out the children of a node
+fn dump_nodes(document: &dom::Document, parent: dom::NodeId) -> String {
+	let mut lines = Vec::new();
+	dump_children(document, parent, 0, &mut lines);
+	lines.join("\n")
+}
+
+// The tests have no CDATA sections, only text, so runs of text and CDATA are written as one node
+fn dump_children(
+	document: &dom::Document,
+	parent: dom::NodeId,
+	depth: usize,
+	lines: &mut Vec<String>,
+) {
+	let mut text: Option<String> = None;
+	for id in document.children(parent) {
+		match document.node(id).node_type {
+			dom::NodeType::Text(ref data) | dom::NodeType::CData(ref data) => {
+				text.get_or_insert_with(String::new).push_str(data);
+				continue;
+			}
+			_ => {}
+		}
+		if let Some(text) = text.take() {
+			lines.push(format!("| {}\"{}\"", "  ".repeat(depth), text));
+		}
+		dump_node(document, id, depth, lines);
+	}
+	if let Some(text) = text {
+		lines.push(format!("| {}\"{}\"", "  ".repeat(depth), text));
+	}
+}
+
+fn dump_node(document: &dom::Document, id: dom::NodeId, depth: usize, lines: &mut Vec<String>) {
+	let indent = "  ".repeat(depth);
+	match document.node(id).node_type {
+		dom::NodeType::Element(ref data) => {
+			match data.namespace.as_deref().and_then(prefix) {
+				Some(prefix) => lines.push(format!("| {}<{} {}>", indent, prefix, data.tag_name)),
+				None => lines.push(format!("| {}<{}>", indent, data.tag_name)),
+			}
+
+			// Namespaced attributes are written like `xlink href`
+			let mut attributes: Vec<_> = data
+				.attributes
+				.iter()
+				.map(
+					|(name, value)| match data.attribute_namespace(name).and_then(prefix) {
+						Some(prefix) => {
+							let local_name = name.split(':').next_back().unwrap_or(name);
+							(format!("{} {}", prefix, local_name), value)
+						}
+						None => (name.clone(), value),
+					},
+				)
+				.collect();
+			attributes.sort();
+			for (name, value) in attributes {
+				lines.push(format!("| {}  {}=\"{}\"", indent, name, value));
+			}
+			if let Some(contents) = document.template_contents(id) {
+				dump_node(document, contents, depth + 1, lines);
+			}
+		}
+		dom::NodeType::DocumentFragment => lines.push(format!("| {}content", indent)),
+		dom::NodeType::Document => unreachable!("documents are written by dump_document"),
+		dom::NodeType::Text(ref text) | dom::NodeType::CData(ref text) => {
+			lines.push(format!("| {}\"{}\"", indent, text))
+		}
+		dom::NodeType::ProcessingInstruction {
+			ref target,
+			ref data,
+		} => lines.push(format!("| {}<?{} {}>", indent, target, data)),
+		dom::NodeType::Comment(ref comment) => {
+			lines.push(format!("| {}<!-- {} -->", indent, comment))
+		}
+		dom::NodeType::Doctype(ref doctype) => {
+			if doctype.public_id.is_some() || doctype.system_id.is_some() {
+				lines.push(format!(
+					"| {}<!DOCTYPE {} \"{}\" \"{}\">",
+					indent,
+					doctype.name,
+					doctype.public_id.as_deref().unwrap_or(""),
+					doctype.system_id.as_deref().unwrap_or("")
+				));
+			} else {
+				lines.push(format!("| {}<!DOCTYPE {}>", indent, doctype.name));
+			}
+		}
+	}
+	dump_children(document, id, depth + 1, lines);
+}
+
+// How the tests write each namespace, which is nothing for HTML
+fn prefix(namespace: &str) -> Option<&'static str> {
+	match namespace {
+		dom::SVG_NAMESPACE => Some("svg"),
+		dom::MATHML_NAMESPACE => Some("math"),
+		dom::XLINK_NAMESPACE => Some("xlink"),
+		dom::XML_NAMESPACE => Some("xml"),
+		dom::XMLNS_NAMESPACE => Some("xmlns"),
+		_ => None,
+	}
+}
+
+// Tokenizer tests
+
+#[derive(Clone, Debug, PartialEq)]
+enum Json {
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<Json>),
+	Object(Vec<(String, Json)>),
+}
+
+impl Json {
+	fn get(&self, key: &str) -> Option<&Json> {
+		match *self {
+			Json::Object(ref members) => members
+				.iter()
+				.find(|(name, _)| name == key)
+				.map(|(_, value)| value),
+			_ => None,
+		}
+	}
+
+	fn as_str(&self) -> Option<&str> {
+		match *self {
+			Json::String(ref s) => Some(s),
+			_ => None,
+		}
+	}
+
+	fn as_array(&self) -> &[Json] {
+		match *self {
+			Json::Array(ref items) => items,
+			_ => &[],
+		}
+	}
+}
+
+// Just enough JSON to read the tokenizer tests
+fn parse_json(parser: &mut Parser) -> Result<Json, ParseError> {
+	parser.consume_whitespace();
+	let value = match parser.peek() {
+		Some('{') => {
+			parser.consume_char();
+			let mut members = Vec::new();
+			parser.consume_whitespace();
+			if parser.peek() == Some('}') {
+				parser.consume_char();
+				return Ok(Json::Object(members));
+			}
+			loop {
+				parser.consume_whitespace();
+				let name = parse_json_string(parser)?;
+				parser.consume_whitespace();
+				parser.expect_char(':')?;
+				members.push((name, parse_json(parser)?));
+				parser.consume_whitespace();
+				if parser.expect_char(',').is_err() {
+					parser.expect_char('}')?;
+					break;
+				}
+			}
+			Json::Object(members)
+		}
+		Some('[') => {
+			parser.consume_char();
+			let mut items = Vec::new();
+			parser.consume_whitespace();
+			if parser.peek() == Some(']') {
+				parser.consume_char();
+				return Ok(Json::Array(items));
+			}
+			loop {
+				items.push(parse_json(parser)?);
+				parser.consume_whitespace();
+				if parser.expect_char(',').is_err() {
+					parser.expect_char(']')?;
+					break;
+				}
+			}
+			Json::Array(items)
+		}
+		Some('"') => Json::String(parse_json_string(parser)?),
+		Some('t') => parser.consume_string("true").map(|_| Json::Bool(true))?,
+		Some('f') => parser.consume_string("false").map(|_| Json::Bool(false))?,
+		Some('n') => parser.consume_string("null").map(|_| Json::Null)?,
+		_ => {
+			let number = parser
+				.consume_while(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'));
+			match number.parse() {
+				Ok(number) => Json::Number(number),
+				Err(_) => return Err(parser.unexpected("JSON value")),
+			}
+		}
+	};
+	Ok(value)
+}
+
+fn parse_json_string(parser: &mut Parser) -> Result<String, ParseError> {
+	parser.expect_char('"')?;
+	let mut result = String::new();
+	loop {
+		if parser.eof() {
+			return Err(parser.unexpected("\""));
+		}
+		match parser.consume_char() {
+			'"' => return Ok(result),
+			'\\' => {
+				if parser.eof() {
+					return Err(parser.unexpected("escape"));
+				}
+				match parser.consume_char() {
+					'b' => result.push('\u{8}'),
+					'f' => result.push('\u{C}'),
+					'n' => result.push('\n'),
+					'r' => result.push('\r'),
+					't' => result.push('\t'),
+					'u' => {
+						let mut code = parse_json_hex(parser)?;
+						if (0xD800..0xDC00).contains(&code) && parser.starts_with("\\u") {
+							parser.consume_string("\\u")?;
+							let low = parse_json_hex(parser)?;
+							code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00));
+						}
+						result.push(unescaped_char(parser, code)?);
+					}
+					c => result.push(c),
+				}
+			}
+			c => result.push(c),
+		}
+	}
+}
+
+fn parse_json_hex(parser: &mut Parser) -> Result<u32, ParseError> {
+	let mut code = 0;
+	for _ in 0..4 {
+		match parser.peek().and_then(|c| c.to_digit(16)) {
+			Some(digit) => {
+				parser.consume_char();
+				code = code * 16 + digit;
+			}
+			None => return Err(parser.unexpected("hex digit")),
+		}
+	}
+	Ok(code)
+}
+
+// Lone surrogates can't be represented in a Rust string, so tests using them are skipped
+fn unescaped_char(parser: &Parser, code: u32) -> Result<char, ParseError> {
+	std::char::from_u32(code).ok_or_else(|| {
+		parser.error(
+			ParseErrorKind::InvalidNumber,
+			Some("unicode scalar value".to_string()),
+			Some(format!("{:X}", code)),
+		)
+	})
+}
+
+// Undo the extra level of `\uXXXX` escaping used by `doubleEscaped` tests
+fn unescape(s: &str) -> Option<String> {
+	let mut parser = Parser::new(format!("\"{}\"", s.replace('"', "\\\"")));
+	parse_json_string(&mut parser).ok()
+}
+
+fn unescape_json(value: &Json) -> Option<Json> {
+	Some(match *value {
+		Json::String(ref s) => Json::String(unescape(s)?),
+		Json::Array(ref items) => {
+			Json::Array(items.iter().map(unescape_json).collect::<Option<_>>()?)
+		}
+		Json::Object(ref members) => Json::Object(
+			members
+				.iter()
+				.map(|(name, value)| Some((unescape(name)?, unescape_json(value)?)))
+				.collect::<Option<_>>()?,
+		),
+		ref other => other.clone(),
+	})
+}
+
+fn run_tokenizer_file(source: &str, options: &Options) -> Result<Counts, ParseError> {
+	let mut parser = Parser::new(source.to_string());
+	let fixture = parse_json(&mut parser)?;
+	let mut counts = Counts::default();
+	let tests = fixture
+		.get("tests")
+		.or_else(|| fixture.get("xmlViolationTests"))
+		.map_or(&[][..], Json::as_array);
+	for test in tests {
+		counts.record(run_tokenizer_test(test, options));
+	}
+	Ok(counts)
+}
+
+fn run_tokenizer_test(test: &Json, options: &Options) -> Outcome {
+	let double_escaped = test.get("doubleEscaped") == Some(&Json::Bool(true));
+	let (input, expected) = match (test.get("input"), test.get("output")) {
+		(Some(input), Some(output)) if double_escaped => {
+			match (unescape_json(input), unescape_json(output)) {
+				(Some(input), Some(output)) => (input, output),
+				_ => return Outcome::Skipped,
+			}
+		}
+		(Some(input), Some(output)) => (input.clone(), output.clone()),
+		_ => return Outcome::Skipped,
+	};
+	let input = match input.as_str() {
+		Some(input) => input,
+		None => return Outcome::Skipped,
+	};
+	let last_start_tag = test
+		.get("lastStartTag")
+		.and_then(Json::as_str)
+		.map(str::to_string);
+	let states = match test.get("initialStates") {
+		Some(states) => states.as_array().iter().filter_map(Json::as_str).collect(),
+		None => vec!["Data state"],
+	};
+
+	let mut outcome = Outcome::Passed;
+	for state_name in states {
+		let state = match state_name {
+			"Data state" => tokenizer::State::Data,
+			"PLAINTEXT state" => tokenizer::State::Plaintext,
+			"RCDATA state" => tokenizer::State::Rcdata,
+			"RAWTEXT state" => tokenizer::State::Rawtext,
+			"Script data state" => tokenizer::State::ScriptData,
+			"CDATA section state" => tokenizer::State::CdataSection,
+			_ => return Outcome::Skipped,
+		};
+		let actual = tokenize(input, state, last_start_tag.clone());
+		if actual != expected {
+			if options.verbose {
+				println!(
+					"FAILED {:?} in {}\n#expected\n{:?}\n#actual\n{:?}\n",
+					input, state_name, expected, actual
+				);
+			}
+			outcome = Outcome::Failed;
+		}
+	}
+	outcome
+}
+
+// Tokenize the input into the JSON format the tests use, with adjacent characters combined
+fn tokenize(input: &str, state: tokenizer::State, last_start_tag: Option<String>) -> Json {
+	let mut tokenizer = Tokenizer::new(input);
+	tokenizer.state = state;
+	tokenizer.set_last_start_tag(last_start_tag);
+
+	let string = |s: &str| Json::String(s.to_string());
+	let optional = |s: Option<String>| s.map_or(Json::Null, Json::String);
+	let mut tokens = Vec::new();
+	let mut text = String::new();
+	loop {
+		let token = tokenizer.next_token();
+		match token {
+			Token::Character(c) => {
+				text.push(c);
+				continue;
+			}
+			Token::CData(ref data) => {
+				text.push_str(data);
+				continue;
+			}
+			_ => {}
+		}
+		if !text.is_empty() {
+			tokens.push(Json::Array(vec![string("Character"), string(&text)]));
+			text.clear();
+		}
+		tokens.push(Json::Array(match token {
+			Token::StartTag(tag) => {
+				let mut token = vec![
+					string("StartTag"),
+					Json::String(tag.name),
+					Json::Object(
+						tag.attributes
+							.into_iter()
+							.map(|(name, value)| (name, Json::String(value)))
+							.collect(),
+					),
+				];
+				if tag.self_closing {
+					token.push(Json::Bool(true));
+				}
+				token
+			}
+			Token::EndTag(tag) => vec![string("EndTag"), Json::String(tag.name)],
+			Token::Comment(comment) => vec![string("Comment"), Json::String(comment)],
+			Token::Doctype(doctype) => vec![
+				string("DOCTYPE"),
+				optional(doctype.name),
+				optional(doctype.public_id),
+				optional(doctype.system_id),
+				Json::Bool(!doctype.force_quirks),
+			],
+			Token::Character(_) | Token::CData(_) => unreachable!(),
+			Token::Eof => break,
+		}));
+	}
+	Json::Array(tokens)
+}
+
+#[cfg(test)]
+mod html5lib_tests {
+	use super::*;
+
+	#[test]
+	fn splits_dat_files_into_tests() {
+		let source = "#data\n<p>a\n\nb\n#errors\n(1,3): expected-doctype\n#document\n| <html>\n|   \"a\n\nb\"\n\n#data\nx\n#errors\n#document-fragment\ntd\n#document\n| \"x\"\n";
+		assert_eq!(
+			parse_dat(source),
+			vec![
+				TreeTest {
+					data: "<p>a\n\nb".into(),
+					fragment_context: None,
+					scripting: None,
+					document: "| <html>\n|   \"a\n\nb\"".into(),
+				},
+				TreeTest {
+					data: "x".into(),
+					fragment_context: Some("td".into()),
+					scripting: None,
+					document: "| \"x\"".into(),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn dumps_documents_like_html5lib() {
+		let (document, _) = html::parse_whatwg("<!DOCTYPE html><p class=a id=b>x<!--c-->".into());
+		assert_eq!(
+			dump_document(&document),
+			"| <!DOCTYPE html>\n| <html>\n|   <head>\n|   <body>\n|     <p>\n|       class=\"a\"\n|       id=\"b\"\n|       \"x\"\n|       <!-- c -->"
+		);
+	}
+
+	#[test]
+	fn runs_tokenizer_tests() {
+		let fixture = r#"{"tests": [
+			{"description": "tag", "input": "<a b='c'>d&amp;", "output": [["StartTag", "a", {"b": "c"}], ["Character", "d&"]]},
+			{"description": "wrong", "input": "x", "output": [["Character", "y"]]},
+			{"description": "escaped", "doubleEscaped": true, "input": "\\u0041", "output": [["Character", "\\u0041"]]},
+			{"description": "surrogate", "doubleEscaped": true, "input": "\\uD800", "output": []}
+		]}"#;
+		let options = Options {
+			simple: false,
+			verbose: false,
+		};
+		let counts = run_tokenizer_file(fixture, &options).unwrap();
+		assert_eq!((counts.passed, counts.failed, counts.skipped), (2, 1, 1));
+	}
+}
@@ -1,14 +1,24 @@
-use parser::Parser;
+use encoding::{self, Encoding};
+use parser::{ParseError, ParseErrorKind, Parser, Span};
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq)]
 pub struct Stylesheet {
 	pub rules: Vec<Rule>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct Rule {
 	pub selectors: Vec<Selector>,
 	pub declarations: Vec<Declaration>,
+	pub span: Option<Span>,
+}
+
+// Spans are source metadata, so two rules are equal if their contents are
+impl PartialEq for Rule {
+	fn eq(&self, other: &Rule) -> bool {
+		self.selectors == other.selectors && self.declarations == other.declarations
+	}
 }
 
 pub type Specificity = (usize, usize, usize);
@@ -32,15 +42,44 @@ impl Selector {
 #[derive(Debug, PartialEq)]
 pub struct SimpleSelector {
 	pub tag_name: Option<String>,
+	pub namespace: NamespaceConstraint,
 	pub id: Option<String>,
 	pub class: Vec<String>,
 	pub universal: bool,
 }
 
+// Which namespaces a selector's elements can be in, set with a prefix like `svg|rect`
+// https://drafts.csswg.org/selectors/#type-nmsp
 #[derive(Debug, PartialEq)]
+pub enum NamespaceConstraint {
+	// `*|rect`, or no prefix when there's no default namespace
+	Any,
+	// `|rect`, for elements which aren't in a namespace
+	NoNamespace,
+	Namespace(String),
+}
+
+impl NamespaceConstraint {
+	pub fn matches(&self, namespace: Option<&str>) -> bool {
+		match *self {
+			NamespaceConstraint::Any => true,
+			NamespaceConstraint::NoNamespace => namespace.is_none(),
+			NamespaceConstraint::Namespace(ref uri) => namespace == Some(uri.as_str()),
+		}
+	}
+}
+
+#[derive(Debug)]
 pub struct Declaration {
 	pub name: String,
 	pub value: Value,
+	pub span: Option<Span>,
+}
+
+impl PartialEq for Declaration {
+	fn eq(&self, other: &Declaration) -> bool {
+		self.name == other.name && self.value == other.value
+	}
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -65,12 +104,18 @@ pub struct Color {
 
 struct CssParser {
 	inner: Parser,
+
+	// Namespace prefixes declared with `@namespace`, and the default namespace if one was declared
+	namespaces: HashMap<String, String>,
+	default_namespace: Option<String>,
 }
 
 impl CssParser {
 	fn new(input: String) -> CssParser {
 		CssParser {
 			inner: Parser::new(input),
+			namespaces: HashMap::new(),
+			default_namespace: None,
 		}
 	}
 
@@ -82,7 +127,7 @@ impl CssParser {
 	// Tells us if the next char can be used to start a selector
 	fn can_start_simple_selector(&self) -> bool {
 		let next = self.inner.next_char();
-		next == '#' || next == '.' || next == '*' || is_valid_identifier_char(next)
+		next == '#' || next == '.' || next == '*' || next == '|' || is_valid_identifier_char(next)
 	}
 
 	// Parse an identifier
@@ -90,10 +135,103 @@ impl CssParser {
 		self.inner.consume_while(is_valid_identifier_char)
 	}
 
+	// Parse a quoted string, returning its contents
+	fn parse_string(&mut self) -> Result<String, ParseError> {
+		let quote = self.inner.consume_char();
+		let value = self.inner.consume_while(|c| c != quote);
+		self.inner.expect_char(quote)?;
+		Ok(value)
+	}
+
+	// Parse a URL, either as a string or as `url(...)`
+	fn parse_url(&mut self) -> Result<String, ParseError> {
+		match self.inner.peek() {
+			Some('"') | Some('\'') => self.parse_string(),
+			_ => {
+				self.inner.consume_string("url(")?;
+				self.inner.consume_whitespace();
+				let url = match self.inner.peek() {
+					Some('"') | Some('\'') => self.parse_string()?,
+					_ => self
+						.inner
+						.consume_while(|c| c != ')' && !c.is_ascii_whitespace()),
+				};
+				self.inner.consume_whitespace();
+				self.inner.expect_char(')')?;
+				Ok(url)
+			}
+		}
+	}
+
+	// Parse an `@namespace` rule, such as `@namespace svg url(http://www.w3.org/2000/svg);`. Without
+	// a prefix, it sets the default namespace for the selectors that follow
+	// https://drafts.csswg.org/css-namespaces/#declaration
+	fn parse_namespace_rule(&mut self) -> Result<(), ParseError> {
+		self.inner.consume_string("@namespace")?;
+		self.inner.consume_whitespace();
+		let start = self.inner.pos();
+		let mut prefix = self.parse_identifier();
+		if prefix == "url" && self.inner.peek() == Some('(') {
+			// That was the URL, not a prefix
+			self.inner.rewind(start);
+			prefix.clear();
+		}
+		self.inner.consume_whitespace();
+		let url = self.parse_url()?;
+		self.inner.consume_whitespace();
+		self.inner.expect_char(';')?;
+
+		if prefix.is_empty() {
+			self.default_namespace = Some(url);
+		} else {
+			self.namespaces.insert(prefix, url);
+		}
+		Ok(())
+	}
+
+	// Parse the `svg|` in `svg|rect`, if it's there
+	fn parse_namespace_prefix(&mut self) -> Result<Option<NamespaceConstraint>, ParseError> {
+		let start = self.inner.pos();
+		let prefix = if self.inner.next_char() == '*' {
+			self.inner.consume_char();
+			"*".to_string()
+		} else {
+			self.parse_identifier()
+		};
+		if self.inner.peek() != Some('|') {
+			self.inner.rewind(start);
+			return Ok(None);
+		}
+		self.inner.consume_char();
+
+		match prefix.as_str() {
+			"*" => Ok(Some(NamespaceConstraint::Any)),
+			"" => Ok(Some(NamespaceConstraint::NoNamespace)),
+			_ => match self.namespaces.get(&prefix) {
+				Some(url) => Ok(Some(NamespaceConstraint::Namespace(url.clone()))),
+				None => Err(self.inner.error_at(
+					start,
+					ParseErrorKind::UndeclaredNamespace,
+					None,
+					Some(prefix),
+				)),
+			},
+		}
+	}
+
 	// Parse a simple selector such as type#id.class1.class2
-	fn parse_simple_selector(&mut self) -> SimpleSelector {
+	fn parse_simple_selector(&mut self) -> Result<SimpleSelector, ParseError> {
+		// Elements have to be in the default namespace, unless the selector says otherwise
+		let namespace = match self.parse_namespace_prefix()? {
+			Some(namespace) => namespace,
+			None => match self.default_namespace {
+				Some(ref url) => NamespaceConstraint::Namespace(url.clone()),
+				None => NamespaceConstraint::Any,
+			},
+		};
 		let mut selector = SimpleSelector {
 			tag_name: None,
+			namespace,
 			id: None,
 			class: Vec::new(),
 			universal: false,
@@ -121,114 +259,137 @@ impl CssParser {
 			}
 		}
 
-		selector
+		Ok(selector)
 	}
 
-	fn parse_selector(&mut self) -> Option<Selector> {
+	fn parse_selector(&mut self) -> Result<Option<Selector>, ParseError> {
 		// Take a simple selector
 		if !self.inner.eof() && self.can_start_simple_selector() {
-			return Some(Selector::Simple(self.parse_simple_selector()));
+			return Ok(Some(Selector::Simple(self.parse_simple_selector()?)));
+		}
+		Ok(None)
+	}
+
+	// Parse a comma-separated list of selectors, like the argument to `querySelector`
+	fn parse_selector_list(&mut self) -> Result<Vec<Selector>, ParseError> {
+		let mut selectors = Vec::new();
+		loop {
+			self.inner.consume_whitespace();
+			match self.parse_selector()? {
+				Some(selector) => selectors.push(selector),
+				None => return Err(self.inner.unexpected("selector")),
+			}
+			self.inner.consume_whitespace();
+			if self.inner.eof() {
+				return Ok(selectors);
+			}
+			self.inner.expect_char(',')?;
 		}
-		None
 	}
 
-	fn parse_declaration(&mut self) -> Declaration {
+	fn parse_declaration(&mut self) -> Result<Declaration, ParseError> {
+		let start = self.inner.pos();
 		let name = self.parse_identifier();
+		if name.is_empty() {
+			return Err(self.inner.unexpected("property name"));
+		}
+		self.inner.consume_whitespace();
+		self.inner.expect_char(':')?;
 		self.inner.consume_whitespace();
-		assert!(self.inner.consume_char() == ':');
+		let value = self.parse_value()?;
+		self.inner.consume_whitespace();
+		self.inner.expect_char(';')?;
+		Ok(Declaration {
+			name,
+			value,
+			span: Some(self.inner.span_from(start)),
+		})
+	}
+
+	// Parse a single color channel, followed by the given separator
+	fn parse_color_component(&mut self, separator: char) -> Result<u8, ParseError> {
 		self.inner.consume_whitespace();
-		let value = self.parse_value();
+		let value = self.inner.consume_number()?;
 		self.inner.consume_whitespace();
-		assert!(self.inner.consume_char() == ';');
-		Declaration { name, value }
+		self.inner.expect_char(separator)?;
+		Ok(value)
 	}
 
-	fn parse_value(&mut self) -> Value {
+	fn parse_value(&mut self) -> Result<Value, ParseError> {
 		// Attempt to parse a color value
 		if self.inner.starts_with("rgba(") {
-			self.inner.consume_string("rgba(");
-			self.inner.consume_whitespace();
-			let r = self.inner.consume_number().unwrap();
-			self.inner.consume_whitespace();
-			assert!(self.inner.consume_char() == ',');
-			self.inner.consume_whitespace();
-			let g = self.inner.consume_number().unwrap();
-			self.inner.consume_whitespace();
-			assert!(self.inner.consume_char() == ',');
-			self.inner.consume_whitespace();
-			let b = self.inner.consume_number().unwrap();
-			self.inner.consume_whitespace();
-			assert!(self.inner.consume_char() == ',');
-			self.inner.consume_whitespace();
-			let a = self.inner.consume_number().unwrap();
-			self.inner.consume_whitespace();
-			assert!(self.inner.consume_char() == ')');
+			self.inner.consume_string("rgba(")?;
+			let r = self.parse_color_component(',')?;
+			let g = self.parse_color_component(',')?;
+			let b = self.parse_color_component(',')?;
+			let a = self.parse_color_component(')')?;
 
-			return Value::ColorValue(Color { r, g, b, a });
+			return Ok(Value::ColorValue(Color { r, g, b, a }));
 		}
 
 		// Attempt to parse a length value
 		if let Ok(num) = self.inner.consume_number() {
-			assert!(self.inner.consume_char() == 'p');
-			assert!(self.inner.consume_char() == 'x');
-			return Value::Length(num, Unit::Px);
+			self.inner.consume_string("px")?;
+			return Ok(Value::Length(num, Unit::Px));
 		}
 
-		Value::Keyword(self.inner.consume_while(is_valid_identifier_char))
+		let keyword = self.inner.consume_while(is_valid_identifier_char);
+		if keyword.is_empty() {
+			return Err(self.inner.unexpected("value"));
+		}
+		Ok(Value::Keyword(keyword))
 	}
 
-	fn parse_rule(&mut self) -> Rule {
+	fn parse_rule(&mut self) -> Result<Rule, ParseError> {
+		let start = self.inner.pos();
 		let mut selectors = Vec::new();
 		let mut declarations = Vec::new();
-		loop {
-			if let Some(selector) = self.parse_selector() {
-				selectors.push(selector);
-				self.inner.consume_whitespace();
-			} else {
-				break;
-			}
+		while let Some(selector) = self.parse_selector()? {
+			selectors.push(selector);
+			self.inner.consume_whitespace();
 		}
 
 		// Sort the selectors by specificity
-		selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
+		selectors.sort_by_key(|selector| std::cmp::Reverse(selector.specificity()));
 
 		self.inner.consume_whitespace();
-		assert!(self.inner.consume_char() == '{');
+		self.inner.expect_char('{')?;
 
 		loop {
 			self.inner.consume_whitespace();
-			if self.inner.next_char() == '}' {
-				self.inner.consume_char();
-				break;
+			match self.inner.peek() {
+				Some('}') => {
+					self.inner.consume_char();
+					break;
+				}
+				None => return Err(self.inner.unexpected("}")),
+				_ => declarations.push(self.parse_declaration()?),
 			}
-
-			declarations.push(self.parse_declaration());
 		}
 
-		Rule {
+		Ok(Rule {
 			selectors,
 			declarations,
-		}
+			span: Some(self.inner.span_from(start)),
+		})
 	}
 
-	fn parse_stylesheet(&mut self) -> Result<Stylesheet, String> {
+	fn parse_stylesheet(&mut self) -> Result<Stylesheet, ParseError> {
 		let mut rules = Vec::new();
 		loop {
 			self.inner.consume_whitespace();
+			if self.inner.starts_with("@namespace") {
+				self.parse_namespace_rule()?;
+				continue;
+			}
 			if self.inner.eof() || !self.can_start_rule() {
 				break;
 			}
-			rules.push(self.parse_rule());
+			rules.push(self.parse_rule()?);
 		}
 
 		if !self.inner.eof() {
-			let pos = self.inner.pos();
-			Err(format!(
-				"Unknown character at {}:{}: {}",
-				pos.line,
-				pos.column,
-				self.inner.next_char()
-			))
+			Err(self.inner.unexpected("selector"))
 		} else {
 			Ok(Stylesheet { rules })
 		}
@@ -239,13 +400,50 @@ fn is_valid_identifier_char(c: char) -> bool {
 	char::is_alphanumeric(c) || c == '_' || c == '-'
 }
 
-pub fn parse(source: String) -> Result<Stylesheet, String> {
+pub fn parse(source: String) -> Result<Stylesheet, ParseError> {
 	CssParser::new(source).parse_stylesheet()
 }
 
+// Parse a selector list such as `p, .note, #main`. There are no `@namespace` rules, so the only
+// namespace prefixes allowed are `*|` and `|`.
+pub fn parse_selectors(source: String) -> Result<Vec<Selector>, ParseError> {
+	CssParser::new(source).parse_selector_list()
+}
+
+// Work out the encoding of a stylesheet and decode it. A byte order mark wins, then the encoding
+// the stylesheet was served with (`hint`), then an `@charset` rule, and otherwise it's UTF-8.
+// https://drafts.csswg.org/css-syntax/#input-byte-stream
+pub fn decode(bytes: &[u8], hint: Option<Encoding>) -> (String, Encoding) {
+	if let Some((encoding, length)) = encoding::sniff_bom(bytes) {
+		return (encoding.decode(&bytes[length..]), encoding);
+	}
+	let encoding = hint
+		.or_else(|| charset_rule(bytes))
+		.unwrap_or(Encoding::Utf8);
+	(encoding.decode(bytes), encoding)
+}
+
+// The encoding named by an `@charset "...";` rule at the very start of the stylesheet
+fn charset_rule(bytes: &[u8]) -> Option<Encoding> {
+	let label = bytes.strip_prefix(b"@charset \"")?;
+	let end = label.windows(2).position(|pair| pair == b"\";")?;
+	let label = String::from_utf8_lossy(&label[..end]);
+	match Encoding::for_label(&label)? {
+		// The rule was readable as ASCII, so the stylesheet can't really be UTF-16
+		encoding if encoding.is_utf16() => Some(Encoding::Utf8),
+		encoding => Some(encoding),
+	}
+}
+
+// Parse a stylesheet from raw bytes, using `decode` to work out its encoding
+pub fn parse_bytes(bytes: &[u8], hint: Option<Encoding>) -> Result<Stylesheet, ParseError> {
+	parse(decode(bytes, hint).0)
+}
+
 #[cfg(test)]
 mod html_tests {
 	use super::*;
+	use parser::ParseErrorKind;
 
 	#[test]
 	fn can_parse_p_selector() {
@@ -257,6 +455,7 @@ mod html_tests {
 			rules: vec![Rule {
 				selectors: vec![Selector::Simple(SimpleSelector {
 					tag_name: Some("p".into()),
+					namespace: NamespaceConstraint::Any,
 					class: Vec::new(),
 					id: None,
 					universal: false,
@@ -269,10 +468,109 @@ mod html_tests {
 						b: 30,
 						a: 40,
 					}),
+					span: None,
 				}],
+				span: None,
 			}],
 		};
 		let actual = parse(input);
 		assert_eq!(Ok(expected), actual);
 	}
+
+	#[test]
+	fn resolves_namespace_prefixes() {
+		let stylesheet = parse(
+			"@namespace url(http://www.w3.org/1999/xhtml);
+			@namespace svg \"http://www.w3.org/2000/svg\";
+			svg|rect {} *|a {} |b {} p {}"
+				.into(),
+		)
+		.unwrap();
+		let namespaces: Vec<_> = stylesheet
+			.rules
+			.iter()
+			.map(|rule| match rule.selectors[0] {
+				Selector::Simple(ref simple) => &simple.namespace,
+			})
+			.collect();
+		assert_eq!(
+			namespaces,
+			vec![
+				&NamespaceConstraint::Namespace("http://www.w3.org/2000/svg".into()),
+				&NamespaceConstraint::Any,
+				&NamespaceConstraint::NoNamespace,
+				&NamespaceConstraint::Namespace("http://www.w3.org/1999/xhtml".into()),
+			]
+		);
+
+		let err = parse("math|mi {}".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UndeclaredNamespace);
+		assert_eq!(err.found, Some("math".to_string()));
+	}
+
+	#[test]
+	fn parses_selector_lists() {
+		let selectors = parse_selectors(" p.a , #b,*|c ".into()).unwrap();
+		assert_eq!(selectors.len(), 3);
+		assert_eq!(selectors[0].specificity(), (0, 1, 1));
+		assert_eq!(selectors[1].specificity(), (1, 0, 0));
+
+		assert!(parse_selectors("".into()).is_err());
+		assert!(parse_selectors("p,".into()).is_err());
+		// Combinators aren't supported
+		assert!(parse_selectors("div p".into()).is_err());
+	}
+
+	#[test]
+	fn reports_missing_colon() {
+		let err = parse("p { color red; }".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedChar);
+		assert_eq!(err.expected, Some(":".to_string()));
+		assert_eq!(err.found, Some("r".to_string()));
+	}
+
+	#[test]
+	fn reports_unterminated_rule() {
+		let err = parse("p { width: 10px;".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+		assert_eq!(err.expected, Some("}".to_string()));
+
+		let err = parse("p { color: rgba(1, 2, 3".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+		assert_eq!(err.expected, Some(",".to_string()));
+	}
+
+	#[test]
+	fn reports_out_of_range_color() {
+		let err = parse("p { color: rgba(1, 2, 300, 4); }".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::InvalidNumber);
+		assert_eq!(err.found, Some("300".to_string()));
+	}
+
+	#[test]
+	fn records_rule_and_declaration_spans() {
+		let stylesheet = parse("a {}\np {\n  width: 10px;\n}".into()).unwrap();
+		let rule = &stylesheet.rules[1];
+		let span = rule.span.unwrap();
+		assert_eq!((span.start.line, span.start.column), (2, 1));
+		assert_eq!((span.end.line, span.end.column), (4, 2));
+
+		let span = rule.declarations[0].span.unwrap();
+		assert_eq!((span.start.line, span.start.column), (3, 3));
+		assert_eq!((span.end.line, span.end.column), (3, 15));
+	}
+
+	#[test]
+	fn decodes_bytes_using_the_charset_rule() {
+		let css = b"@charset \"latin1\";\np { content: \xE9; }";
+		assert_eq!(decode(css, None).1, Encoding::Windows1252);
+		assert_eq!(decode(css, Some(Encoding::Utf8)).1, Encoding::Utf8);
+		assert_eq!(decode(b"@charset \"utf-16\";", None).1, Encoding::Utf8);
+		assert_eq!(decode(b"p { color: red; }", None).1, Encoding::Utf8);
+		assert_eq!(
+			decode(b"\xFE\xFF\0p", Some(Encoding::Windows1252)),
+			("p".to_string(), Encoding::Utf16Be)
+		);
+		assert!(parse_bytes(b"\xEF\xBB\xBFp { color: rgba(1, 2, 3, 4); }", None).is_ok());
+	}
 }
@@ -1,120 +1,821 @@
+use css::Stylesheet;
+use html::serializer;
+use parser::Span;
 use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
+use std::iter;
+
+mod index;
+pub mod query;
+pub mod traversal;
 
 pub type AttrMap = HashMap<String, String>;
 
-#[derive(PartialEq)]
+// Namespaces elements and attributes can be in
+// https://infra.spec.whatwg.org/#namespaces
+pub const HTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+pub const MATHML_NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+pub const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+pub const XLINK_NAMESPACE: &str = "http://www.w3.org/1999/xlink";
+pub const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+pub const XMLNS_NAMESPACE: &str = "http://www.w3.org/2000/xmlns/";
+
+// A tree of nodes which owns its children, for building nodes to put in a `Document`. Builders like
+// `elem` and `text` make them, and `Document::new` and `Document::create_node` move them into the
+// document's arena, where they're linked to each other.
+#[derive(Clone)]
 pub struct Node {
 	// Data common to all nodes
 	pub children: Vec<Node>,
 
+	// Where in the source this node came from, if it was parsed
+	pub span: Option<Span>,
+
 	// Data specific to each node type
 	pub node_type: NodeType,
 }
 
-#[derive(Debug, PartialEq)]
+// Spans are source metadata, so two nodes are equal if their contents are
+impl PartialEq for Node {
+	fn eq(&self, other: &Node) -> bool {
+		self.node_type == other.node_type && self.children == other.children
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum NodeType {
+	// Text which was written as `<![CDATA[...]]>`
+	CData(String),
 	Comment(String),
+	Doctype(DocumentType),
+	// The node at the top of a `Document`, which everything else in it descends from
+	Document,
+	// A lightweight container for nodes which aren't part of the document, such as the contents of
+	// a `<template>`
+	DocumentFragment,
 	Element(ElementData),
+	// An instruction for whatever is processing the document, like `<?xml-stylesheet href="a"?>`
+	ProcessingInstruction { target: String, data: String },
 	Text(String),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct DocumentType {
+	pub name: String,
+	// `None` when the identifier is missing, which is distinct from being empty
+	pub public_id: Option<String>,
+	pub system_id: Option<String>,
+}
+
+// How closely the document should follow the standards when it's rendered
+// https://dom.spec.whatwg.org/#concept-document-mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuirksMode {
+	NoQuirks,
+	LimitedQuirks,
+	Quirks,
+}
+
+// A handle to one of the nodes in a `Document`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+// A node in a `Document`, linked to the nodes around it. The links are kept consistent by the
+// document, so they can only be read from outside.
+#[derive(Debug)]
+pub struct NodeData {
+	pub node_type: NodeType,
+	pub span: Option<Span>,
+
+	parent: Option<NodeId>,
+	previous_sibling: Option<NodeId>,
+	next_sibling: Option<NodeId>,
+	first_child: Option<NodeId>,
+	last_child: Option<NodeId>,
+
+	// The document fragment holding a `<template>`'s contents, which has no parent
+	template_contents: Option<NodeId>,
+
+	// For a template's contents, the template they belong to
+	host: Option<NodeId>,
+}
+
+// A document, which owns all of its nodes. They're kept in an arena and refer to each other by
+// `NodeId`, so any node can find its parent and siblings as well as its children.
+pub struct Document {
+	// Indexed by `NodeId`. The first node is the document node, whose children are the doctype,
+	// the root element and any comments around them.
+	nodes: Vec<NodeData>,
+	pub mode: QuirksMode,
+
+	// The elements in the document by id, class and tag name
+	index: index::Index,
+
+	// The stylesheets embedded in the document, in document order
+	pub stylesheets: Vec<Stylesheet>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ElementData {
+	// The local name, such as `div` or `foreignObject`
 	pub tag_name: String,
+	// `None` for elements which aren't in any namespace
+	pub namespace: Option<String>,
+	// The prefix the element was written with in XML, such as `svg` in `<svg:rect>`
+	pub prefix: Option<String>,
+
+	// Attributes are keyed by their qualified name, such as `xlink:href`
 	pub attributes: AttrMap,
+	// The namespaces of the attributes which are in one
+	pub attribute_namespaces: HashMap<String, String>,
+
+	// The document fragment holding a `<template>`'s contents, which aren't its children so that
+	// they aren't rendered. Once the element is in a `Document` the fragment is one of the
+	// document's nodes instead, found with `Document::template_contents`.
+	// https://html.spec.whatwg.org/multipage/scripting.html#template-contents
+	pub template_contents: Option<Box<Node>>,
 }
 
 pub fn text(data: String) -> Node {
 	Node {
 		children: Vec::new(),
+		span: None,
 		node_type: NodeType::Text(data),
 	}
 }
 
+// An HTML element
 pub fn elem(name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
+	elem_ns(Some(HTML_NAMESPACE.into()), name, attrs, children)
+}
+
+pub fn elem_ns(
+	namespace: Option<String>,
+	name: String,
+	attrs: AttrMap,
+	children: Vec<Node>,
+) -> Node {
 	Node {
 		children,
+		span: None,
 		node_type: NodeType::Element(ElementData {
 			tag_name: name,
+			namespace,
+			prefix: None,
 			attributes: attrs,
+			attribute_namespaces: HashMap::new(),
+			template_contents: None,
 		}),
 	}
 }
 
+// A `<template>` element, which keeps `contents` in a document fragment rather than as children
+pub fn template(attrs: AttrMap, contents: Vec<Node>) -> Node {
+	let mut node = elem("template".into(), attrs, Vec::new());
+	if let NodeType::Element(ref mut data) = node.node_type {
+		data.template_contents = Some(Box::new(fragment(contents)));
+	}
+	node
+}
+
+pub fn fragment(children: Vec<Node>) -> Node {
+	Node {
+		children,
+		span: None,
+		node_type: NodeType::DocumentFragment,
+	}
+}
+
+pub fn cdata(data: String) -> Node {
+	Node {
+		children: Vec::new(),
+		span: None,
+		node_type: NodeType::CData(data),
+	}
+}
+
+pub fn processing_instruction(target: String, data: String) -> Node {
+	Node {
+		children: Vec::new(),
+		span: None,
+		node_type: NodeType::ProcessingInstruction { target, data },
+	}
+}
+
 pub fn comment(contents: String) -> Node {
 	Node {
 		children: Vec::new(),
+		span: None,
 		node_type: NodeType::Comment(contents),
 	}
 }
 
+pub fn doctype(name: String, public_id: Option<String>, system_id: Option<String>) -> Node {
+	Node {
+		children: Vec::new(),
+		span: None,
+		node_type: NodeType::Doctype(DocumentType {
+			name,
+			public_id,
+			system_id,
+		}),
+	}
+}
+
+// Document methods
+impl Document {
+	pub fn new(children: Vec<Node>, mode: QuirksMode) -> Document {
+		let mut document = Document {
+			nodes: Vec::new(),
+			mode,
+			index: Default::default(),
+			stylesheets: Vec::new(),
+		};
+		let id = document.add_node(NodeType::Document, None);
+		for child in children {
+			let child = document.add_tree(child);
+			document.link(id, child, None);
+		}
+		document
+	}
+
+	// Move a tree of nodes into the arena, returning the id of its top node, which has no parent
+	fn add_tree(&mut self, node: Node) -> NodeId {
+		let Node {
+			children,
+			span,
+			mut node_type,
+		} = node;
+		let contents = match node_type {
+			NodeType::Element(ref mut data) => data.template_contents.take(),
+			_ => None,
+		};
+		let id = self.add_node(node_type, span);
+		if let Some(contents) = contents {
+			let contents = self.add_tree(*contents);
+			self.nodes[id.0].template_contents = Some(contents);
+			self.nodes[contents.0].host = Some(id);
+		}
+		for child in children {
+			let child = self.add_tree(child);
+			self.link(id, child, None);
+		}
+		id
+	}
+
+	fn add_node(&mut self, node_type: NodeType, span: Option<Span>) -> NodeId {
+		self.nodes.push(NodeData {
+			node_type,
+			span,
+			parent: None,
+			previous_sibling: None,
+			next_sibling: None,
+			first_child: None,
+			last_child: None,
+			template_contents: None,
+			host: None,
+		});
+		NodeId(self.nodes.len() - 1)
+	}
+
+	// Link a node with no parent in as a child of `parent`, before `before` or at the end
+	fn link(&mut self, parent: NodeId, child: NodeId, before: Option<NodeId>) {
+		let previous = match before {
+			Some(next) => self.nodes[next.0].previous_sibling,
+			None => self.nodes[parent.0].last_child,
+		};
+		match previous {
+			Some(previous) => self.nodes[previous.0].next_sibling = Some(child),
+			None => self.nodes[parent.0].first_child = Some(child),
+		}
+		match before {
+			Some(next) => self.nodes[next.0].previous_sibling = Some(child),
+			None => self.nodes[parent.0].last_child = Some(child),
+		}
+
+		let node = &mut self.nodes[child.0];
+		node.parent = Some(parent);
+		node.previous_sibling = previous;
+		node.next_sibling = before;
+
+		if self.is_connected(parent) {
+			self.index_subtree(child, true);
+		}
+	}
+
+	// Take a node out of its parent's children. It keeps its own children.
+	fn unlink(&mut self, id: NodeId) {
+		if self.parent(id).is_some() && self.is_connected(id) {
+			self.index_subtree(id, false);
+		}
+		let (parent, previous, next) = {
+			let node = &mut self.nodes[id.0];
+			let links = (node.parent, node.previous_sibling, node.next_sibling);
+			node.parent = None;
+			node.previous_sibling = None;
+			node.next_sibling = None;
+			links
+		};
+		let parent = match parent {
+			Some(parent) => parent,
+			None => return,
+		};
+		match previous {
+			Some(previous) => self.nodes[previous.0].next_sibling = next,
+			None => self.nodes[parent.0].first_child = next,
+		}
+		match next {
+			Some(next) => self.nodes[next.0].previous_sibling = previous,
+			None => self.nodes[parent.0].last_child = previous,
+		}
+	}
+
+	// The node everything else in the document descends from
+	pub fn document_node(&self) -> NodeId {
+		NodeId(0)
+	}
+
+	pub fn node(&self, id: NodeId) -> &NodeData {
+		&self.nodes[id.0]
+	}
+
+	// The element data of a node, if it's an element
+	pub fn element(&self, id: NodeId) -> Option<&ElementData> {
+		match self.node(id).node_type {
+			NodeType::Element(ref data) => Some(data),
+			_ => None,
+		}
+	}
+
+	pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+		self.node(id).parent
+	}
+
+	pub fn previous_sibling(&self, id: NodeId) -> Option<NodeId> {
+		self.node(id).previous_sibling
+	}
+
+	pub fn next_sibling(&self, id: NodeId) -> Option<NodeId> {
+		self.node(id).next_sibling
+	}
+
+	pub fn first_child(&self, id: NodeId) -> Option<NodeId> {
+		self.node(id).first_child
+	}
+
+	pub fn last_child(&self, id: NodeId) -> Option<NodeId> {
+		self.node(id).last_child
+	}
+
+	// The children of a node, first to last
+	pub fn children(&self, id: NodeId) -> Children<'_> {
+		Children {
+			document: self,
+			next: self.first_child(id),
+		}
+	}
+
+	// The document fragment holding a `<template>` element's contents
+	pub fn template_contents(&self, id: NodeId) -> Option<NodeId> {
+		self.node(id).template_contents
+	}
+
+	// Is `ancestor` the node itself or one of its ancestors?
+	pub fn is_inclusive_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+		let mut current = Some(id);
+		while let Some(node) = current {
+			if node == ancestor {
+				return true;
+			}
+			current = self.parent(node);
+		}
+		false
+	}
+
+	// Like `is_inclusive_ancestor`, but a template's contents count as being inside the template
+	// https://dom.spec.whatwg.org/#concept-tree-host-including-inclusive-ancestor
+	fn is_host_including_inclusive_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+		let mut current = Some(id);
+		while let Some(node) = current {
+			if node == ancestor {
+				return true;
+			}
+			current = self.parent(node).or(self.node(node).host);
+		}
+		false
+	}
+
+	// Copy a tree of nodes into the document. The new nodes have no parent until they're inserted.
+	pub fn create_node(&mut self, node: Node) -> NodeId {
+		self.add_tree(node)
+	}
+
+	pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+		self.insert_before(parent, child, None)
+	}
+
+	// Insert `child` into `parent` before `reference`, or at the end if there's no reference. If
+	// the child is already somewhere in the document it's moved, along with its descendants.
+	// https://dom.spec.whatwg.org/#concept-node-pre-insert
+	pub fn insert_before(
+		&mut self,
+		parent: NodeId,
+		child: NodeId,
+		reference: Option<NodeId>,
+	) -> Result<(), DomError> {
+		self.check_insert(parent, child)?;
+		if reference.is_some_and(|reference| self.parent(reference) != Some(parent)) {
+			return Err(DomError::NotFound);
+		}
+
+		// Inserting a node before itself leaves it where it is
+		let reference = if reference == Some(child) {
+			self.next_sibling(child)
+		} else {
+			reference
+		};
+		self.unlink(child);
+		self.link(parent, child, reference);
+		Ok(())
+	}
+
+	// Take `child` out of `parent`. It stays in the document, without a parent, so it can be
+	// inserted again.
+	pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+		if self.parent(child) != Some(parent) {
+			return Err(DomError::NotFound);
+		}
+		self.unlink(child);
+		Ok(())
+	}
+
+	// Put `new_child` where `old_child` is, leaving `old_child` without a parent
+	pub fn replace_child(
+		&mut self,
+		parent: NodeId,
+		new_child: NodeId,
+		old_child: NodeId,
+	) -> Result<(), DomError> {
+		if self.parent(old_child) != Some(parent) {
+			return Err(DomError::NotFound);
+		}
+		if new_child == old_child {
+			return Ok(());
+		}
+		self.check_insert(parent, new_child)?;
+		let reference = match self.next_sibling(old_child) {
+			Some(next) if next == new_child => self.next_sibling(new_child),
+			next => next,
+		};
+		self.unlink(old_child);
+		self.unlink(new_child);
+		self.link(parent, new_child, reference);
+		Ok(())
+	}
+
+	// Take a node out of its parent, if it has one
+	pub fn detach(&mut self, id: NodeId) {
+		self.unlink(id);
+	}
+
+	// Can `child` be made a child of `parent`? Nodes can't be put inside themselves, even through
+	// a template's contents, and only elements, fragments and the document have children. A
+	// template's contents stay with the template.
+	// https://dom.spec.whatwg.org/#concept-node-ensure-pre-insertion-validity
+	fn check_insert(&self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+		if self.is_host_including_inclusive_ancestor(child, parent)
+			|| self.node(child).host.is_some()
+		{
+			return Err(DomError::HierarchyRequest);
+		}
+		let allowed = match (&self.node(parent).node_type, &self.node(child).node_type) {
+			(_, NodeType::Document) => false,
+			// A document has at most one doctype and one element, and no text
+			(NodeType::Document, NodeType::Doctype(_)) => self
+				.children(parent)
+				.all(|id| id == child || !matches!(self.node(id).node_type, NodeType::Doctype(_))),
+			(NodeType::Document, NodeType::Element(_)) => {
+				self.root().is_none_or(|root| root == child)
+			}
+			(NodeType::Document, NodeType::Text(_)) | (NodeType::Document, NodeType::CData(_)) => {
+				false
+			}
+			(NodeType::Document, _) => true,
+			(_, NodeType::Doctype(_)) => false,
+			(NodeType::Element(_), _) | (NodeType::DocumentFragment, _) => true,
+			_ => false,
+		};
+		if allowed {
+			Ok(())
+		} else {
+			Err(DomError::HierarchyRequest)
+		}
+	}
+
+	fn element_mut(&mut self, id: NodeId) -> Result<&mut ElementData, DomError> {
+		match self.nodes[id.0].node_type {
+			NodeType::Element(ref mut data) => Ok(data),
+			_ => Err(DomError::InvalidNodeType),
+		}
+	}
+
+	pub fn set_attribute(&mut self, id: NodeId, name: &str, value: &str) -> Result<(), DomError> {
+		self.edit_attributes(id, name, |data| {
+			data.attributes.insert(name.to_string(), value.to_string());
+		})
+	}
+
+	// Remove an attribute, returning its value if it was set
+	pub fn remove_attribute(&mut self, id: NodeId, name: &str) -> Result<Option<String>, DomError> {
+		self.edit_attributes(id, name, |data| {
+			data.attribute_namespaces.remove(name);
+			data.attributes.remove(name)
+		})
+	}
+
+	// Change an element's attributes, re-indexing it if the id or classes might change
+	fn edit_attributes<F, T>(&mut self, id: NodeId, name: &str, edit: F) -> Result<T, DomError>
+	where
+		F: FnOnce(&mut ElementData) -> T,
+	{
+		self.element_mut(id)?;
+		let reindex = (name == "id" || name == "class") && self.is_connected(id);
+		if reindex {
+			self.index_element(id, false);
+		}
+		let result = edit(self.element_mut(id)?);
+		if reindex {
+			self.index_element(id, true);
+		}
+		Ok(result)
+	}
+
+	// Set the text of a node, like setting `textContent`. Text, comments and processing
+	// instructions have their data replaced, and elements and fragments have their children
+	// replaced with a single text node. It does nothing to doctypes or the document.
+	pub fn set_text(&mut self, id: NodeId, text: &str) {
+		match self.nodes[id.0].node_type {
+			NodeType::Text(ref mut data)
+			| NodeType::CData(ref mut data)
+			| NodeType::Comment(ref mut data)
+			| NodeType::ProcessingInstruction { ref mut data, .. } => {
+				*data = text.to_string();
+				return;
+			}
+			NodeType::Element(_) | NodeType::DocumentFragment => {}
+			NodeType::Doctype(_) | NodeType::Document => return,
+		}
+		while let Some(child) = self.first_child(id) {
+			self.unlink(child);
+		}
+		if !text.is_empty() {
+			let child = self.add_node(NodeType::Text(text.to_string()), None);
+			self.link(id, child, None);
+		}
+	}
+
+	// Add to the end of the data of text, a comment or a processing instruction, like
+	// `appendData`. It does nothing to other nodes.
+	// https://dom.spec.whatwg.org/#dom-characterdata-appenddata
+	pub fn append_data(&mut self, id: NodeId, data: &str) {
+		match self.nodes[id.0].node_type {
+			NodeType::Text(ref mut existing)
+			| NodeType::CData(ref mut existing)
+			| NodeType::Comment(ref mut existing)
+			| NodeType::ProcessingInstruction {
+				data: ref mut existing,
+				..
+			} => existing.push_str(data),
+			_ => {}
+		}
+	}
+
+	// Merge adjacent text nodes and remove empty ones, throughout the node's descendants
+	// https://dom.spec.whatwg.org/#dom-node-normalize
+	pub fn normalize(&mut self, id: NodeId) {
+		let mut next = self.first_child(id);
+		while let Some(child) = next {
+			next = self.next_sibling(child);
+			if !matches!(self.node(child).node_type, NodeType::Text(_)) {
+				self.normalize(child);
+				continue;
+			}
+
+			let mut merged = String::new();
+			while let Some(sibling) = next {
+				match self.node(sibling).node_type {
+					NodeType::Text(ref text) => merged.push_str(text),
+					_ => break,
+				}
+				next = self.next_sibling(sibling);
+				self.unlink(sibling);
+			}
+			if let NodeType::Text(ref mut text) = self.nodes[child.0].node_type {
+				text.push_str(&merged);
+				if !text.is_empty() {
+					continue;
+				}
+			}
+			self.unlink(child);
+		}
+	}
+
+	// The document's doctype, if it has one
+	pub fn doctype(&self) -> Option<&DocumentType> {
+		self.children(self.document_node())
+			.find_map(|id| match self.node(id).node_type {
+				NodeType::Doctype(ref doctype) => Some(doctype),
+				_ => None,
+			})
+	}
+
+	// The root element of the document
+	pub fn root(&self) -> Option<NodeId> {
+		self.children(self.document_node())
+			.find(|&id| self.element(id).is_some())
+	}
+
+	// The text in a node, like `textContent`. Text and CDATA sections are joined in document
+	// order, leaving out comments, processing instructions and template contents.
+	pub fn text_content(&self, id: NodeId) -> String {
+		match self.node(id).node_type {
+			NodeType::Comment(ref data) | NodeType::ProcessingInstruction { ref data, .. } => {
+				return data.clone();
+			}
+			NodeType::Doctype(_) => return String::new(),
+			_ => {}
+		}
+		iter::once(id)
+			.chain(self.descendants(id))
+			.filter_map(|id| match self.node(id).node_type {
+				NodeType::Text(ref data) | NodeType::CData(ref data) => Some(&**data),
+				_ => None,
+			})
+			.collect()
+	}
+
+	// The whole document serialized as HTML
+	pub fn to_html(&self) -> String {
+		serializer::document_html(self)
+	}
+
+	// The node and its descendants serialized as HTML, like `outerHTML`
+	pub fn outer_html(&self, id: NodeId) -> String {
+		serializer::outer_html(self, id)
+	}
+
+	// The node's descendants serialized as HTML, like `innerHTML`
+	pub fn inner_html(&self, id: NodeId) -> String {
+		serializer::inner_html(self, id)
+	}
+
+	// Do two nodes, possibly in different documents, have the same contents? Spans are ignored,
+	// like they are when comparing `Node`s.
+	fn same_tree(&self, id: NodeId, other: &Document, other_id: NodeId) -> bool {
+		let (node, other_node) = (self.node(id), other.node(other_id));
+		let same_contents = match (node.template_contents, other_node.template_contents) {
+			(Some(a), Some(b)) => self.same_tree(a, other, b),
+			(a, b) => a.is_none() && b.is_none(),
+		};
+		node.node_type == other_node.node_type
+			&& same_contents
+			&& self.children(id).count() == other.children(other_id).count()
+			&& self
+				.children(id)
+				.zip(other.children(other_id))
+				.all(|(a, b)| self.same_tree(a, other, b))
+	}
+}
+
+impl PartialEq for Document {
+	fn eq(&self, other: &Document) -> bool {
+		self.mode == other.mode
+			&& self.stylesheets == other.stylesheets
+			&& self.same_tree(self.document_node(), other, other.document_node())
+	}
+}
+
+// Why a change to a document was refused
+// https://webidl.spec.whatwg.org/#idl-DOMException-error-names
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DomError {
+	// The change would put a node somewhere it can't go, like inside itself or inside text
+	HierarchyRequest,
+	// A node wasn't where the change said it would be
+	NotFound,
+	// The change doesn't apply to that kind of node, like setting an attribute on text
+	InvalidNodeType,
+}
+
+impl fmt::Display for DomError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			DomError::HierarchyRequest => "The node can't be inserted there",
+			DomError::NotFound => "The node isn't a child of the parent",
+			DomError::InvalidNodeType => "The operation doesn't apply to that type of node",
+		})
+	}
+}
+
+impl Error for DomError {}
+
+// An iterator over the children of a node
+pub struct Children<'a> {
+	document: &'a Document,
+	next: Option<NodeId>,
+}
+
+impl<'a> Iterator for Children<'a> {
+	type Item = NodeId;
+
+	fn next(&mut self) -> Option<NodeId> {
+		let id = self.next?;
+		self.next = self.document.next_sibling(id);
+		Some(id)
+	}
+}
+
 // Node methods
 impl Node {
 	pub fn append(&mut self, child: Node) {
 		self.children.push(child);
 	}
+
+	pub fn is_element(&self) -> bool {
+		matches!(self.node_type, NodeType::Element(_))
+	}
+
+	// Attach the source span this node was parsed from
+	pub fn with_span(mut self, span: Span) -> Node {
+		self.span = Some(span);
+		self
+	}
 }
 
 // Element methods
 impl ElementData {
+	pub fn is_in(&self, namespace: &str) -> bool {
+		self.namespace.as_ref().is_some_and(|ns| ns == namespace)
+	}
+
+	pub fn is_html(&self) -> bool {
+		self.is_in(HTML_NAMESPACE)
+	}
+
+	// The name including any prefix, which is how it's written in markup
+	pub fn qualified_name(&self) -> String {
+		match self.prefix {
+			Some(ref prefix) => format!("{}:{}", prefix, self.tag_name),
+			None => self.tag_name.clone(),
+		}
+	}
+
+	pub fn attribute_namespace(&self, name: &str) -> Option<&str> {
+		self.attribute_namespaces.get(name).map(String::as_str)
+	}
+
+	// Put an attribute in a namespace. The attribute is set separately, under its qualified name
+	pub fn set_attribute_namespace(&mut self, name: String, namespace: String) {
+		self.attribute_namespaces.insert(name, namespace);
+	}
+
 	pub fn id(&self) -> Option<&String> {
 		self.attributes.get("id")
 	}
 
+	// The classes in the `class` attribute, which are separated by any ASCII whitespace
 	pub fn classes(&self) -> HashSet<&str> {
 		match self.attributes.get("class") {
-			Some(classlist) => classlist.split(' ').collect(),
+			Some(classlist) => classlist
+				.split(|c: char| c.is_ascii_whitespace())
+				.filter(|class| !class.is_empty())
+				.collect(),
 			None => HashSet::new(),
 		}
 	}
 }
 
-// Pretty print DOM nodes
-impl fmt::Debug for Node {
+// Pretty print DOM nodes as HTML
+impl fmt::Debug for Document {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		self.pretty_print_helper(f, 0)
+		f.write_str(&serializer::pretty_document_html(self))
 	}
 }
 
-impl Node {
-	fn pretty_print_helper(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
-		let c = f.fill();
-		let width = f.width().unwrap_or(0) * depth;
-		let indent = if depth > 0 {
-			format!("{:width$}", c, width = width)
-		} else {
-			"".to_string()
-		};
-
-		match &self.node_type {
-			NodeType::Comment(ref s) => write!(f, "<!-- {}{} -->", indent, s),
-			NodeType::Element(ref data) => {
-				write!(f, "{}<{}", indent, data.tag_name);
-
-				for (key, val) in data.attributes.iter() {
-					write!(f, " {}=\"{}\"", key, val);
-				}
-
-				if self.children.len() == 0 {
-					write!(f, " />")
-				} else {
-					write!(f, ">");
-					for node in &self.children {
-						write!(f, "\n");
-						let _ = node.pretty_print_helper(f, depth + 1);
-					}
-					write!(f, "\n{}</{}>", indent, data.tag_name)
-				}
-			}
-			NodeType::Text(ref s) => write!(f, "{}{}", indent, s),
-		}
+// A node which isn't in a document yet is printed by putting a copy in one
+impl fmt::Debug for Node {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut document = Document::new(Vec::new(), QuirksMode::NoQuirks);
+		let id = document.create_node(self.clone());
+		f.write_str(&serializer::pretty_html(&document, id))
 	}
 }
 
 #[cfg(test)]
 mod dom_tests {
 	use super::*;
+	use html;
 
 	macro_rules! test_with_output {
 		($test_name:ident, $body:expr) => {
@@ -132,6 +833,7 @@ mod dom_tests {
 		let input = "test".to_string();
 		let expected = Node {
 			children: vec![],
+			span: None,
 			node_type: NodeType::Text(input.clone()),
 		};
 		let actual = text(input.clone());
@@ -146,8 +848,10 @@ mod dom_tests {
 	fn can_append_children() {
 		let expected = Node {
 			node_type: NodeType::Text("parent".into()),
+			span: None,
 			children: vec![Node {
 				children: vec![],
+				span: None,
 				node_type: NodeType::Text("child".into()),
 			}],
 		};
@@ -182,4 +886,236 @@ mod dom_tests {
 			unreachable!();
 		}
 	}
+
+	#[test]
+	fn links_nodes_in_a_document() {
+		let document = Document::new(
+			vec![
+				doctype("html".into(), None, None),
+				elem(
+					"html".into(),
+					HashMap::new(),
+					vec![
+						elem("head".into(), HashMap::new(), vec![]),
+						elem("body".into(), HashMap::new(), vec![text("a".into())]),
+					],
+				),
+			],
+			QuirksMode::NoQuirks,
+		);
+		let html = document.root().unwrap();
+		assert_eq!(document.parent(html), Some(document.document_node()));
+		assert_eq!(document.element(html).unwrap().tag_name, "html");
+
+		let head = document.first_child(html).unwrap();
+		let body = document.last_child(html).unwrap();
+		assert_eq!(document.next_sibling(head), Some(body));
+		assert_eq!(document.previous_sibling(body), Some(head));
+		assert_eq!(document.previous_sibling(head), None);
+		assert_eq!(document.next_sibling(body), None);
+		assert_eq!(
+			document.children(html).collect::<Vec<_>>(),
+			vec![head, body]
+		);
+
+		let a = document.first_child(body).unwrap();
+		assert_eq!(document.node(a).node_type, NodeType::Text("a".into()));
+		assert_eq!(document.parent(a), Some(body));
+		assert_eq!(document.first_child(a), None);
+	}
+
+	#[test]
+	fn keeps_template_contents_out_of_the_children() {
+		let node = template(HashMap::new(), vec![text("a".into())]);
+		let document = Document::new(vec![node], QuirksMode::NoQuirks);
+		let id = document.root().unwrap();
+		assert_eq!(document.first_child(id), None);
+
+		let contents = document.template_contents(id).unwrap();
+		assert_eq!(
+			document.node(contents).node_type,
+			NodeType::DocumentFragment
+		);
+		assert_eq!(document.parent(contents), None);
+		assert_eq!(document.outer_html(id), "<template>a</template>");
+	}
+
+	// A document whose root is `<div><p>a</p><p>b</p></div>`, and the ids of the div and paragraphs
+	fn paragraphs() -> (Document, NodeId, NodeId, NodeId) {
+		let p = |text_: &str| elem("p".into(), HashMap::new(), vec![text(text_.into())]);
+		let div = elem("div".into(), HashMap::new(), vec![p("a"), p("b")]);
+		let document = Document::new(vec![div], QuirksMode::NoQuirks);
+		let div = document.root().unwrap();
+		let a = document.first_child(div).unwrap();
+		let b = document.last_child(div).unwrap();
+		(document, div, a, b)
+	}
+
+	fn html(document: &Document, id: NodeId) -> String {
+		document.outer_html(id)
+	}
+
+	#[test]
+	fn inserts_removes_and_replaces_children() {
+		let (mut document, div, a, b) = paragraphs();
+		let hr = document.create_node(elem("hr".into(), HashMap::new(), vec![]));
+		assert_eq!(document.parent(hr), None);
+
+		document.insert_before(div, hr, Some(b)).unwrap();
+		assert_eq!(html(&document, div), "<div><p>a</p><hr><p>b</p></div>");
+		document.remove_child(div, a).unwrap();
+		assert_eq!(html(&document, div), "<div><hr><p>b</p></div>");
+		document.replace_child(div, a, b).unwrap();
+		assert_eq!(html(&document, div), "<div><hr><p>a</p></div>");
+		assert_eq!(document.parent(b), None);
+		document.append_child(div, b).unwrap();
+		assert_eq!(html(&document, div), "<div><hr><p>a</p><p>b</p></div>");
+		assert_eq!(document.last_child(div), Some(b));
+
+		assert_eq!(document.remove_child(a, b), Err(DomError::NotFound));
+		assert_eq!(
+			document.insert_before(div, hr, Some(document.first_child(a).unwrap())),
+			Err(DomError::NotFound)
+		);
+	}
+
+	#[test]
+	fn moves_subtrees_between_parents() {
+		let (mut document, div, a, b) = paragraphs();
+		let text_a = document.first_child(a).unwrap();
+		document.append_child(b, text_a).unwrap();
+		assert_eq!(html(&document, div), "<div><p></p><p>ba</p></div>");
+
+		// Moving a node in front of itself or its next sibling leaves it where it is
+		document.insert_before(div, a, Some(a)).unwrap();
+		document.insert_before(div, a, Some(b)).unwrap();
+		assert_eq!(document.children(div).collect::<Vec<_>>(), vec![a, b]);
+
+		document.insert_before(div, b, Some(a)).unwrap();
+		assert_eq!(html(&document, div), "<div><p>ba</p><p></p></div>");
+		assert_eq!(document.previous_sibling(a), Some(b));
+		assert_eq!(document.next_sibling(a), None);
+	}
+
+	#[test]
+	fn refuses_to_break_the_tree() {
+		let (mut document, div, a, b) = paragraphs();
+		let text_a = document.first_child(a).unwrap();
+		let doc = document.document_node();
+		assert_eq!(
+			document.append_child(a, div),
+			Err(DomError::HierarchyRequest)
+		);
+		assert_eq!(document.append_child(a, a), Err(DomError::HierarchyRequest));
+		assert_eq!(
+			document.append_child(text_a, b),
+			Err(DomError::HierarchyRequest)
+		);
+		assert_eq!(
+			document.append_child(a, doc),
+			Err(DomError::HierarchyRequest)
+		);
+
+		// A document can only have one root element
+		assert_eq!(
+			document.append_child(doc, b),
+			Err(DomError::HierarchyRequest)
+		);
+		assert_eq!(document.children(div).count(), 2);
+	}
+
+	#[test]
+	fn refuses_to_put_a_template_inside_its_contents() {
+		let p = elem("p".into(), HashMap::new(), vec![]);
+		let node = elem(
+			"div".into(),
+			HashMap::new(),
+			vec![template(HashMap::new(), vec![p])],
+		);
+		let mut document = Document::new(vec![node], QuirksMode::NoQuirks);
+		let div = document.root().unwrap();
+		let template = document.first_child(div).unwrap();
+		let contents = document.template_contents(template).unwrap();
+		let p = document.first_child(contents).unwrap();
+
+		for &(parent, child) in &[
+			(contents, template),
+			(p, template),
+			(p, div),
+			(div, contents),
+		] {
+			assert_eq!(
+				document.append_child(parent, child),
+				Err(DomError::HierarchyRequest)
+			);
+		}
+		assert_eq!(
+			document.outer_html(div),
+			"<div><template><p></p></template></div>"
+		);
+
+		// Other nodes can still go in and out of the contents
+		let span = document.create_node(elem("span".into(), HashMap::new(), vec![]));
+		document.append_child(contents, span).unwrap();
+		document.append_child(div, p).unwrap();
+		assert_eq!(document.parent(p), Some(div));
+	}
+
+	#[test]
+	fn edits_attributes_and_text() {
+		let (mut document, div, a, b) = paragraphs();
+		document.set_attribute(div, "class", "x").unwrap();
+		document.set_attribute(div, "id", "y").unwrap();
+		assert_eq!(document.remove_attribute(div, "id"), Ok(Some("y".into())));
+		assert_eq!(document.remove_attribute(div, "id"), Ok(None));
+		let text_a = document.first_child(a).unwrap();
+		assert_eq!(
+			document.set_attribute(text_a, "id", "z"),
+			Err(DomError::InvalidNodeType)
+		);
+
+		document.set_text(text_a, "c");
+		document.set_text(b, "<d>");
+		assert_eq!(
+			html(&document, div),
+			"<div class=\"x\"><p>c</p><p>&lt;d&gt;</p></div>"
+		);
+		document.set_text(div, "");
+		assert_eq!(document.first_child(div), None);
+	}
+
+	#[test]
+	fn normalize_merges_adjacent_text() {
+		let (mut document, div, a, b) = paragraphs();
+		let text_b = document.first_child(b).unwrap();
+		document.append_child(a, text_b).unwrap();
+		let empty = document.create_node(text(String::new()));
+		document.append_child(b, empty).unwrap();
+
+		document.normalize(div);
+		assert_eq!(document.children(a).count(), 1);
+		assert_eq!(
+			document.node(document.first_child(a).unwrap()).node_type,
+			NodeType::Text("ab".into())
+		);
+		assert_eq!(document.first_child(b), None);
+	}
+
+	#[test]
+	fn gets_text_content() {
+		let source =
+			"<div>a<!--b--><p>c<?pi d?></p><template>e</template><svg><![CDATA[f]]></svg></div>";
+		let (document, _) = html::parse_whatwg(source.into());
+		let div = document
+			.query_selector(document.document_node(), "div")
+			.unwrap()
+			.unwrap();
+		assert_eq!(document.text_content(div), "acf");
+
+		let comment = document
+			.next_sibling(document.first_child(div).unwrap())
+			.unwrap();
+		assert_eq!(document.text_content(comment), "b");
+		assert_eq!(document.text_content(document.document_node()), "acf");
+	}
 }
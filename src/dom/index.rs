@@ -0,0 +1,258 @@
+use dom::{Document, ElementData, NodeId, NodeType};
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+// Looking up elements by id, class and tag name without walking the tree. Only elements which are
+// in the document are indexed, so detached nodes and the contents of templates aren't found.
+// https://dom.spec.whatwg.org/#dom-nonelementparentnode-getelementbyid
+
+type Entries = HashMap<String, HashSet<NodeId>>;
+
+#[derive(Default)]
+pub struct Index {
+	ids: Entries,
+	classes: Entries,
+	tag_names: Entries,
+
+	// Where each node in the document comes in document order, indexed by `NodeId`, for sorting
+	// the elements found. It's worked out when it's first needed after the document changes.
+	positions: RefCell<Option<Vec<usize>>>,
+}
+
+impl Index {
+	fn insert(&mut self, id: NodeId, data: &ElementData) {
+		if let Some(value) = data.id().filter(|value| !value.is_empty()) {
+			self.ids.entry(value.clone()).or_default().insert(id);
+		}
+		for class in data.classes() {
+			self.classes
+				.entry(class.to_string())
+				.or_default()
+				.insert(id);
+		}
+		self.tag_names
+			.entry(data.tag_name.clone())
+			.or_default()
+			.insert(id);
+	}
+
+	fn remove(&mut self, id: NodeId, data: &ElementData) {
+		if let Some(value) = data.id() {
+			remove_entry(&mut self.ids, value, id);
+		}
+		for class in data.classes() {
+			remove_entry(&mut self.classes, class, id);
+		}
+		remove_entry(&mut self.tag_names, &data.tag_name, id);
+	}
+}
+
+fn remove_entry(entries: &mut Entries, key: &str, id: NodeId) {
+	if let Some(ids) = entries.get_mut(key) {
+		ids.remove(&id);
+		if ids.is_empty() {
+			entries.remove(key);
+		}
+	}
+}
+
+// Index methods
+impl Document {
+	// Is the node in the document, rather than detached or in a template's contents?
+	pub fn is_connected(&self, id: NodeId) -> bool {
+		self.is_inclusive_ancestor(self.document_node(), id)
+	}
+
+	// Add an element to the index, or take it out
+	pub(super) fn index_element(&mut self, id: NodeId, add: bool) {
+		let Document {
+			ref nodes,
+			ref mut index,
+			..
+		} = *self;
+		if let NodeType::Element(ref data) = nodes[id.0].node_type {
+			if add {
+				index.insert(id, data);
+			} else {
+				index.remove(id, data);
+			}
+		}
+	}
+
+	// Add every element in a subtree which has just been connected to the index, or take them out
+	// of it before the subtree is disconnected
+	pub(super) fn index_subtree(&mut self, id: NodeId, add: bool) {
+		*self.index.positions.get_mut() = None;
+		let ids: Vec<NodeId> = iter::once(id).chain(self.descendants(id)).collect();
+		for id in ids {
+			self.index_element(id, add);
+		}
+	}
+
+	// The first element in the document with the id
+	pub fn get_element_by_id(&self, id: &str) -> Option<NodeId> {
+		let ids = self.index.ids.get(id)?;
+		if ids.len() == 1 {
+			return ids.iter().next().copied();
+		}
+		let positions = self.positions();
+		ids.iter().copied().min_by_key(|id| positions[id.0])
+	}
+
+	// The elements which have all of the space-separated classes, in document order
+	pub fn get_elements_by_class_name(&self, names: &str) -> Vec<NodeId> {
+		let sets: Option<Vec<&HashSet<NodeId>>> = names
+			.split(|c: char| c.is_ascii_whitespace())
+			.filter(|name| !name.is_empty())
+			.map(|name| self.index.classes.get(name))
+			.collect();
+		let mut sets = match sets {
+			Some(sets) => sets,
+			None => return Vec::new(),
+		};
+
+		// Start from the rarest class, and check the others on each element
+		sets.sort_by_key(|ids| ids.len());
+		let (rarest, others) = match sets.split_first() {
+			Some(sets) => sets,
+			None => return Vec::new(),
+		};
+		let found = rarest
+			.iter()
+			.copied()
+			.filter(|id| others.iter().all(|ids| ids.contains(id)))
+			.collect();
+		self.in_document_order(found)
+	}
+
+	// The elements with the tag name, in document order. HTML names ignore case, and `*` finds
+	// every element.
+	pub fn get_elements_by_tag_name(&self, name: &str) -> Vec<NodeId> {
+		let found = if name == "*" {
+			self.index.tag_names.values().flatten().copied().collect()
+		} else {
+			let lowercase = name.to_ascii_lowercase();
+			let is_html = |id: NodeId| self.element(id).is_some_and(ElementData::is_html);
+			let exact = self.index.tag_names.get(name).into_iter().flatten();
+			let folded = self.index.tag_names.get(&lowercase).into_iter().flatten();
+
+			// An HTML element with a lowercase name can be in both sets
+			exact
+				.copied()
+				.filter(|&id| !is_html(id))
+				.chain(folded.copied().filter(|&id| is_html(id)))
+				.collect()
+		};
+		self.in_document_order(found)
+	}
+
+	fn in_document_order(&self, mut ids: Vec<NodeId>) -> Vec<NodeId> {
+		let positions = self.positions();
+		ids.sort_by_key(|id| positions[id.0]);
+		ids
+	}
+
+	// Where each node comes in document order, found with one walk of the document if it's
+	// changed since the last time
+	fn positions(&self) -> Ref<'_, Vec<usize>> {
+		if self.index.positions.borrow().is_none() {
+			let mut positions = vec![usize::MAX; self.nodes.len()];
+			for (position, id) in self.descendants(self.document_node()).enumerate() {
+				positions[id.0] = position;
+			}
+			*self.index.positions.borrow_mut() = Some(positions);
+		}
+		Ref::map(self.index.positions.borrow(), |positions| {
+			positions.as_ref().unwrap()
+		})
+	}
+}
+
+#[cfg(test)]
+mod index_tests {
+	use super::*;
+	use dom;
+	use html;
+	use std::collections::HashMap;
+
+	fn parse(source: &str) -> Document {
+		html::parse_whatwg(source.into()).0
+	}
+
+	fn texts(document: &Document, ids: &[NodeId]) -> Vec<String> {
+		ids.iter().map(|&id| document.inner_html(id)).collect()
+	}
+
+	#[test]
+	fn looks_up_elements() {
+		let document = parse(
+			"<p id=a class='x y'>1</p><div><p class=y>2</p><span id=a class='y  x'>3</span></div>\
+			 <svg><foreignObject id=b></foreignObject></svg><template><p id=c></p></template>",
+		);
+		let first = document.get_element_by_id("a").unwrap();
+		assert_eq!(texts(&document, &[first]), vec!["1"]);
+		assert!(document.get_element_by_id("b").is_some());
+		assert_eq!(document.get_element_by_id("c"), None);
+		assert_eq!(document.get_element_by_id(""), None);
+
+		assert_eq!(
+			texts(&document, &document.get_elements_by_class_name("y")),
+			vec!["1", "2", "3"]
+		);
+		assert_eq!(
+			texts(&document, &document.get_elements_by_class_name(" x y ")),
+			vec!["1", "3"]
+		);
+		assert_eq!(document.get_elements_by_class_name("z"), vec![]);
+		assert_eq!(document.get_elements_by_class_name("x\u{A0}y"), vec![]);
+
+		assert_eq!(
+			texts(&document, &document.get_elements_by_tag_name("P")),
+			vec!["1", "2"]
+		);
+		assert_eq!(document.get_elements_by_tag_name("foreignObject").len(), 1);
+		assert_eq!(document.get_elements_by_tag_name("foreignobject").len(), 0);
+		assert_eq!(document.get_elements_by_tag_name("*").len(), 10);
+
+		// Classes can be separated by any whitespace in the attribute as well
+		let document = parse("<p class='a\tb\nc'>x</p>");
+		assert_eq!(document.get_elements_by_class_name("b").len(), 1);
+		assert_eq!(document.get_elements_by_class_name("c a").len(), 1);
+	}
+
+	#[test]
+	fn keeps_the_index_up_to_date() {
+		let mut document = parse("<div id=main><p class=item>1</p></div>");
+		let main = document.get_element_by_id("main").unwrap();
+		let mut attributes = HashMap::new();
+		attributes.insert("class".to_string(), "item".to_string());
+		let item = document.create_node(dom::elem(
+			"p".into(),
+			attributes,
+			vec![dom::elem("b".into(), HashMap::new(), vec![])],
+		));
+		assert_eq!(document.get_elements_by_class_name("item").len(), 1);
+
+		document
+			.insert_before(main, item, document.first_child(main))
+			.unwrap();
+		let items = document.get_elements_by_class_name("item");
+		assert_eq!(items[0], item);
+		assert_eq!(items.len(), 2);
+		assert_eq!(document.get_elements_by_tag_name("b").len(), 1);
+
+		document.set_attribute(item, "id", "new").unwrap();
+		document.set_attribute(item, "class", "other").unwrap();
+		assert_eq!(document.get_element_by_id("new"), Some(item));
+		assert_eq!(document.get_elements_by_class_name("item").len(), 1);
+		document.remove_attribute(item, "id").unwrap();
+		assert_eq!(document.get_element_by_id("new"), None);
+
+		document.remove_child(main, item).unwrap();
+		assert_eq!(document.get_elements_by_tag_name("b"), vec![]);
+		document.set_text(main, "gone");
+		assert_eq!(document.get_elements_by_class_name("item"), vec![]);
+		assert_eq!(document.get_element_by_id("main"), Some(main));
+	}
+}
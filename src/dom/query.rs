@@ -0,0 +1,154 @@
+use css::{self, Selector};
+use dom::{Document, Node, NodeId, NodeType};
+use parser::ParseError;
+use std::iter;
+use style;
+
+// Finding elements with CSS selectors, like `querySelector`. Matching is the same as it is for
+// style rules.
+// https://dom.spec.whatwg.org/#scope-match-a-selectors-string
+
+fn matches_any(node_type: &NodeType, selectors: &[Selector]) -> bool {
+	match *node_type {
+		NodeType::Element(ref data) => selectors
+			.iter()
+			.any(|selector| style::matches(data, selector)),
+		_ => false,
+	}
+}
+
+// Query methods
+impl Document {
+	// The first of the node's descendants which matches the selectors
+	pub fn query_selector(
+		&self,
+		id: NodeId,
+		selectors: &str,
+	) -> Result<Option<NodeId>, ParseError> {
+		let selectors = css::parse_selectors(selectors.to_string())?;
+		Ok(self
+			.descendants(id)
+			.find(|&descendant| matches_any(&self.node(descendant).node_type, &selectors)))
+	}
+
+	// All of the node's descendants which match the selectors, in document order
+	pub fn query_selector_all(
+		&self,
+		id: NodeId,
+		selectors: &str,
+	) -> Result<Vec<NodeId>, ParseError> {
+		let selectors = css::parse_selectors(selectors.to_string())?;
+		Ok(self
+			.descendants(id)
+			.filter(|&descendant| matches_any(&self.node(descendant).node_type, &selectors))
+			.collect())
+	}
+
+	// Is the node an element which matches the selectors?
+	pub fn matches(&self, id: NodeId, selectors: &str) -> Result<bool, ParseError> {
+		let selectors = css::parse_selectors(selectors.to_string())?;
+		Ok(matches_any(&self.node(id).node_type, &selectors))
+	}
+
+	// The node itself or its closest ancestor which matches the selectors
+	pub fn closest(&self, id: NodeId, selectors: &str) -> Result<Option<NodeId>, ParseError> {
+		let selectors = css::parse_selectors(selectors.to_string())?;
+		Ok(iter::once(id)
+			.chain(self.ancestors(id))
+			.find(|&ancestor| matches_any(&self.node(ancestor).node_type, &selectors)))
+	}
+}
+
+// The same queries for a tree of nodes outside a document. Without parent links there's no
+// `closest`.
+impl Node {
+	pub fn query_selector(&self, selectors: &str) -> Result<Option<&Node>, ParseError> {
+		Ok(self.query_selector_all(selectors)?.into_iter().next())
+	}
+
+	pub fn query_selector_all(&self, selectors: &str) -> Result<Vec<&Node>, ParseError> {
+		let selectors = css::parse_selectors(selectors.to_string())?;
+		let mut found = Vec::new();
+		collect_matches(self, &selectors, &mut found);
+		Ok(found)
+	}
+
+	pub fn matches(&self, selectors: &str) -> Result<bool, ParseError> {
+		let selectors = css::parse_selectors(selectors.to_string())?;
+		Ok(matches_any(&self.node_type, &selectors))
+	}
+}
+
+// Add the node's descendants which match to `found`, in document order
+fn collect_matches<'a>(node: &'a Node, selectors: &[Selector], found: &mut Vec<&'a Node>) {
+	for child in &node.children {
+		if matches_any(&child.node_type, selectors) {
+			found.push(child);
+		}
+		collect_matches(child, selectors, found);
+	}
+}
+
+#[cfg(test)]
+mod query_tests {
+	use super::*;
+	use html;
+
+	fn tag_names(document: &Document, ids: &[NodeId]) -> Vec<String> {
+		ids.iter()
+			.map(|&id| document.element(id).unwrap().tag_name.clone())
+			.collect()
+	}
+
+	#[test]
+	fn finds_elements_in_a_document() {
+		let (document, _) = html::parse_whatwg(
+			"<div id=main><p class=note>a</p><SPAN class='note x'>b</SPAN><p>c</p></div>".into(),
+		);
+		let root = document.document_node();
+		let main = document.query_selector(root, "#main").unwrap().unwrap();
+		assert_eq!(document.element(main).unwrap().tag_name, "div");
+
+		let notes = document.query_selector_all(root, "span, .note").unwrap();
+		assert_eq!(tag_names(&document, &notes), vec!["p", "span"]);
+		assert_eq!(document.query_selector(main, "em").unwrap(), None);
+
+		// Only descendants are searched
+		assert_eq!(document.query_selector_all(main, "div").unwrap(), vec![]);
+		assert!(document.matches(main, "div#main").unwrap());
+		assert!(!document.matches(main, ".note").unwrap());
+
+		// Classes can be separated by any whitespace
+		let (document, _) = html::parse_whatwg("<p class='a\tb\nc'>x</p>".into());
+		let root = document.document_node();
+		let p = document.query_selector(root, "p").unwrap();
+		assert_eq!(document.query_selector(root, ".a.b").unwrap(), p);
+		assert_eq!(document.query_selector_all(root, ".c").unwrap().len(), 1);
+	}
+
+	#[test]
+	fn finds_the_closest_matching_ancestor() {
+		let (document, _) =
+			html::parse_whatwg("<section class=a><div class=a><p>x</p></div></section>".into());
+		let root = document.document_node();
+		let p = document.query_selector(root, "p").unwrap().unwrap();
+		let div = document.parent(p).unwrap();
+		assert_eq!(document.closest(p, ".a").unwrap(), Some(div));
+		assert_eq!(document.closest(p, "p").unwrap(), Some(p));
+		let section = document.closest(p, "section").unwrap().unwrap();
+		assert_eq!(document.parent(div), Some(section));
+		assert_eq!(document.closest(p, "table").unwrap(), None);
+	}
+
+	#[test]
+	fn finds_elements_in_a_tree() {
+		let root =
+			html::parse("<ul><li class=a>1</li><li><b class=a>2</b></li></ul>".into()).unwrap();
+		let found = root.query_selector_all(".a").unwrap();
+		assert_eq!(found.len(), 2);
+		assert!(found[1].matches("b").unwrap());
+		assert_eq!(root.query_selector("li").unwrap(), Some(&root.children[0]));
+		assert!(root.matches("ul").unwrap());
+		assert!(root.query_selector("ul li").is_err());
+	}
+}
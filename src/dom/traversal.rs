@@ -0,0 +1,630 @@
+use dom::{Document, NodeId, NodeType};
+
+// Ways of walking through the nodes of a document. Like the rest of the DOM, none of these go into
+// the contents of `<template>` elements.
+// https://dom.spec.whatwg.org/#traversal
+
+// Bits for `what_to_show`, saying which types of node a `TreeWalker` or `NodeIterator` stops at
+// https://dom.spec.whatwg.org/#interface-nodefilter
+pub const SHOW_ALL: u32 = 0xFFFF_FFFF;
+pub const SHOW_ELEMENT: u32 = 0x1;
+pub const SHOW_TEXT: u32 = 0x4;
+pub const SHOW_CDATA_SECTION: u32 = 0x8;
+pub const SHOW_PROCESSING_INSTRUCTION: u32 = 0x40;
+pub const SHOW_COMMENT: u32 = 0x80;
+pub const SHOW_DOCUMENT: u32 = 0x100;
+pub const SHOW_DOCUMENT_TYPE: u32 = 0x200;
+pub const SHOW_DOCUMENT_FRAGMENT: u32 = 0x400;
+
+fn show_bit(node_type: &NodeType) -> u32 {
+	match *node_type {
+		NodeType::Element(_) => SHOW_ELEMENT,
+		NodeType::Text(_) => SHOW_TEXT,
+		NodeType::CData(_) => SHOW_CDATA_SECTION,
+		NodeType::ProcessingInstruction { .. } => SHOW_PROCESSING_INSTRUCTION,
+		NodeType::Comment(_) => SHOW_COMMENT,
+		NodeType::Document => SHOW_DOCUMENT,
+		NodeType::Doctype(_) => SHOW_DOCUMENT_TYPE,
+		NodeType::DocumentFragment => SHOW_DOCUMENT_FRAGMENT,
+	}
+}
+
+// Reaching and leaving a node while traversing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+	Open(NodeId),
+	Close(NodeId),
+}
+
+// What a filter makes of a node. Skipping a node still visits its children, rejecting it doesn't.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterResult {
+	Accept,
+	Reject,
+	Skip,
+}
+
+// Traversal methods
+impl Document {
+	// The node's descendants, in document order
+	pub fn descendants(&self, id: NodeId) -> Descendants<'_> {
+		let mut traverse = self.traverse(id);
+		traverse.next();
+		Descendants(traverse)
+	}
+
+	// The node's parent, its parent's parent and so on up to the document
+	pub fn ancestors(&self, id: NodeId) -> Ancestors<'_> {
+		Ancestors {
+			document: self,
+			next: self.parent(id),
+		}
+	}
+
+	// The siblings after the node, in order
+	pub fn following_siblings(&self, id: NodeId) -> FollowingSiblings<'_> {
+		FollowingSiblings {
+			document: self,
+			next: self.next_sibling(id),
+		}
+	}
+
+	// The node and its descendants, with an event when each one is reached and another when
+	// everything inside it has been visited. `Open` events are in pre-order and `Close` events
+	// are in post-order.
+	pub fn traverse(&self, id: NodeId) -> Traverse<'_> {
+		Traverse {
+			document: self,
+			root: id,
+			next: Some(Event::Open(id)),
+		}
+	}
+}
+
+pub struct Traverse<'a> {
+	document: &'a Document,
+	root: NodeId,
+	next: Option<Event>,
+}
+
+impl<'a> Iterator for Traverse<'a> {
+	type Item = Event;
+
+	fn next(&mut self) -> Option<Event> {
+		let event = self.next.take()?;
+		self.next = match event {
+			Event::Open(id) => match self.document.first_child(id) {
+				Some(child) => Some(Event::Open(child)),
+				None => Some(Event::Close(id)),
+			},
+			Event::Close(id) if id == self.root => None,
+			Event::Close(id) => match self.document.next_sibling(id) {
+				Some(sibling) => Some(Event::Open(sibling)),
+				None => self.document.parent(id).map(Event::Close),
+			},
+		};
+		Some(event)
+	}
+}
+
+pub struct Descendants<'a>(Traverse<'a>);
+
+impl<'a> Iterator for Descendants<'a> {
+	type Item = NodeId;
+
+	fn next(&mut self) -> Option<NodeId> {
+		loop {
+			match self.0.next()? {
+				Event::Open(id) => return Some(id),
+				Event::Close(_) => {}
+			}
+		}
+	}
+}
+
+pub struct Ancestors<'a> {
+	document: &'a Document,
+	next: Option<NodeId>,
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+	type Item = NodeId;
+
+	fn next(&mut self) -> Option<NodeId> {
+		let id = self.next?;
+		self.next = self.document.parent(id);
+		Some(id)
+	}
+}
+
+pub struct FollowingSiblings<'a> {
+	document: &'a Document,
+	next: Option<NodeId>,
+}
+
+impl<'a> Iterator for FollowingSiblings<'a> {
+	type Item = NodeId;
+
+	fn next(&mut self) -> Option<NodeId> {
+		let id = self.next?;
+		self.next = self.document.next_sibling(id);
+		Some(id)
+	}
+}
+
+type Filter<'f> = Box<dyn Fn(&Document, NodeId) -> FilterResult + 'f>;
+
+// A move from one node to another, like `Document::next_sibling`
+type Step = fn(&Document, NodeId) -> Option<NodeId>;
+
+// Which nodes a `TreeWalker` or `NodeIterator` stops at: those whose type is in `what_to_show`,
+// and which the filter, if there is one, accepts
+struct NodeFilter<'f> {
+	what_to_show: u32,
+	filter: Option<Filter<'f>>,
+}
+
+impl<'f> NodeFilter<'f> {
+	// https://dom.spec.whatwg.org/#concept-node-filter
+	fn accept(&self, document: &Document, id: NodeId) -> FilterResult {
+		if show_bit(&document.node(id).node_type) & self.what_to_show == 0 {
+			return FilterResult::Skip;
+		}
+		match self.filter {
+			Some(ref filter) => filter(document, id),
+			None => FilterResult::Accept,
+		}
+	}
+}
+
+// Moves around the nodes under `root` which are shown and accepted by the filter, as if the
+// others weren't there. The walker doesn't borrow the document, so it can be changed in between
+// moves.
+// https://dom.spec.whatwg.org/#interface-treewalker
+pub struct TreeWalker<'f> {
+	pub root: NodeId,
+	pub current: NodeId,
+	filter: NodeFilter<'f>,
+}
+
+impl<'f> TreeWalker<'f> {
+	pub fn new(root: NodeId, what_to_show: u32) -> TreeWalker<'f> {
+		TreeWalker {
+			root,
+			current: root,
+			filter: NodeFilter {
+				what_to_show,
+				filter: None,
+			},
+		}
+	}
+
+	pub fn with_filter<F>(mut self, filter: F) -> TreeWalker<'f>
+	where
+		F: Fn(&Document, NodeId) -> FilterResult + 'f,
+	{
+		self.filter.filter = Some(Box::new(filter));
+		self
+	}
+
+	fn accept(&self, document: &Document, id: NodeId) -> FilterResult {
+		self.filter.accept(document, id)
+	}
+
+	// Move to the closest accepted ancestor, without leaving the root
+	pub fn parent_node(&mut self, document: &Document) -> Option<NodeId> {
+		let mut node = self.current;
+		while node != self.root {
+			node = document.parent(node)?;
+			if self.accept(document, node) == FilterResult::Accept {
+				self.current = node;
+				return Some(node);
+			}
+		}
+		None
+	}
+
+	pub fn first_child(&mut self, document: &Document) -> Option<NodeId> {
+		self.traverse_children(document, true)
+	}
+
+	pub fn last_child(&mut self, document: &Document) -> Option<NodeId> {
+		self.traverse_children(document, false)
+	}
+
+	// https://dom.spec.whatwg.org/#concept-traverse-children
+	fn traverse_children(&mut self, document: &Document, first: bool) -> Option<NodeId> {
+		let (start, step): (Step, Step) = if first {
+			(Document::first_child, Document::next_sibling)
+		} else {
+			(Document::last_child, Document::previous_sibling)
+		};
+		let mut node = start(document, self.current)?;
+		loop {
+			match self.accept(document, node) {
+				FilterResult::Accept => {
+					self.current = node;
+					return Some(node);
+				}
+				FilterResult::Skip => {
+					if let Some(child) = start(document, node) {
+						node = child;
+						continue;
+					}
+				}
+				FilterResult::Reject => {}
+			}
+			loop {
+				if let Some(sibling) = step(document, node) {
+					node = sibling;
+					break;
+				}
+				match document.parent(node) {
+					Some(parent) if parent != self.root && parent != self.current => node = parent,
+					_ => return None,
+				}
+			}
+		}
+	}
+
+	pub fn next_sibling(&mut self, document: &Document) -> Option<NodeId> {
+		self.traverse_siblings(document, true)
+	}
+
+	pub fn previous_sibling(&mut self, document: &Document) -> Option<NodeId> {
+		self.traverse_siblings(document, false)
+	}
+
+	// https://dom.spec.whatwg.org/#concept-traverse-siblings
+	fn traverse_siblings(&mut self, document: &Document, next: bool) -> Option<NodeId> {
+		let (step, start): (Step, Step) = if next {
+			(Document::next_sibling, Document::first_child)
+		} else {
+			(Document::previous_sibling, Document::last_child)
+		};
+		let mut node = self.current;
+		if node == self.root {
+			return None;
+		}
+		loop {
+			let mut sibling = step(document, node);
+			while let Some(current) = sibling {
+				node = current;
+				let result = self.accept(document, node);
+				if result == FilterResult::Accept {
+					self.current = node;
+					return Some(node);
+				}
+				sibling = start(document, node);
+				if result == FilterResult::Reject || sibling.is_none() {
+					sibling = step(document, node);
+				}
+			}
+			node = document.parent(node)?;
+			if node == self.root || self.accept(document, node) == FilterResult::Accept {
+				return None;
+			}
+		}
+	}
+
+	// Move to the previous accepted node in document order
+	// https://dom.spec.whatwg.org/#dom-treewalker-previousnode
+	pub fn previous_node(&mut self, document: &Document) -> Option<NodeId> {
+		let mut node = self.current;
+		while node != self.root {
+			let mut sibling = document.previous_sibling(node);
+			while let Some(current) = sibling {
+				node = current;
+				let mut result = self.accept(document, node);
+				while result != FilterResult::Reject {
+					match document.last_child(node) {
+						Some(child) => {
+							node = child;
+							result = self.accept(document, node);
+						}
+						None => break,
+					}
+				}
+				if result == FilterResult::Accept {
+					self.current = node;
+					return Some(node);
+				}
+				sibling = document.previous_sibling(node);
+			}
+			node = document.parent(node)?;
+			if self.accept(document, node) == FilterResult::Accept {
+				self.current = node;
+				return Some(node);
+			}
+		}
+		None
+	}
+
+	// Move to the next accepted node in document order
+	// https://dom.spec.whatwg.org/#dom-treewalker-nextnode
+	pub fn next_node(&mut self, document: &Document) -> Option<NodeId> {
+		let mut node = self.current;
+		let mut result = FilterResult::Accept;
+		loop {
+			while result != FilterResult::Reject {
+				match document.first_child(node) {
+					Some(child) => {
+						node = child;
+						result = self.accept(document, node);
+						if result == FilterResult::Accept {
+							self.current = node;
+							return Some(node);
+						}
+					}
+					None => break,
+				}
+			}
+
+			// The next node which isn't a descendant, without leaving the root
+			let mut ancestor = node;
+			loop {
+				if ancestor == self.root {
+					return None;
+				}
+				if let Some(sibling) = document.next_sibling(ancestor) {
+					node = sibling;
+					break;
+				}
+				ancestor = document.parent(ancestor)?;
+			}
+			result = self.accept(document, node);
+			if result == FilterResult::Accept {
+				self.current = node;
+				return Some(node);
+			}
+		}
+	}
+}
+
+// Steps through the nodes under `root`, including the root itself, which are shown and accepted
+// by the filter. A rejected node's descendants are still visited, as it's a flat list. Unlike the
+// DOM's iterator, this one isn't moved along when the node it's on is removed.
+// https://dom.spec.whatwg.org/#interface-nodeiterator
+pub struct NodeIterator<'f> {
+	pub root: NodeId,
+	pub reference: NodeId,
+	pub pointer_before_reference: bool,
+	filter: NodeFilter<'f>,
+}
+
+impl<'f> NodeIterator<'f> {
+	pub fn new(root: NodeId, what_to_show: u32) -> NodeIterator<'f> {
+		NodeIterator {
+			root,
+			reference: root,
+			pointer_before_reference: true,
+			filter: NodeFilter {
+				what_to_show,
+				filter: None,
+			},
+		}
+	}
+
+	pub fn with_filter<F>(mut self, filter: F) -> NodeIterator<'f>
+	where
+		F: Fn(&Document, NodeId) -> FilterResult + 'f,
+	{
+		self.filter.filter = Some(Box::new(filter));
+		self
+	}
+
+	pub fn next_node(&mut self, document: &Document) -> Option<NodeId> {
+		self.traverse(document, true)
+	}
+
+	pub fn previous_node(&mut self, document: &Document) -> Option<NodeId> {
+		self.traverse(document, false)
+	}
+
+	// https://dom.spec.whatwg.org/#concept-nodeiterator-traverse
+	fn traverse(&mut self, document: &Document, next: bool) -> Option<NodeId> {
+		let mut node = self.reference;
+		let mut before_node = self.pointer_before_reference;
+		loop {
+			if next {
+				if before_node {
+					before_node = false;
+				} else {
+					node = self.following(document, node)?;
+				}
+			} else if before_node {
+				node = self.preceding(document, node)?;
+			} else {
+				before_node = true;
+			}
+			if self.filter.accept(document, node) == FilterResult::Accept {
+				break;
+			}
+		}
+		self.reference = node;
+		self.pointer_before_reference = before_node;
+		Some(node)
+	}
+
+	// The node after `id` in document order, without leaving the root
+	fn following(&self, document: &Document, id: NodeId) -> Option<NodeId> {
+		if let Some(child) = document.first_child(id) {
+			return Some(child);
+		}
+		let mut node = id;
+		loop {
+			if node == self.root {
+				return None;
+			}
+			if let Some(sibling) = document.next_sibling(node) {
+				return Some(sibling);
+			}
+			node = document.parent(node)?;
+		}
+	}
+
+	// The node before `id` in document order, without leaving the root
+	fn preceding(&self, document: &Document, id: NodeId) -> Option<NodeId> {
+		if id == self.root {
+			return None;
+		}
+		match document.previous_sibling(id) {
+			Some(mut node) => {
+				while let Some(child) = document.last_child(node) {
+					node = child;
+				}
+				Some(node)
+			}
+			None => document.parent(id),
+		}
+	}
+}
+
+#[cfg(test)]
+mod traversal_tests {
+	use super::*;
+	use dom::{self, QuirksMode};
+	use html;
+
+	fn parse(source: &str) -> Document {
+		html::parse_whatwg(source.into()).0
+	}
+
+	// The text of a text node or the tag name of an element
+	fn name(document: &Document, id: NodeId) -> String {
+		match document.node(id).node_type {
+			NodeType::Element(ref data) => data.tag_name.clone(),
+			NodeType::Text(ref text) => text.clone(),
+			_ => "?".into(),
+		}
+	}
+
+	fn names<I: Iterator<Item = NodeId>>(document: &Document, ids: I) -> Vec<String> {
+		ids.map(|id| name(document, id)).collect()
+	}
+
+	fn find(document: &Document, tag_name: &str) -> NodeId {
+		document
+			.descendants(document.document_node())
+			.find(|&id| name(document, id) == tag_name)
+			.unwrap()
+	}
+
+	#[test]
+	fn iterates_descendants_ancestors_and_siblings() {
+		let document = parse("<ul><li>a<b>b</b></li><li>c</li><li>d</li></ul>");
+		let ul = find(&document, "ul");
+		assert_eq!(
+			names(&document, document.descendants(ul)),
+			vec!["li", "a", "b", "b", "li", "c", "li", "d"]
+		);
+
+		let b = find(&document, "b");
+		assert_eq!(
+			names(&document, document.ancestors(b)),
+			vec!["li", "ul", "body", "html", "?"]
+		);
+
+		let li = document.first_child(ul).unwrap();
+		assert_eq!(
+			names(&document, document.following_siblings(li)),
+			vec!["li", "li"]
+		);
+		assert_eq!(document.following_siblings(ul).count(), 0);
+	}
+
+	#[test]
+	fn traverses_with_open_and_close_events() {
+		let document = Document::new(
+			vec![dom::elem(
+				"p".into(),
+				Default::default(),
+				vec![dom::text("a".into())],
+			)],
+			QuirksMode::NoQuirks,
+		);
+		let p = document.root().unwrap();
+		let a = document.first_child(p).unwrap();
+		assert_eq!(
+			document.traverse(p).collect::<Vec<_>>(),
+			vec![
+				Event::Open(p),
+				Event::Open(a),
+				Event::Close(a),
+				Event::Close(p)
+			]
+		);
+	}
+
+	#[test]
+	fn walks_shown_and_accepted_nodes() {
+		let document = parse("<div><p>a<i>b</i></p><span><em>c</em></span><p>d</p></div>");
+		let div = find(&document, "div");
+
+		// Rejecting the span hides the em inside it, while skipping would have kept it
+		let mut walker = TreeWalker::new(div, SHOW_ELEMENT).with_filter(|document, id| {
+			match document.element(id) {
+				Some(data) if data.tag_name == "span" => FilterResult::Reject,
+				Some(data) if data.tag_name == "p" => FilterResult::Skip,
+				_ => FilterResult::Accept,
+			}
+		});
+		let mut visited = Vec::new();
+		while let Some(id) = walker.next_node(&document) {
+			visited.push(id);
+		}
+		assert_eq!(names(&document, visited.into_iter()), vec!["i"]);
+		assert_eq!(walker.previous_node(&document), Some(div));
+
+		// The skipped paragraph's children take its place
+		let mut walker = TreeWalker::new(div, SHOW_ELEMENT | SHOW_TEXT).with_filter(
+			|document, id| match document.element(id) {
+				Some(data) if data.tag_name == "p" => FilterResult::Skip,
+				_ => FilterResult::Accept,
+			},
+		);
+		assert_eq!(
+			names(&document, walker.first_child(&document).into_iter()),
+			vec!["a"]
+		);
+		assert_eq!(
+			names(&document, walker.next_sibling(&document).into_iter()),
+			vec!["i"]
+		);
+		assert_eq!(
+			names(&document, walker.next_sibling(&document).into_iter()),
+			vec!["span"]
+		);
+		assert_eq!(walker.parent_node(&document), Some(div));
+		assert_eq!(
+			names(&document, walker.last_child(&document).into_iter()),
+			vec!["d"]
+		);
+		assert_eq!(walker.parent_node(&document), Some(div));
+		assert_eq!(walker.parent_node(&document), None);
+	}
+
+	#[test]
+	fn iterates_nodes_in_both_directions() {
+		let document = parse("<p>a<b>b</b><!--c--></p>");
+		let p = find(&document, "p");
+		let mut iterator = NodeIterator::new(p, SHOW_ALL & !SHOW_COMMENT);
+		let mut forwards = Vec::new();
+		while let Some(id) = iterator.next_node(&document) {
+			forwards.push(id);
+		}
+		assert_eq!(
+			names(&document, forwards.into_iter()),
+			vec!["p", "a", "b", "b"]
+		);
+
+		let mut backwards = Vec::new();
+		while let Some(id) = iterator.previous_node(&document) {
+			backwards.push(id);
+		}
+		assert_eq!(
+			names(&document, backwards.into_iter()),
+			vec!["b", "b", "a", "p"]
+		);
+	}
+}
@@ -0,0 +1,158 @@
+use std::char;
+
+// Turning the bytes of a document into text
+// https://encoding.spec.whatwg.org/
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+	Utf8,
+	Utf16Le,
+	Utf16Be,
+	Windows1252,
+	// Never chosen from a label, since the standard treats every Latin-1 label as windows-1252,
+	// but available to callers who know their input really is ISO-8859-1
+	Iso8859_1,
+}
+
+// What windows-1252 maps 0x80 to 0x9F to. Everything else is the same as ISO-8859-1
+static WINDOWS_1252_C1: [char; 32] = [
+	'\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+	'\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+	'\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+	'\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+// The labels each encoding is known by
+// https://encoding.spec.whatwg.org/#names-and-labels
+static LABELS: &[(&str, Encoding)] = &[
+	("unicode-1-1-utf-8", Encoding::Utf8),
+	("unicode11utf8", Encoding::Utf8),
+	("unicode20utf8", Encoding::Utf8),
+	("utf-8", Encoding::Utf8),
+	("utf8", Encoding::Utf8),
+	("x-unicode20utf8", Encoding::Utf8),
+	("csunicode", Encoding::Utf16Le),
+	("iso-10646-ucs-2", Encoding::Utf16Le),
+	("ucs-2", Encoding::Utf16Le),
+	("unicode", Encoding::Utf16Le),
+	("unicodefeff", Encoding::Utf16Le),
+	("utf-16", Encoding::Utf16Le),
+	("utf-16le", Encoding::Utf16Le),
+	("unicodefffe", Encoding::Utf16Be),
+	("utf-16be", Encoding::Utf16Be),
+	("ansi_x3.4-1968", Encoding::Windows1252),
+	("ascii", Encoding::Windows1252),
+	("cp1252", Encoding::Windows1252),
+	("cp819", Encoding::Windows1252),
+	("csisolatin1", Encoding::Windows1252),
+	("ibm819", Encoding::Windows1252),
+	("iso-8859-1", Encoding::Windows1252),
+	("iso-ir-100", Encoding::Windows1252),
+	("iso8859-1", Encoding::Windows1252),
+	("iso88591", Encoding::Windows1252),
+	("iso_8859-1", Encoding::Windows1252),
+	("iso_8859-1:1987", Encoding::Windows1252),
+	("l1", Encoding::Windows1252),
+	("latin1", Encoding::Windows1252),
+	("us-ascii", Encoding::Windows1252),
+	("windows-1252", Encoding::Windows1252),
+	("x-cp1252", Encoding::Windows1252),
+];
+
+pub fn windows_1252_char(byte: u8) -> char {
+	match byte {
+		0x80..=0x9F => WINDOWS_1252_C1[byte as usize - 0x80],
+		_ => byte as char,
+	}
+}
+
+impl Encoding {
+	// Look up an encoding by one of its labels, such as `utf-8` or `latin1`
+	pub fn for_label(label: &str) -> Option<Encoding> {
+		let label = label.trim_matches(|c| matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' '));
+		LABELS
+			.iter()
+			.find(|&&(name, _)| name.eq_ignore_ascii_case(label))
+			.map(|&(_, encoding)| encoding)
+	}
+
+	pub fn is_utf16(self) -> bool {
+		self == Encoding::Utf16Le || self == Encoding::Utf16Be
+	}
+
+	// Decode `bytes`, replacing anything malformed with U+FFFD
+	pub fn decode(self, bytes: &[u8]) -> String {
+		match self {
+			Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+			Encoding::Utf16Le | Encoding::Utf16Be => {
+				let units = bytes.chunks(2).map(|pair| match *pair {
+					[a, b] if self == Encoding::Utf16Le => u16::from_le_bytes([a, b]),
+					[a, b] => u16::from_be_bytes([a, b]),
+					// A truncated code unit
+					_ => 0xFFFD,
+				});
+				char::decode_utf16(units)
+					.map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+					.collect()
+			}
+			Encoding::Windows1252 => bytes.iter().map(|&byte| windows_1252_char(byte)).collect(),
+			Encoding::Iso8859_1 => bytes.iter().map(|&byte| byte as char).collect(),
+		}
+	}
+}
+
+// Look for a byte order mark. Returns the encoding it indicates and its length
+pub fn sniff_bom(bytes: &[u8]) -> Option<(Encoding, usize)> {
+	if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+		Some((Encoding::Utf8, 3))
+	} else if bytes.starts_with(&[0xFE, 0xFF]) {
+		Some((Encoding::Utf16Be, 2))
+	} else if bytes.starts_with(&[0xFF, 0xFE]) {
+		Some((Encoding::Utf16Le, 2))
+	} else {
+		None
+	}
+}
+
+#[cfg(test)]
+mod encoding_tests {
+	use super::*;
+
+	#[test]
+	fn looks_up_labels() {
+		assert_eq!(Encoding::for_label(" UTF-8 "), Some(Encoding::Utf8));
+		assert_eq!(Encoding::for_label("latin1"), Some(Encoding::Windows1252));
+		assert_eq!(Encoding::for_label("utf-16"), Some(Encoding::Utf16Le));
+		assert_eq!(Encoding::for_label("klingon"), None);
+	}
+
+	#[test]
+	fn decodes_single_byte_encodings() {
+		let bytes = b"caf\xE9 \x93quoted\x94 \x81";
+		assert_eq!(
+			Encoding::Windows1252.decode(bytes),
+			"caf\u{E9} \u{201C}quoted\u{201D} \u{81}"
+		);
+		assert_eq!(
+			Encoding::Iso8859_1.decode(bytes),
+			"caf\u{E9} \u{93}quoted\u{94} \u{81}"
+		);
+	}
+
+	#[test]
+	fn decodes_utf16() {
+		assert_eq!(
+			Encoding::Utf16Le.decode(b"h\0i\0=\xD8\x00\xDE"),
+			"hi\u{1F600}"
+		);
+		assert_eq!(Encoding::Utf16Be.decode(b"\0h\0i\xD8"), "hi\u{FFFD}");
+	}
+
+	#[test]
+	fn sniffs_byte_order_marks() {
+		assert_eq!(sniff_bom(b"\xEF\xBB\xBFa"), Some((Encoding::Utf8, 3)));
+		assert_eq!(sniff_bom(b"\xFF\xFEa\0"), Some((Encoding::Utf16Le, 2)));
+		assert_eq!(sniff_bom(b"\xFE\xFF\0a"), Some((Encoding::Utf16Be, 2)));
+		assert_eq!(sniff_bom(b"abc"), None);
+	}
+}
@@ -1,152 +1,862 @@
+mod char_refs;
+mod entities;
+mod foreign;
+mod prescan;
+mod quirks;
+pub mod serializer;
+pub mod tokenizer;
+mod tree_builder;
+
+use css;
 use dom;
-use parser::Parser;
+use encoding::{self, Encoding};
+use parser::{LinePos, ParseError, ParseErrorKind, Parser};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::mem;
+
+// Elements which can never have children, and so never have a closing tag
+// https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+const VOID_ELEMENTS: &[&str] = &[
+	"area", "base", "br", "col", "embed", "hr", "img", "input", "keygen", "link", "meta", "param",
+	"source", "track", "wbr",
+];
+
+// Elements whose contents are kept as text up to their closing tag, such as scripts and styles
+// https://html.spec.whatwg.org/multipage/syntax.html#raw-text-elements
+const RAW_TEXT_ELEMENTS: &[&str] = &["iframe", "noembed", "noframes", "script", "style", "xmp"];
+
+// Elements whose contents are text, but which can contain character references
+// https://html.spec.whatwg.org/multipage/syntax.html#escapable-raw-text-elements
+const ESCAPABLE_RAW_TEXT_ELEMENTS: &[&str] = &["textarea", "title"];
+
+// Paragraphs are closed by the start of any of these
+const CLOSES_P: &[&str] = &[
+	"address",
+	"article",
+	"aside",
+	"blockquote",
+	"dd",
+	"details",
+	"div",
+	"dl",
+	"dt",
+	"fieldset",
+	"figcaption",
+	"figure",
+	"footer",
+	"form",
+	"h1",
+	"h2",
+	"h3",
+	"h4",
+	"h5",
+	"h6",
+	"header",
+	"hgroup",
+	"hr",
+	"li",
+	"main",
+	"menu",
+	"nav",
+	"ol",
+	"p",
+	"pre",
+	"section",
+	"table",
+	"ul",
+];
+
+// Elements whose closing tags can be left out, and the start tags which close them instead
+// https://html.spec.whatwg.org/multipage/syntax.html#optional-tags
+const OPTIONAL_END_TAGS: &[(&str, &[&str])] = &[
+	("li", &["li"]),
+	("dt", &["dt", "dd"]),
+	("dd", &["dt", "dd"]),
+	("p", CLOSES_P),
+	("option", &["option", "optgroup"]),
+	("optgroup", &["optgroup"]),
+	("rt", &["rt", "rp"]),
+	("rp", &["rt", "rp"]),
+	("thead", &["tbody", "tfoot"]),
+	("tbody", &["tbody", "tfoot"]),
+	("tr", &["tr", "tbody", "tfoot"]),
+	("td", &["td", "th", "tr", "tbody", "tfoot"]),
+	("th", &["td", "th", "tr", "tbody", "tfoot"]),
+];
+
+fn is_one_of(tag_name: &str, names: &[&str]) -> bool {
+	names.iter().any(|name| name.eq_ignore_ascii_case(tag_name))
+}
+
+pub fn is_void_element(tag_name: &str) -> bool {
+	is_one_of(tag_name, VOID_ELEMENTS)
+}
+
+pub fn is_raw_text_element(tag_name: &str) -> bool {
+	is_one_of(tag_name, RAW_TEXT_ELEMENTS)
+}
+
+pub fn is_escapable_raw_text_element(tag_name: &str) -> bool {
+	is_one_of(tag_name, ESCAPABLE_RAW_TEXT_ELEMENTS)
+}
 
 pub struct HtmlParser {
 	inner: Parser,
+
+	// When set, malformed input is recorded in `errors` instead of aborting the parse
+	recover: bool,
+	errors: Vec<ParseError>,
+
+	// Names of the elements currently being parsed, outermost first
+	open_elements: Vec<String>,
+
+	// The namespace new elements go in: HTML, or SVG or MathML inside `<svg>` or `<math>`
+	namespace: &'static str,
+
+	// Set when the doctype is malformed, which forces the document into quirks mode
+	force_quirks: bool,
 }
 
 impl HtmlParser {
 	fn new(input: String) -> HtmlParser {
 		HtmlParser {
 			inner: Parser::new(input),
+			recover: false,
+			errors: Vec::new(),
+			open_elements: Vec::new(),
+			namespace: dom::HTML_NAMESPACE,
+			force_quirks: false,
 		}
 	}
 
-	// Parse a tag or attribute name
+	// Record a recoverable error, or fail if we're not recovering
+	fn report(&mut self, err: ParseError) -> Result<(), ParseError> {
+		if self.recover {
+			self.errors.push(err);
+			Ok(())
+		} else {
+			Err(err)
+		}
+	}
+
+	// Parse a tag name. Tag names are case-insensitive, so they're stored lowercased
 	fn parse_tag_name(&mut self) -> String {
-		self.inner.consume_while(|c| match c {
-			'a'...'z' | 'A'...'Z' | '0'...'9' => true,
-			_ => false,
-		})
+		// Names have to start with a letter, but can contain anything that doesn't end the tag
+		match self.inner.peek() {
+			Some(c) if c.is_ascii_alphabetic() => self
+				.inner
+				.consume_while(|c| !c.is_ascii_whitespace() && c != '/' && c != '>')
+				.to_ascii_lowercase(),
+			_ => String::new(),
+		}
+	}
+
+	// Parse an attribute name. Attribute names are case-insensitive, so they're stored lowercased
+	fn parse_attr_name(&mut self) -> String {
+		self.inner
+			.consume_while(|c| {
+				!c.is_ascii_whitespace() && !matches!(c, '"' | '\'' | '>' | '/' | '=')
+			})
+			.to_ascii_lowercase()
+	}
+
+	// Skip past the end of a malformed tag
+	fn skip_to_tag_end(&mut self) {
+		self.inner.consume_while(|c| c != '>');
+		if !self.inner.eof() {
+			self.inner.consume_char();
+		}
 	}
 
 	// Parse a single node
-	fn parse_node(&mut self) -> dom::Node {
+	fn parse_node(&mut self) -> Result<dom::Node, ParseError> {
 		if self.inner.starts_with("<!--") {
 			return self.parse_comment();
 		}
+		if self.inner.starts_with_ignore_case("<!DOCTYPE") {
+			return self.parse_doctype();
+		}
 
 		match self.inner.next_char() {
 			'<' => self.parse_element(),
-			_ => self.parse_text(),
+			_ => Ok(self.parse_text()),
 		}
 	}
 
+	// Does the start tag coming up close the element being parsed, because its closing tag is
+	// optional? `<li>` closes an open `<li>`, for example.
+	fn start_tag_closes_element(&mut self) -> bool {
+		let open = match self.open_elements.last() {
+			Some(open) if self.namespace == dom::HTML_NAMESPACE => open.clone(),
+			_ => return false,
+		};
+		if self.inner.peek() != Some('<') {
+			return false;
+		}
+		let start = self.inner.pos();
+		self.inner.consume_char();
+		let tag_name = self.parse_tag_name();
+		self.inner.rewind(start);
+		OPTIONAL_END_TAGS
+			.iter()
+			.any(|&(name, closed_by)| name == open && closed_by.contains(&&*tag_name))
+	}
+
 	// Parse a text node
 	fn parse_text(&mut self) -> dom::Node {
-		dom::text(self.inner.consume_while(|c| c != '<'))
+		let start = self.inner.pos();
+		let text = self.inner.consume_while(|c| c != '<');
+		dom::text(char_refs::decode(&text, false)).with_span(self.inner.span_from(start))
 	}
 
 	// Parse a single element, including its open tag, contents, and closing tag
-	fn parse_element(&mut self) -> dom::Node {
+	fn parse_element(&mut self) -> Result<dom::Node, ParseError> {
 		// Opening tag
-		assert!(self.inner.consume_char() == '<');
+		let start = self.inner.pos();
+		self.inner.expect_char('<')?;
 		let tag_name = self.parse_tag_name();
-		let attrs = self.parse_attributes();
-		assert!(self.inner.consume_char() == '>');
+		if tag_name.is_empty() {
+			// Not a tag after all, so keep the `<` as text
+			let err = self.inner.unexpected("tag name");
+			self.report(err)?;
+			return Ok(dom::text("<".to_string()).with_span(self.inner.span_from(start)));
+		}
+		let attrs = self.parse_attributes()?;
+		let namespace = match (self.namespace, tag_name.as_str()) {
+			(dom::HTML_NAMESPACE, "svg") => dom::SVG_NAMESPACE,
+			(dom::HTML_NAMESPACE, "math") => dom::MATHML_NAMESPACE,
+			(namespace, _) => namespace,
+		};
+		let is_html = namespace == dom::HTML_NAMESPACE;
+		let self_closing = self.inner.starts_with("/>");
+		if self_closing {
+			// HTML ignores the slash, so it only matters to void elements, which never have
+			// contents anyway. Browsers don't treat it as fatal, so it's only worth a diagnostic
+			// when recovering. SVG and MathML elements really do close themselves.
+			if self.recover && is_html && !is_void_element(&tag_name) {
+				let err = self.inner.error(
+					ParseErrorKind::Spec("non-void-html-element-start-tag-with-trailing-solidus"),
+					None,
+					Some(tag_name.clone()),
+				);
+				self.errors.push(err);
+			}
+			self.inner.consume_string("/>")?;
+		} else if let Err(err) = self.inner.expect_char('>') {
+			self.report(err)?;
+		}
 
-		// Contents
-		let children = self.parse_nodes();
+		if !is_html {
+			return self.parse_foreign_element(start, namespace, tag_name, attrs, self_closing);
+		}
 
-		// Closing tag
-		assert!(self.inner.consume_char() == '<');
-		assert!(self.inner.consume_char() == '/');
-		assert!(self.parse_tag_name() == tag_name);
-		assert!(self.inner.consume_char() == '>');
+		// Void elements have no contents or closing tag
+		if is_void_element(&tag_name) {
+			let span = self.inner.span_from(start);
+			return Ok(dom::elem(tag_name, attrs, Vec::new()).with_span(span));
+		}
 
-		dom::elem(tag_name, attrs, children)
+		// Text-only elements can't contain markup, so their contents run up to their closing tag
+		if is_raw_text_element(&tag_name) || is_escapable_raw_text_element(&tag_name) {
+			let children = self.parse_raw_text(&tag_name).into_iter().collect();
+			if self.inner.eof() {
+				let err = self.inner.unexpected(&format!("</{}>", tag_name));
+				self.report(err)?;
+			} else {
+				self.parse_end_tag()?;
+			}
+			let span = self.inner.span_from(start);
+			return Ok(dom::elem(tag_name, attrs, children).with_span(span));
+		}
+
+		let children = self.parse_contents(&tag_name, dom::HTML_NAMESPACE)?;
+		let span = self.inner.span_from(start);
+		if tag_name == "template" {
+			// A template's contents are kept out of the document
+			return Ok(dom::template(attrs, children).with_span(span));
+		}
+		Ok(dom::elem(tag_name, attrs, children).with_span(span))
 	}
 
-	// Parse out a comment
-	fn parse_comment(&mut self) -> dom::Node {
-		// Opening tag
-		self.inner.consume_string("<!--");
+	// Parse the rest of an SVG or MathML element, after its start tag. The parser lowercases
+	// names, so mixed-case SVG and MathML ones are put back, and attributes like `xlink:href` go
+	// in their own namespaces.
+	// https://html.spec.whatwg.org/multipage/parsing.html#creating-and-inserting-nodes
+	fn parse_foreign_element(
+		&mut self,
+		start: LinePos,
+		namespace: &'static str,
+		tag_name: String,
+		attrs: dom::AttrMap,
+		self_closing: bool,
+	) -> Result<dom::Node, ParseError> {
+		let name = match namespace {
+			dom::SVG_NAMESPACE => foreign::svg_tag_name(&tag_name).map(String::from),
+			_ => None,
+		}
+		.unwrap_or_else(|| tag_name.clone());
 
-		// Contents
-		let mut contents = String::new();
+		// Integration points hold HTML again
+		let integration_point = match namespace {
+			dom::SVG_NAMESPACE => foreign::SVG_HTML_INTEGRATION_POINTS.contains(&&*name),
+			_ => {
+				foreign::MATHML_TEXT_INTEGRATION_POINTS.contains(&&*name)
+					|| (name == "annotation-xml"
+						&& attrs.get("encoding").is_some_and(|encoding| {
+							encoding.eq_ignore_ascii_case("text/html")
+								|| encoding.eq_ignore_ascii_case("application/xhtml+xml")
+						}))
+			}
+		};
+		let children = if self_closing {
+			Vec::new()
+		} else if integration_point {
+			self.parse_contents(&tag_name, dom::HTML_NAMESPACE)?
+		} else {
+			self.parse_contents(&tag_name, namespace)?
+		};
+
+		let mut attributes = HashMap::new();
+		let mut attribute_namespaces = HashMap::new();
+		for (name, value) in attrs {
+			let name = foreign::attribute_name(namespace, &name)
+				.map(String::from)
+				.unwrap_or(name);
+			if let Some(attribute_namespace) = foreign::attribute_namespace(&name) {
+				attribute_namespaces.insert(name.clone(), attribute_namespace.to_string());
+			}
+			attributes.insert(name, value);
+		}
+		let mut element = dom::elem_ns(Some(namespace.into()), name, attributes, children);
+		if let dom::NodeType::Element(ref mut data) = element.node_type {
+			data.attribute_namespaces = attribute_namespaces;
+		}
+		Ok(element.with_span(self.inner.span_from(start)))
+	}
+
+	// Parse an element's children and its closing tag, with new elements going in `namespace`
+	fn parse_contents(
+		&mut self,
+		tag_name: &str,
+		namespace: &'static str,
+	) -> Result<Vec<dom::Node>, ParseError> {
+		let parent_namespace = mem::replace(&mut self.namespace, namespace);
+		self.open_elements.push(tag_name.to_string());
+		let mut children = Vec::new();
 		loop {
-			if self.inner.starts_with("-->") {
+			for node in self.parse_nodes()? {
+				push_node(&mut children, node);
+			}
+			if self.inner.eof() {
+				let err = self.inner.unexpected(&format!("</{}>", tag_name));
+				self.report(err)?;
+				break;
+			}
+			if !self.inner.starts_with("</") {
+				// Closed by the start of a sibling
+				break;
+			}
+
+			// Closing tag
+			let end_tag_start = self.inner.pos();
+			let closing_name = self.parse_end_tag()?;
+			if closing_name == tag_name {
+				break;
+			}
+
+			// An element with an optional closing tag can be closed by its parent's
+			let optional = OPTIONAL_END_TAGS.iter().any(|&(name, _)| name == tag_name);
+			if optional
+				&& self.namespace == dom::HTML_NAMESPACE
+				&& self.open_elements.contains(&closing_name)
+			{
+				self.inner.rewind(end_tag_start);
 				break;
 			}
 
+			let err = self.inner.error(
+				ParseErrorKind::MismatchedTag,
+				Some(tag_name.to_string()),
+				Some(closing_name.clone()),
+			);
+			self.report(err)?;
+			if self.open_elements.contains(&closing_name) {
+				// Closes an ancestor, so implicitly close this element and let the ancestor have it
+				self.inner.rewind(end_tag_start);
+				break;
+			}
+			// Otherwise it's a stray closing tag which can be ignored
+		}
+		self.open_elements.pop();
+		self.namespace = parent_namespace;
+		Ok(children)
+	}
+
+	// Parse the contents of a raw text or escapable raw text element, up to but not including its
+	// closing tag. Returns `None` if the element is empty.
+	fn parse_raw_text(&mut self, tag_name: &str) -> Option<dom::Node> {
+		let start = self.inner.pos();
+		let end_tag = format!("</{}", tag_name);
+		let mut text = String::new();
+		while !self.inner.eof() {
+			if self.inner.starts_with_ignore_case(&end_tag) {
+				match self.inner.peek_nth(end_tag.chars().count()) {
+					Some('>') | Some('/') | None => break,
+					Some(c) if c.is_ascii_whitespace() => break,
+					_ => {}
+				}
+			}
+			text.push(self.inner.consume_char());
+		}
+
+		if text.is_empty() {
+			return None;
+		}
+		if is_escapable_raw_text_element(tag_name) {
+			text = char_refs::decode(&text, false);
+		}
+		Some(dom::text(text).with_span(self.inner.span_from(start)))
+	}
+
+	// Parse a closing tag, returning its name
+	fn parse_end_tag(&mut self) -> Result<String, ParseError> {
+		self.inner.consume_string("</")?;
+		let name = self.parse_tag_name();
+		self.inner.consume_whitespace();
+		if let Err(err) = self.inner.expect_char('>') {
+			self.report(err)?;
+			self.skip_to_tag_end();
+		}
+		Ok(name)
+	}
+
+	// Parse a doctype such as `<!DOCTYPE html>` or
+	// `<!DOCTYPE html PUBLIC "-//W3C//DTD HTML 4.01//EN" "http://www.w3.org/TR/html4/strict.dtd">`
+	fn parse_doctype(&mut self) -> Result<dom::Node, ParseError> {
+		let start = self.inner.pos();
+		self.inner.consume_string_ignore_case("<!DOCTYPE")?;
+		self.inner.consume_whitespace();
+		let name = self
+			.inner
+			.consume_while(|c| !c.is_ascii_whitespace() && c != '>')
+			.to_ascii_lowercase();
+		if name.is_empty() {
+			let err = self.inner.unexpected("doctype name");
+			self.report(err)?;
+			self.force_quirks = true;
+		}
+		self.inner.consume_whitespace();
+
+		let mut public_id = None;
+		let mut system_id = None;
+		if self.inner.starts_with_ignore_case("PUBLIC") {
+			self.inner.consume_string_ignore_case("PUBLIC")?;
+			self.inner.consume_whitespace();
+			public_id = self.parse_doctype_id()?;
+			self.inner.consume_whitespace();
+			if let Some('"') | Some('\'') = self.inner.peek() {
+				system_id = self.parse_doctype_id()?;
+			}
+		} else if self.inner.starts_with_ignore_case("SYSTEM") {
+			self.inner.consume_string_ignore_case("SYSTEM")?;
+			self.inner.consume_whitespace();
+			system_id = self.parse_doctype_id()?;
+		}
+		self.inner.consume_whitespace();
+
+		if let Err(err) = self.inner.expect_char('>') {
+			self.report(err)?;
+			self.force_quirks = true;
+			self.skip_to_tag_end();
+		}
+
+		let span = self.inner.span_from(start);
+		Ok(dom::doctype(name, public_id, system_id).with_span(span))
+	}
+
+	// Parse a quoted public or system identifier
+	fn parse_doctype_id(&mut self) -> Result<Option<String>, ParseError> {
+		let quote = match self.inner.peek() {
+			Some(c) if c == '"' || c == '\'' => self.inner.consume_char(),
+			_ => {
+				let err = self.inner.unexpected("quote");
+				self.report(err)?;
+				self.force_quirks = true;
+				return Ok(None);
+			}
+		};
+		let id = self.inner.consume_while(|c| c != quote && c != '>');
+		if let Err(err) = self.inner.expect_char(quote) {
+			self.report(err)?;
+			self.force_quirks = true;
+		}
+		Ok(Some(id))
+	}
+
+	// Parse out a comment
+	fn parse_comment(&mut self) -> Result<dom::Node, ParseError> {
+		// Opening tag
+		let start = self.inner.pos();
+		self.inner.consume_string("<!--")?;
+
+		// Contents
+		let mut contents = String::new();
+		while !self.inner.eof() && !self.inner.starts_with("-->") {
 			contents.push(self.inner.consume_char());
 		}
 
 		// Closing tag
-		self.inner.consume_string("-->");
+		if let Err(err) = self.inner.consume_string("-->") {
+			self.report(err)?;
+		}
 
-		dom::comment(contents)
+		Ok(dom::comment(contents).with_span(self.inner.span_from(start)))
 	}
 
-	// Parse a single name="value" pair
-	fn parse_attr(&mut self) -> (String, String) {
-		let name = self.parse_tag_name();
-		assert!(self.inner.consume_char() == '=');
-		let value = self.parse_attr_value();
-		(name, value)
+	// Parse a single attribute, which is either `name`, `name=value`, `name='value'` or
+	// `name="value"`
+	fn parse_attr(&mut self) -> Result<Option<(String, String)>, ParseError> {
+		let name = self.parse_attr_name();
+		if name.is_empty() {
+			// Skip over whatever character can't start a name
+			let err = self.inner.unexpected("attribute name");
+			self.report(err)?;
+			self.inner.consume_char();
+			return Ok(None);
+		}
+
+		// Boolean attributes don't have a value
+		self.inner.consume_whitespace();
+		if self.inner.peek() != Some('=') {
+			return Ok(Some((name, String::new())));
+		}
+		self.inner.consume_char();
+		self.inner.consume_whitespace();
+
+		let value = self.parse_attr_value()?;
+		Ok(Some((name, value)))
 	}
 
-	// Parse a quoted value
-	fn parse_attr_value(&mut self) -> String {
-		let open_quote = self.inner.consume_char();
-		assert!(open_quote == '"' || open_quote == '\'');
+	// Parse a quoted or unquoted value, decoding any character references
+	fn parse_attr_value(&mut self) -> Result<String, ParseError> {
+		let value = self.parse_raw_attr_value()?;
+		Ok(char_refs::decode(&value, true))
+	}
+
+	fn parse_raw_attr_value(&mut self) -> Result<String, ParseError> {
+		let open_quote = match self.inner.peek() {
+			Some(c) if c == '"' || c == '\'' => self.inner.consume_char(),
+			Some('>') | None => {
+				let err = self.inner.unexpected("attribute value");
+				self.report(err)?;
+				return Ok(String::new());
+			}
+			_ => {
+				return Ok(self
+					.inner
+					.consume_while(|c| !c.is_ascii_whitespace() && c != '>'))
+			}
+		};
 		let value = self.inner.consume_while(|c| c != open_quote);
-		assert!(self.inner.consume_char() == open_quote);
-		value
+		if let Err(err) = self.inner.expect_char(open_quote) {
+			self.report(err)?;
+		}
+		Ok(value)
 	}
 
-	// Parse a list of name="value" pairs, separated by whitespace
-	fn parse_attributes(&mut self) -> dom::AttrMap {
+	// Parse a list of attributes, separated by whitespace
+	fn parse_attributes(&mut self) -> Result<dom::AttrMap, ParseError> {
 		let mut attributes = HashMap::new();
 		loop {
 			self.inner.consume_whitespace();
-			if self.inner.next_char() == '>' {
-				break;
+			match self.inner.peek() {
+				Some('>') | None => break,
+				Some('/') if self.inner.starts_with("/>") => break,
+				_ => {}
+			}
+			let start = self.inner.pos();
+			if let Some((name, value)) = self.parse_attr()? {
+				// The first occurrence of an attribute wins. Browsers don't treat duplicates as
+				// fatal, so they're only worth a diagnostic when recovering.
+				match attributes.entry(name) {
+					Entry::Vacant(entry) => {
+						entry.insert(value);
+					}
+					Entry::Occupied(entry) => {
+						if self.recover {
+							let err = self.inner.error_at(
+								start,
+								ParseErrorKind::DuplicateAttribute,
+								None,
+								Some(entry.key().clone()),
+							);
+							self.errors.push(err);
+						}
+					}
+				}
 			}
-			let (name, value) = self.parse_attr();
-			attributes.insert(name, value);
 		}
-		attributes
+		Ok(attributes)
 	}
 
 	// Parse a sequence of sibling nodes
-	fn parse_nodes(&mut self) -> Vec<dom::Node> {
+	fn parse_nodes(&mut self) -> Result<Vec<dom::Node>, ParseError> {
 		let mut nodes = Vec::new();
 		loop {
-			self.inner.consume_whitespace();
-			if self.inner.eof() || self.inner.starts_with("</") {
+			if self.inner.eof() || self.inner.starts_with("</") || self.start_tag_closes_element() {
 				break;
 			}
-			nodes.push(self.parse_node());
+			let node = self.parse_node()?;
+			if is_doctype(&node) && !self.open_elements.is_empty() {
+				self.report_misplaced_doctype(&node)?;
+				continue;
+			}
+			push_node(&mut nodes, node);
 		}
-		nodes
+		Ok(nodes)
+	}
+
+	// Doctypes are only allowed at the start of the document, and are ignored anywhere else
+	fn report_misplaced_doctype(&mut self, doctype: &dom::Node) -> Result<(), ParseError> {
+		let start = doctype.span.map_or(self.inner.pos(), |span| span.start);
+		let err = self
+			.inner
+			.error_at(start, ParseErrorKind::MisplacedDoctype, None, None);
+		self.report(err)
 	}
+
+	// Parse the whole input. Returns the doctype and any comments before it separately from the
+	// root node.
+	fn parse_root(&mut self) -> Result<(Vec<dom::Node>, dom::Node), ParseError> {
+		let mut nodes = Vec::new();
+		loop {
+			nodes.append(&mut self.parse_nodes()?);
+			if self.inner.eof() {
+				break;
+			}
+
+			// A closing tag with nothing left to close
+			let err = self.inner.unexpected("end of input");
+			self.report(err)?;
+			self.parse_end_tag()?;
+		}
+
+		// Whitespace before the doctype and between the top-level comments is insignificant
+		let first_content = nodes
+			.iter()
+			.position(|node| !is_comment(node) && !is_doctype(node) && !is_whitespace(node))
+			.unwrap_or(nodes.len());
+		let mut i = 0;
+		nodes.retain(|node| {
+			i += 1;
+			i > first_content || !is_whitespace(node)
+		});
+
+		// The doctype can only be preceded by comments
+		let doctype_index = nodes
+			.iter()
+			.position(|node| !matches!(node.node_type, dom::NodeType::Comment(_)))
+			.filter(|&i| is_doctype(&nodes[i]));
+		let mut prologue = Vec::new();
+		if let Some(i) = doctype_index {
+			let rest = nodes.split_off(i + 1);
+			prologue = nodes;
+			nodes = rest;
+		}
+		for node in nodes.iter().filter(|node| is_doctype(node)) {
+			self.report_misplaced_doctype(node)?;
+		}
+		nodes.retain(|node| !is_doctype(node));
+
+		// If the document contains a root element, just return it. Otherwise, create one
+		let content = nodes.iter().filter(|node| !is_whitespace(node)).count();
+		let root = if content == 1 {
+			nodes.retain(|node| !is_whitespace(node));
+			nodes.swap_remove(0)
+		} else {
+			dom::elem("html".to_string(), HashMap::new(), nodes)
+		};
+		Ok((prologue, root))
+	}
+
+	// Parse the whole input as a document, along with what was wrong with its stylesheets
+	fn parse_document(&mut self) -> Result<(dom::Document, Vec<ParseError>), ParseError> {
+		let (mut children, root) = self.parse_root()?;
+		let mode = {
+			let doctype = children.iter().find_map(|node| match node.node_type {
+				dom::NodeType::Doctype(ref doctype) => Some(doctype),
+				_ => None,
+			});
+			quirks::quirks_mode(doctype, self.force_quirks)
+		};
+		children.push(root);
+		let mut document = dom::Document::new(children, mode);
+		let (stylesheets, errors) = collect_stylesheets(&document);
+		document.stylesheets = stylesheets;
+		Ok((document, errors))
+	}
+}
+
+// Parse the contents of every HTML and SVG `<style>` element, in document order. A stylesheet which
+// doesn't parse is left out, like browsers do, and its error is returned instead.
+pub fn collect_stylesheets(document: &dom::Document) -> (Vec<css::Stylesheet>, Vec<ParseError>) {
+	let mut stylesheets = Vec::new();
+	let mut errors = Vec::new();
+	for id in document.descendants(document.document_node()) {
+		let is_style = document.element(id).is_some_and(|data| {
+			let namespace = data.namespace.as_deref();
+			data.tag_name == "style"
+				&& (namespace == Some(dom::HTML_NAMESPACE) || namespace == Some(dom::SVG_NAMESPACE))
+		});
+		if !is_style {
+			continue;
+		}
+
+		let mut source = String::new();
+		for child in document.children(id) {
+			// XHTML stylesheets are often wrapped in a CDATA section
+			match document.node(child).node_type {
+				dom::NodeType::Text(ref text) | dom::NodeType::CData(ref text) => {
+					source.push_str(text)
+				}
+				_ => {}
+			}
+		}
+
+		// Report errors relative to the document rather than the style element
+		let origin = document
+			.first_child(id)
+			.and_then(|child| document.node(child).span);
+		match css::parse(source) {
+			Ok(stylesheet) => stylesheets.push(stylesheet),
+			Err(err) => errors.push(match origin {
+				Some(span) => err.relative_to(span.start),
+				None => err,
+			}),
+		}
+	}
+	(stylesheets, errors)
 }
 
-pub fn parse(source: String) -> dom::Node {
-	let mut nodes = HtmlParser::new(source).parse_nodes();
+// Add a node to a list of siblings, merging text into the text before it. A stray `<` or closing
+// tag would otherwise split the text up.
+fn push_node(nodes: &mut Vec<dom::Node>, node: dom::Node) {
+	if let (Some(last), dom::NodeType::Text(ref text)) = (nodes.last_mut(), &node.node_type) {
+		if let dom::NodeType::Text(ref mut last_text) = last.node_type {
+			last_text.push_str(text);
+			if let (Some(span), Some(end)) = (last.span.as_mut(), node.span) {
+				span.end = end.end;
+			}
+			return;
+		}
+	}
+	nodes.push(node);
+}
+
+fn is_doctype(node: &dom::Node) -> bool {
+	matches!(node.node_type, dom::NodeType::Doctype(_))
+}
+
+fn is_comment(node: &dom::Node) -> bool {
+	matches!(node.node_type, dom::NodeType::Comment(_))
+}
 
-	// If the document contains a root element, just return it. Otherwise, create one
-	if nodes.len() == 1 {
-		nodes.swap_remove(0)
-	} else {
-		dom::elem("html".to_string(), HashMap::new(), nodes)
+// Is this a text node containing nothing but whitespace?
+fn is_whitespace(node: &dom::Node) -> bool {
+	match node.node_type {
+		dom::NodeType::Text(ref text) => text.chars().all(|c| c.is_ascii_whitespace()),
+		_ => false,
 	}
 }
 
+// Parse a document, returning its root node. Any doctype is discarded.
+pub fn parse(source: String) -> Result<dom::Node, ParseError> {
+	let (_, root) = HtmlParser::new(source).parse_root()?;
+	Ok(root)
+}
+
+// Parse a document, keeping its doctype, working out which quirks mode it should use and parsing
+// the contents of its `<style>` elements. Broken stylesheets are skipped rather than failing the
+// document, and what was wrong with them is returned alongside it.
+pub fn parse_document(source: String) -> Result<(dom::Document, Vec<ParseError>), ParseError> {
+	HtmlParser::new(source).parse_document()
+}
+
+// Parse a document, recovering from malformed input the way browsers do. Unclosed elements are
+// closed implicitly, stray closing tags are ignored, and everything that had to be fixed up is
+// returned alongside the tree.
+pub fn parse_with_recovery(source: String) -> (dom::Node, Vec<ParseError>) {
+	let mut parser = HtmlParser::new(source);
+	parser.recover = true;
+	let (_, root) = parser
+		.parse_root()
+		.expect("errors are recorded rather than returned while recovering");
+	(root, parser.errors)
+}
+
+// Work out the encoding of a document and decode it. A byte order mark wins, then the encoding the
+// document was served with (`hint`), then a `<meta charset>` near the start of the document, and
+// otherwise it's assumed to be windows-1252.
+// https://html.spec.whatwg.org/multipage/parsing.html#determining-the-character-encoding
+pub fn decode(bytes: &[u8], hint: Option<Encoding>) -> (String, Encoding) {
+	if let Some((encoding, length)) = encoding::sniff_bom(bytes) {
+		return (encoding.decode(&bytes[length..]), encoding);
+	}
+	let encoding = hint
+		.or_else(|| prescan::prescan(bytes))
+		.unwrap_or(Encoding::Windows1252);
+	(encoding.decode(bytes), encoding)
+}
+
+// Parse a document from raw bytes, using `decode` to work out its encoding
+pub fn parse_bytes(bytes: &[u8], hint: Option<Encoding>) -> Result<dom::Node, ParseError> {
+	parse(decode(bytes, hint).0)
+}
+
+// Parse a document with the algorithm from the HTML standard, which builds the same tree a browser
+// would for any input. Unlike the other parsers this never fails: every problem it had to work
+// around is returned alongside the document.
+pub fn parse_whatwg(source: String) -> (dom::Document, Vec<ParseError>) {
+	let (mut document, mut errors) = tree_builder::TreeBuilder::new(&source).build();
+	let (stylesheets, stylesheet_errors) = collect_stylesheets(&document);
+	document.stylesheets = stylesheets;
+	errors.extend(stylesheet_errors);
+	(document, errors)
+}
+
+// Parse a snippet of HTML as if it were the contents of a `context_tag` element, the way
+// `innerHTML` does. So `<li>` closes an open `<li>` inside a `ul`, and `<tr>` is kept rather than
+// dropped inside a `table`. The snippet's nodes are returned in a document fragment, in a document
+// of their own.
+pub fn parse_fragment(source: String, context_tag: &str) -> (dom::Document, dom::NodeId) {
+	parse_fragment_ns(source, dom::HTML_NAMESPACE, context_tag)
+}
+
+// Like `parse_fragment`, for a context element which may be in the SVG or MathML namespace
+pub fn parse_fragment_ns(
+	source: String,
+	namespace: &str,
+	context_tag: &str,
+) -> (dom::Document, dom::NodeId) {
+	let (document, fragment, _) =
+		tree_builder::TreeBuilder::new_fragment(&source, namespace, context_tag).build_fragment();
+	(document, fragment)
+}
+
 #[cfg(test)]
 mod html_tests {
 	use super::*;
 	use dom;
 	use std::collections::HashMap;
+	use style;
 
 	#[test]
 	fn can_parse_basic_html() {
 		let input = "<html></html>".into();
 		let expected = dom::elem("html".into(), HashMap::new(), Vec::new());
 		let actual = parse(input);
-		assert_eq!(expected, actual);
+		assert_eq!(Ok(expected), actual);
 	}
 
 	#[test]
@@ -163,18 +873,22 @@ mod html_tests {
 		let expected = dom::elem(
 			"html".into(),
 			HashMap::new(),
-			vec![dom::elem(
-				"body".into(),
-				HashMap::new(),
-				vec![dom::elem(
-					"h1".into(),
-					h1_attrs,
-					vec![dom::text("Test".into())],
-				)],
-			)],
+			vec![
+				dom::text("\n\t\t\t\t".into()),
+				dom::elem(
+					"body".into(),
+					HashMap::new(),
+					vec![
+						dom::text("\n\t\t\t\t\t".into()),
+						dom::elem("h1".into(), h1_attrs, vec![dom::text("Test".into())]),
+						dom::text("\n\t\t\t\t".into()),
+					],
+				),
+				dom::text("\n\t\t\t".into()),
+			],
 		);
 		let actual = parse(input);
-		assert_eq!(expected, actual);
+		assert_eq!(Ok(expected), actual);
 	}
 
 	#[test]
@@ -182,6 +896,617 @@ mod html_tests {
 		let input = "<!-- some comment -->".into();
 		let expected = dom::comment(" some comment ".into());
 		let actual = parse(input);
-		assert_eq!(expected, actual);
+		assert_eq!(Ok(expected), actual);
+	}
+
+	#[test]
+	fn reports_mismatched_closing_tag() {
+		let err = parse("<div><span>text</div></span>".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::MismatchedTag);
+		assert_eq!(err.expected, Some("span".to_string()));
+		assert_eq!(err.found, Some("div".to_string()));
+	}
+
+	#[test]
+	fn reports_truncated_input() {
+		let err = parse("<div id=\"a".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+		assert_eq!(err.expected, Some("\"".to_string()));
+
+		let err = parse("<!-- never closed".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+		assert_eq!(err.expected, Some("-->".to_string()));
+	}
+
+	#[test]
+	fn can_parse_html_attribute_syntax() {
+		let input =
+			"<input disabled data-id=5 aria-label='x' HTTP-EQUIV = \"refresh\" xlink:href=#a>"
+				.into();
+		let mut attrs = HashMap::new();
+		attrs.insert("disabled".into(), "".into());
+		attrs.insert("data-id".into(), "5".into());
+		attrs.insert("aria-label".into(), "x".into());
+		attrs.insert("http-equiv".into(), "refresh".into());
+		attrs.insert("xlink:href".into(), "#a".into());
+		let expected = dom::elem("input".into(), attrs, vec![]);
+		assert_eq!(Ok(expected), parse(input));
+	}
+
+	#[test]
+	fn lowercases_tag_names() {
+		let input = "<DIV Class=a><P>x</p><Br></Div>".into();
+		let mut attrs = HashMap::new();
+		attrs.insert("class".into(), "a".into());
+		let expected = dom::elem(
+			"div".into(),
+			attrs,
+			vec![
+				dom::elem("p".into(), HashMap::new(), vec![dom::text("x".into())]),
+				dom::elem("br".into(), HashMap::new(), vec![]),
+			],
+		);
+		assert_eq!(Ok(expected), parse(input));
+	}
+
+	#[test]
+	fn first_duplicate_attribute_wins() {
+		let input = "<my-widget id=a class=b ID=c></my-widget>".to_string();
+		let mut attrs = HashMap::new();
+		attrs.insert("id".into(), "a".into());
+		attrs.insert("class".into(), "b".into());
+		let expected = dom::elem("my-widget".into(), attrs, vec![]);
+		assert_eq!(Ok(expected), parse(input.clone()));
+
+		let (_, errors) = parse_with_recovery(input);
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].kind, ParseErrorKind::DuplicateAttribute);
+		assert_eq!(errors[0].column, 25);
+	}
+
+	#[test]
+	fn records_node_spans() {
+		let root = parse("<div>\n\t<p>hi</p>\n</div>".into()).unwrap();
+		let span = root.span.unwrap();
+		assert_eq!((span.start.line, span.start.column), (1, 1));
+		assert_eq!((span.end.line, span.end.column), (3, 7));
+
+		let p = &root.children[1];
+		let span = p.span.unwrap();
+		assert_eq!((span.start.pos, span.end.pos), (7, 16));
+		assert_eq!((span.start.line, span.start.column), (2, 2));
+
+		let text = p.children[0].span.unwrap();
+		assert_eq!((text.start.column, text.end.column), (5, 7));
+	}
+
+	#[test]
+	fn recovers_from_unclosed_elements() {
+		// Leaving out the closing tags of list items and paragraphs is allowed
+		let item =
+			|text: &str| dom::elem("li".into(), HashMap::new(), vec![dom::text(text.into())]);
+		let expected = dom::elem("ul".into(), HashMap::new(), vec![item("a"), item("b")]);
+		assert_eq!(Ok(expected), parse("<ul><li>a<li>b</ul>".into()));
+
+		let paragraph =
+			|text: &str| dom::elem("p".into(), HashMap::new(), vec![dom::text(text.into())]);
+		let expected = dom::elem(
+			"div".into(),
+			HashMap::new(),
+			vec![
+				paragraph("a"),
+				paragraph("b"),
+				dom::elem("ul".into(), HashMap::new(), vec![item("c")]),
+			],
+		);
+		assert_eq!(
+			Ok(expected),
+			parse("<div><p>a<p>b<ul><li>c</ul></div>".into())
+		);
+
+		// Other elements are closed by their parents' closing tags, but that's an error
+		let (root, errors) = parse_with_recovery("<div><span>a</div>".into());
+		let expected = dom::elem(
+			"div".into(),
+			HashMap::new(),
+			vec![dom::elem(
+				"span".into(),
+				HashMap::new(),
+				vec![dom::text("a".into())],
+			)],
+		);
+		assert_eq!(expected, root);
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].kind, ParseErrorKind::MismatchedTag);
+	}
+
+	#[test]
+	fn recovers_from_stray_closing_tags() {
+		let (root, errors) = parse_with_recovery("</div><p>a</span>b</p></p>".into());
+		let expected = dom::elem("p".into(), HashMap::new(), vec![dom::text("ab".into())]);
+		assert_eq!(expected, root);
+		assert_eq!(errors.len(), 3);
+		assert_eq!(errors[1].found, Some("span".to_string()));
+	}
+
+	#[test]
+	fn recovers_from_truncated_input() {
+		let (root, errors) = parse_with_recovery("<div class=\"a\"><p>text <b id=x".into());
+		let mut div_attrs = HashMap::new();
+		div_attrs.insert("class".into(), "a".into());
+		let mut b_attrs = HashMap::new();
+		b_attrs.insert("id".into(), "x".into());
+		let expected = dom::elem(
+			"div".into(),
+			div_attrs,
+			vec![dom::elem(
+				"p".into(),
+				HashMap::new(),
+				vec![
+					dom::text("text ".into()),
+					dom::elem("b".into(), b_attrs, vec![]),
+				],
+			)],
+		);
+		assert_eq!(expected, root);
+		assert!(errors
+			.iter()
+			.any(|err| err.kind == ParseErrorKind::UnexpectedEof));
+	}
+
+	#[test]
+	fn keeps_stray_less_than_as_text() {
+		let (root, errors) = parse_with_recovery("<p>a < b</p>".into());
+		let expected = dom::elem("p".into(), HashMap::new(), vec![dom::text("a < b".into())]);
+		assert_eq!(expected, root);
+		assert_eq!(errors.len(), 1);
+	}
+
+	#[test]
+	fn can_parse_void_elements() {
+		let input = "<div>a<br>b<img src=\"x.png\"><hr><input type=\"text\"></div>".into();
+		let mut img_attrs = HashMap::new();
+		img_attrs.insert("src".into(), "x.png".into());
+		let mut input_attrs = HashMap::new();
+		input_attrs.insert("type".into(), "text".into());
+		let expected = dom::elem(
+			"div".into(),
+			HashMap::new(),
+			vec![
+				dom::text("a".into()),
+				dom::elem("br".into(), HashMap::new(), vec![]),
+				dom::text("b".into()),
+				dom::elem("img".into(), img_attrs, vec![]),
+				dom::elem("hr".into(), HashMap::new(), vec![]),
+				dom::elem("input".into(), input_attrs, vec![]),
+			],
+		);
+		assert_eq!(Ok(expected), parse(input));
+	}
+
+	#[test]
+	fn can_parse_self_closing_tags() {
+		let input =
+			"<head><meta charset=\"utf-8\"/><link rel=\"a\" /><br/><div/><p>b</p></div></head>";
+		let mut meta_attrs = HashMap::new();
+		meta_attrs.insert("charset".into(), "utf-8".into());
+		let mut link_attrs = HashMap::new();
+		link_attrs.insert("rel".into(), "a".into());
+		let expected = dom::elem(
+			"head".into(),
+			HashMap::new(),
+			vec![
+				dom::elem("meta".into(), meta_attrs, vec![]),
+				dom::elem("link".into(), link_attrs, vec![]),
+				dom::elem("br".into(), HashMap::new(), vec![]),
+				// The slash doesn't close a non-void element, so the paragraph goes inside it
+				dom::elem(
+					"div".into(),
+					HashMap::new(),
+					vec![dom::elem(
+						"p".into(),
+						HashMap::new(),
+						vec![dom::text("b".into())],
+					)],
+				),
+			],
+		);
+		assert_eq!(Ok(expected.clone()), parse(input.into()));
+
+		let (root, errors) = parse_with_recovery(input.into());
+		assert_eq!(root, expected);
+		assert_eq!(
+			errors
+				.iter()
+				.map(|err| err.kind.clone())
+				.collect::<Vec<_>>(),
+			vec![ParseErrorKind::Spec(
+				"non-void-html-element-start-tag-with-trailing-solidus"
+			)]
+		);
+	}
+
+	#[test]
+	fn puts_svg_and_mathml_in_their_namespaces() {
+		let root = parse(
+			"<div><svg viewBox='0 0 10 10'><clipPath><rect/></clipPath><a xlink:href=#x></a>\
+			 <foreignObject><p>a<br></p></foreignObject></svg><math definitionurl=y><mi><b>x</b>\
+			 </mi></math></div>"
+				.into(),
+		)
+		.unwrap();
+		let element = |node: &dom::Node| match node.node_type {
+			dom::NodeType::Element(ref data) => data.clone(),
+			_ => panic!("not an element"),
+		};
+
+		let svg = &root.children[0];
+		let data = element(svg);
+		assert_eq!(data.namespace.as_deref(), Some(dom::SVG_NAMESPACE));
+		assert_eq!(data.attributes["viewBox"], "0 0 10 10");
+		let clip_path = element(&svg.children[0]);
+		assert_eq!(clip_path.tag_name, "clipPath");
+		assert!(clip_path.is_in(dom::SVG_NAMESPACE));
+		assert_eq!(svg.children[0].children.len(), 1);
+		let link = element(&svg.children[1]);
+		assert!(link.is_in(dom::SVG_NAMESPACE));
+		assert_eq!(
+			link.attribute_namespace("xlink:href"),
+			Some(dom::XLINK_NAMESPACE)
+		);
+
+		// The contents of `foreignObject` and `mi` are HTML again
+		let foreign_object = &svg.children[2];
+		assert_eq!(element(foreign_object).tag_name, "foreignObject");
+		let p = &foreign_object.children[0];
+		assert!(element(p).is_html());
+		assert!(element(&p.children[1]).is_html());
+
+		let math = &root.children[1];
+		assert!(element(math).is_in(dom::MATHML_NAMESPACE));
+		assert_eq!(element(math).attributes["definitionURL"], "y");
+		let mi = &math.children[0];
+		assert!(element(mi).is_in(dom::MATHML_NAMESPACE));
+		assert!(element(&mi.children[0]).is_html());
+	}
+
+	#[test]
+	fn decodes_character_references() {
+		let input =
+			"<a title=\"&lt;&#169;&#x1F600;\" href=?x=1&copy=2>Tom &amp; Jerry &copy 2020</a>";
+		let mut attrs = HashMap::new();
+		attrs.insert("title".into(), "<\u{A9}\u{1F600}".into());
+		attrs.insert("href".into(), "?x=1&copy=2".into());
+		let expected = dom::elem(
+			"a".into(),
+			attrs,
+			vec![dom::text("Tom & Jerry \u{A9} 2020".into())],
+		);
+		assert_eq!(Ok(expected), parse(input.into()));
+	}
+
+	#[test]
+	fn can_parse_doctype() {
+		let (document, _) =
+			parse_document("<!-- a --><!doctype HTML>\n<html></html>".into()).unwrap();
+		let expected = dom::Document::new(
+			vec![
+				dom::comment(" a ".into()),
+				dom::doctype("html".into(), None, None),
+				dom::elem("html".into(), HashMap::new(), vec![]),
+			],
+			dom::QuirksMode::NoQuirks,
+		);
+		assert_eq!(expected, document);
+		assert_eq!(document.doctype().unwrap().name, "html");
+		assert_eq!(
+			document.outer_html(document.root().unwrap()),
+			"<html></html>"
+		);
+	}
+
+	#[test]
+	fn can_parse_doctype_identifiers() {
+		let input = "<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01//EN\"
+			'http://www.w3.org/TR/html4/strict.dtd'><p></p>";
+		let (document, _) = parse_document(input.into()).unwrap();
+		let doctype = document.doctype().unwrap();
+		assert_eq!(doctype.public_id, Some("-//W3C//DTD HTML 4.01//EN".into()));
+		assert_eq!(
+			doctype.system_id,
+			Some("http://www.w3.org/TR/html4/strict.dtd".into())
+		);
+		assert_eq!(document.mode, dom::QuirksMode::NoQuirks);
+	}
+
+	#[test]
+	fn detects_quirks_mode() {
+		let mode = |input: &str| parse_document(input.into()).unwrap().0.mode;
+		assert_eq!(mode("<p></p>"), dom::QuirksMode::Quirks);
+		assert_eq!(mode("<!DOCTYPE svg><p></p>"), dom::QuirksMode::Quirks);
+		assert_eq!(
+			mode("<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\"><p></p>"),
+			dom::QuirksMode::Quirks
+		);
+		assert_eq!(
+			mode("<!DOCTYPE html PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \"x\"><p></p>"),
+			dom::QuirksMode::LimitedQuirks
+		);
+		assert_eq!(
+			mode("<!DOCTYPE html PUBLIC \"-//w3c//dtd xhtml 1.0 transitional//en\"><p></p>"),
+			dom::QuirksMode::LimitedQuirks
+		);
+		assert_eq!(
+			mode("<!DOCTYPE html SYSTEM \"about:legacy-compat\"><p></p>"),
+			dom::QuirksMode::NoQuirks
+		);
+	}
+
+	#[test]
+	fn ignores_misplaced_doctypes() {
+		let input = "<!DOCTYPE html><p><!DOCTYPE html>a</p>".to_string();
+		let err = parse(input.clone()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::MisplacedDoctype);
+		assert_eq!(err.column, 19);
+
+		let (root, errors) = parse_with_recovery(input);
+		let expected = dom::elem("p".into(), HashMap::new(), vec![dom::text("a".into())]);
+		assert_eq!(expected, root);
+		assert_eq!(errors.len(), 1);
+	}
+
+	#[test]
+	fn malformed_doctypes_force_quirks_mode() {
+		let mut parser = HtmlParser::new("<!DOCTYPE html PUBLIC foo><p></p>".into());
+		parser.recover = true;
+		let (document, _) = parser.parse_document().unwrap();
+		assert_eq!(document.mode, dom::QuirksMode::Quirks);
+		assert_eq!(parser.errors.len(), 2);
+	}
+
+	#[test]
+	fn extracts_stylesheets() {
+		let input = "
+			<html>
+				<head><style>p { width: 10px; } a { display: block; }</style></head>
+				<body>
+					<p>a</p>
+					<style>p { width: 20px; }</style>
+				</body>
+			</html>";
+		let (document, errors) = parse_document(input.into()).unwrap();
+		assert_eq!(document.stylesheets.len(), 2);
+		assert!(errors.is_empty());
+		assert_eq!(document.stylesheets[0].rules.len(), 2);
+		assert_eq!(document.stylesheets[1].rules.len(), 1);
+
+		// Later stylesheets take precedence
+		let styled = style::style_tree(&document, document.root().unwrap(), &document.stylesheets);
+		let p = &styled.children[3].children[1];
+		assert_eq!(
+			p.value("width"),
+			Some(css::Value::Length(20.0, css::Unit::Px))
+		);
+	}
+
+	#[test]
+	fn reports_stylesheet_errors_in_document_coordinates() {
+		let input =
+			"<html>\n<style>\n\tp { width 10px; }</style><style>p { width: 1px; }</style></html>";
+
+		// The broken stylesheet is skipped, and the document and the other stylesheet are kept
+		let (document, errors) = parse_document(input.into()).unwrap();
+		assert_eq!(document.stylesheets.len(), 1);
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].kind, ParseErrorKind::UnexpectedChar);
+		assert_eq!((errors[0].line, errors[0].column), (3, 12));
+
+		let (document, errors) = parse_whatwg(input.into());
+		assert_eq!(document.stylesheets.len(), 1);
+		assert_eq!(
+			errors.last().map(|err| err.kind.clone()),
+			Some(ParseErrorKind::UnexpectedChar)
+		);
+	}
+
+	#[test]
+	fn only_extracts_html_and_svg_stylesheets() {
+		let input =
+			"<style>a {}</style><svg><style>b {}</style></svg><math><style>c {}</style></math>";
+		let expected = vec![
+			css::parse("a {}".into()).unwrap(),
+			css::parse("b {}".into()).unwrap(),
+		];
+		assert_eq!(parse_whatwg(input.into()).0.stylesheets, expected);
+	}
+
+	#[test]
+	fn keeps_raw_text_intact() {
+		let input = "<div>
+			<style> a > b { color: red; } </style><script>if (a < b && c) { x = \"</div>\"; }</SCRIPT>
+			<script></script>
+		</div>";
+		let expected = dom::elem(
+			"div".into(),
+			HashMap::new(),
+			vec![
+				dom::text("\n\t\t\t".into()),
+				dom::elem(
+					"style".into(),
+					HashMap::new(),
+					vec![dom::text(" a > b { color: red; } ".into())],
+				),
+				dom::elem(
+					"script".into(),
+					HashMap::new(),
+					vec![dom::text("if (a < b && c) { x = \"</div>\"; }".into())],
+				),
+				dom::text("\n\t\t\t".into()),
+				dom::elem("script".into(), HashMap::new(), vec![]),
+				dom::text("\n\t\t".into()),
+			],
+		);
+		assert_eq!(Ok(expected), parse(input.into()));
+	}
+
+	#[test]
+	fn decodes_escapable_raw_text() {
+		let input = "<head><title>A &amp; <b>B</b></title><textarea>\n  <p>&lt;</textarea></head>";
+		let expected = dom::elem(
+			"head".into(),
+			HashMap::new(),
+			vec![
+				dom::elem(
+					"title".into(),
+					HashMap::new(),
+					vec![dom::text("A & <b>B</b>".into())],
+				),
+				dom::elem(
+					"textarea".into(),
+					HashMap::new(),
+					vec![dom::text("\n  <p><".into())],
+				),
+			],
+		);
+		assert_eq!(Ok(expected), parse(input.into()));
+	}
+
+	#[test]
+	fn reports_unclosed_raw_text() {
+		let err = parse("<script>var a = 1;</scripty>".into()).unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+		assert_eq!(err.expected, Some("</script>".to_string()));
+
+		let (root, _) = parse_with_recovery("<script>a</scrip".into());
+		let expected = dom::elem(
+			"script".into(),
+			HashMap::new(),
+			vec![dom::text("a</scrip".into())],
+		);
+		assert_eq!(expected, root);
+	}
+
+	#[test]
+	fn whatwg_parser_builds_the_browser_tree() {
+		let (document, errors) = parse_whatwg(
+			"<!DOCTYPE html><style>p { color: red; }</style><p>a<table>b</table>".into(),
+		);
+		assert_eq!(document.mode, dom::QuirksMode::NoQuirks);
+		assert_eq!(document.stylesheets.len(), 1);
+		assert_eq!(
+			errors
+				.iter()
+				.map(|err| err.kind.clone())
+				.collect::<Vec<_>>(),
+			vec![ParseErrorKind::Spec("unexpected-character-in-table")]
+		);
+
+		// The table closes the paragraph, and text inside it is moved before it
+		let html = document.root().unwrap();
+		let body = document.last_child(html).unwrap();
+		assert_eq!(document.inner_html(body), "<p>a</p>b<table></table>");
+	}
+
+	#[test]
+	fn decodes_bytes_using_the_declared_encoding() {
+		let html = b"<html><meta charset=latin1><p>caf\xE9</p></html>";
+		assert_eq!(decode(html, None).1, Encoding::Windows1252);
+		assert_eq!(decode(html, Some(Encoding::Utf8)).1, Encoding::Utf8);
+		assert_eq!(
+			decode(
+				b"\xEF\xBB\xBF<p>caf\xC3\xA9</p>",
+				Some(Encoding::Windows1252)
+			),
+			("<p>caf\u{E9}</p>".to_string(), Encoding::Utf8)
+		);
+		assert_eq!(
+			decode(b"\xFF\xFE<\0p\0>\0", None),
+			("<p>".to_string(), Encoding::Utf16Le)
+		);
+
+		let root = parse_bytes(html, None).unwrap();
+		let p = &root.children[1];
+		assert_eq!(p.children, vec![dom::text("caf\u{E9}".into())]);
+	}
+
+	#[test]
+	fn falls_back_to_windows_1252() {
+		assert_eq!(
+			decode(b"<p>\x93hi\x94</p>", None),
+			(
+				"<p>\u{201C}hi\u{201D}</p>".to_string(),
+				Encoding::Windows1252
+			)
+		);
+	}
+
+	#[test]
+	fn preserves_whitespace_text() {
+		let root = parse("<p><b>a</b> <i>b</i></p>".into()).unwrap();
+		assert_eq!(root.children[1], dom::text(" ".into()));
+
+		let root = parse("\n<pre>\n  x\n    y\n</pre>\n".into()).unwrap();
+		assert_eq!(
+			root,
+			dom::elem(
+				"pre".into(),
+				HashMap::new(),
+				vec![dom::text("\n  x\n    y\n".into())]
+			)
+		);
+
+		// Non-breaking spaces aren't whitespace as far as HTML is concerned
+		let root = parse("&nbsp;<p>a</p>".into()).unwrap();
+		assert_eq!(root.children[0], dom::text("\u{A0}".into()));
+	}
+
+	#[test]
+	fn parses_fragments_in_context() {
+		let html = |source: &str, context_tag: &str| {
+			let (document, fragment) = parse_fragment(source.into(), context_tag);
+			document.inner_html(fragment)
+		};
+		assert_eq!(html("<li>a<li>b", "ul"), "<li>a</li><li>b</li>");
+		assert_eq!(html("<tr><td>x</td></tr>", "tbody"), "<tr><td>x</td></tr>");
+		assert_eq!(
+			html("<tr><td>x</td></tr>", "table"),
+			"<tbody><tr><td>x</td></tr></tbody>"
+		);
+
+		// Outside of a table, table parts are dropped
+		assert_eq!(html("<td>x</td>", "div"), "x");
+		assert_eq!(
+			html("<b>a</b></title>", "title"),
+			"&lt;b&gt;a&lt;/b&gt;&lt;/title&gt;"
+		);
+
+		// The nodes are in a fragment rather than in the document's tree
+		let (document, fragment) = parse_fragment("<p>a</p>".into(), "div");
+		assert_eq!(document.parent(fragment), None);
+		assert_eq!(document.root(), None);
+	}
+
+	#[test]
+	fn keeps_template_contents_in_a_fragment() {
+		let root = parse("<div><template><p>a</p></template></div>".into()).unwrap();
+		let template = &root.children[0];
+		assert!(template.children.is_empty());
+		let contents = match template.node_type {
+			dom::NodeType::Element(ref data) => data.template_contents.as_deref(),
+			_ => None,
+		};
+		assert_eq!(
+			contents,
+			Some(&dom::fragment(vec![dom::elem(
+				"p".into(),
+				HashMap::new(),
+				vec![dom::text("a".into())]
+			)]))
+		);
+
+		// In a document the contents are a fragment of their own, but they're still serialized
+		let document = dom::Document::new(vec![root], dom::QuirksMode::NoQuirks);
+		let div = document.root().unwrap();
+		assert_eq!(document.inner_html(div), "<template><p>a</p></template>");
 	}
 }
@@ -0,0 +1,157 @@
+use encoding;
+use html::entities::NAMED_REFERENCES;
+
+// Decoding of character references such as `&amp;`, `&#169;` and `&#x1F600;`
+// https://html.spec.whatwg.org/multipage/parsing.html#character-reference-state
+
+// The longest name in the named reference table
+const MAX_NAME_LENGTH: usize = 32;
+
+// Decode every character reference in `input`. References which aren't recognised are left as
+// they are. In attribute values, legacy references without a semicolon are only decoded if they
+// aren't followed by `=` or an alphanumeric, so query strings like `?a=1&copy=2` survive.
+pub fn decode(input: &str, in_attribute: bool) -> String {
+	let mut output = String::with_capacity(input.len());
+	let mut rest = input;
+	while let Some(amp) = rest.find('&') {
+		output.push_str(&rest[..amp]);
+		rest = &rest[amp + 1..];
+		match decode_reference(rest, in_attribute, &mut output) {
+			Some(consumed) => rest = &rest[consumed..],
+			None => output.push('&'),
+		}
+	}
+	output.push_str(rest);
+	output
+}
+
+// Decode the reference at the start of `input`, which follows an `&`, onto the end of `output`.
+// Returns the number of bytes of `input` the reference used.
+pub fn decode_reference(input: &str, in_attribute: bool, output: &mut String) -> Option<usize> {
+	if let Some(numeric) = input.strip_prefix('#') {
+		let (decoded, consumed) = decode_numeric(numeric)?;
+		output.push(decoded);
+		Some(consumed + 1)
+	} else {
+		let (decoded, consumed) = decode_named(input, in_attribute)?;
+		output.push_str(decoded);
+		Some(consumed)
+	}
+}
+
+fn decode_numeric(input: &str) -> Option<(char, usize)> {
+	let (radix, prefix) = if input.starts_with('x') || input.starts_with('X') {
+		(16, 1)
+	} else {
+		(10, 0)
+	};
+	let digits = input[prefix..]
+		.chars()
+		.take_while(|c| c.is_digit(radix))
+		.count();
+	if digits == 0 {
+		return None;
+	}
+
+	// Values too large to fit are out of range anyway
+	let mut consumed = prefix + digits;
+	let value = u32::from_str_radix(&input[prefix..consumed], radix).unwrap_or(u32::MAX);
+
+	// A missing semicolon is a parse error, but the reference is still decoded
+	if input[consumed..].starts_with(';') {
+		consumed += 1;
+	}
+	Some((numeric_reference_char(value), consumed))
+}
+
+fn numeric_reference_char(value: u32) -> char {
+	match value {
+		0 => '\u{FFFD}',
+		// Numeric references to C1 control characters are assumed to mean their windows-1252
+		// character
+		0x80..=0x9F => encoding::windows_1252_char(value as u8),
+		// Surrogates and anything outside of Unicode
+		_ => char::from_u32(value).unwrap_or('\u{FFFD}'),
+	}
+}
+
+fn decode_named(input: &str, in_attribute: bool) -> Option<(&'static str, usize)> {
+	let name_length = input.bytes().take_while(u8::is_ascii_alphanumeric).count();
+	if name_length == 0 {
+		return None;
+	}
+
+	if input[name_length..].starts_with(';') {
+		if let Some(decoded) = lookup(&input[..=name_length]) {
+			return Some((decoded, name_length + 1));
+		}
+	}
+
+	// Legacy references are recognised without their semicolon, and the longest one wins
+	for length in (1..=name_length.min(MAX_NAME_LENGTH)).rev() {
+		if let Some(decoded) = lookup(&input[..length]) {
+			let next = input[length..].chars().next();
+			if in_attribute && next.is_some_and(|c| c == '=' || c.is_ascii_alphanumeric()) {
+				return None;
+			}
+			return Some((decoded, length));
+		}
+	}
+	None
+}
+
+fn lookup(name: &str) -> Option<&'static str> {
+	NAMED_REFERENCES
+		.binary_search_by_key(&name, |&(name, _)| name)
+		.ok()
+		.map(|index| NAMED_REFERENCES[index].1)
+}
+
+#[cfg(test)]
+mod char_refs_tests {
+	use super::*;
+
+	#[test]
+	fn decodes_named_references() {
+		assert_eq!(
+			decode("a &amp; b &lt;&gt; &NotNestedLessLess;", false),
+			"a & b <> \u{2AA1}\u{338}"
+		);
+	}
+
+	#[test]
+	fn decodes_numeric_references() {
+		assert_eq!(
+			decode("&#169;&#xa9;&#X1F600;&#65", false),
+			"\u{A9}\u{A9}\u{1F600}A"
+		);
+		assert_eq!(
+			decode("&#0;&#xD800;&#x110000;&#99999999999;", false),
+			"\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}"
+		);
+		assert_eq!(decode("&#x80;&#x81;", false), "\u{20AC}\u{81}");
+	}
+
+	#[test]
+	fn leaves_unknown_references_alone() {
+		assert_eq!(
+			decode("AT&T &; &# &#x; &bogus;", false),
+			"AT&T &; &# &#x; &bogus;"
+		);
+	}
+
+	#[test]
+	fn decodes_legacy_references_without_semicolons() {
+		assert_eq!(
+			decode("&copy 2020 &notit; &notin;", false),
+			"\u{A9} 2020 \u{AC}it; \u{2209}"
+		);
+		assert_eq!(decode("&ampfoo", false), "&foo");
+	}
+
+	#[test]
+	fn keeps_legacy_references_in_attribute_urls() {
+		assert_eq!(decode("?a=1&copy=2&not", true), "?a=1&copy=2\u{AC}");
+		assert_eq!(decode("&copyright &copy;", true), "&copyright \u{A9}");
+	}
+}
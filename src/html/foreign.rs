@@ -0,0 +1,212 @@
+use dom;
+use html::tokenizer::Tag;
+
+// SVG and MathML inside HTML. The tokenizer lowercases every name, so names which are mixed case in
+// SVG and MathML have to be fixed up before the elements are created
+// https://html.spec.whatwg.org/multipage/parsing.html#creating-and-inserting-nodes
+
+// Foreign elements whose contents are parsed as HTML
+// https://html.spec.whatwg.org/multipage/parsing.html#mathml-text-integration-point
+// https://html.spec.whatwg.org/multipage/parsing.html#html-integration-point
+pub const MATHML_TEXT_INTEGRATION_POINTS: &[&str] = &["mi", "mo", "mn", "ms", "mtext"];
+pub const SVG_HTML_INTEGRATION_POINTS: &[&str] = &["foreignObject", "desc", "title"];
+
+// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign
+static SVG_TAG_NAMES: &[(&str, &str)] = &[
+	("altglyph", "altGlyph"),
+	("altglyphdef", "altGlyphDef"),
+	("altglyphitem", "altGlyphItem"),
+	("animatecolor", "animateColor"),
+	("animatemotion", "animateMotion"),
+	("animatetransform", "animateTransform"),
+	("clippath", "clipPath"),
+	("feblend", "feBlend"),
+	("fecolormatrix", "feColorMatrix"),
+	("fecomponenttransfer", "feComponentTransfer"),
+	("fecomposite", "feComposite"),
+	("feconvolvematrix", "feConvolveMatrix"),
+	("fediffuselighting", "feDiffuseLighting"),
+	("fedisplacementmap", "feDisplacementMap"),
+	("fedistantlight", "feDistantLight"),
+	("fedropshadow", "feDropShadow"),
+	("feflood", "feFlood"),
+	("fefunca", "feFuncA"),
+	("fefuncb", "feFuncB"),
+	("fefuncg", "feFuncG"),
+	("fefuncr", "feFuncR"),
+	("fegaussianblur", "feGaussianBlur"),
+	("feimage", "feImage"),
+	("femerge", "feMerge"),
+	("femergenode", "feMergeNode"),
+	("femorphology", "feMorphology"),
+	("feoffset", "feOffset"),
+	("fepointlight", "fePointLight"),
+	("fespecularlighting", "feSpecularLighting"),
+	("fespotlight", "feSpotLight"),
+	("fetile", "feTile"),
+	("feturbulence", "feTurbulence"),
+	("foreignobject", "foreignObject"),
+	("glyphref", "glyphRef"),
+	("lineargradient", "linearGradient"),
+	("radialgradient", "radialGradient"),
+	("textpath", "textPath"),
+];
+
+// https://html.spec.whatwg.org/multipage/parsing.html#adjust-svg-attributes
+static SVG_ATTRIBUTES: &[(&str, &str)] = &[
+	("attributename", "attributeName"),
+	("attributetype", "attributeType"),
+	("basefrequency", "baseFrequency"),
+	("baseprofile", "baseProfile"),
+	("calcmode", "calcMode"),
+	("clippathunits", "clipPathUnits"),
+	("diffuseconstant", "diffuseConstant"),
+	("edgemode", "edgeMode"),
+	("filterunits", "filterUnits"),
+	("glyphref", "glyphRef"),
+	("gradienttransform", "gradientTransform"),
+	("gradientunits", "gradientUnits"),
+	("kernelmatrix", "kernelMatrix"),
+	("kernelunitlength", "kernelUnitLength"),
+	("keypoints", "keyPoints"),
+	("keysplines", "keySplines"),
+	("keytimes", "keyTimes"),
+	("lengthadjust", "lengthAdjust"),
+	("limitingconeangle", "limitingConeAngle"),
+	("markerheight", "markerHeight"),
+	("markerunits", "markerUnits"),
+	("markerwidth", "markerWidth"),
+	("maskcontentunits", "maskContentUnits"),
+	("maskunits", "maskUnits"),
+	("numoctaves", "numOctaves"),
+	("pathlength", "pathLength"),
+	("patterncontentunits", "patternContentUnits"),
+	("patterntransform", "patternTransform"),
+	("patternunits", "patternUnits"),
+	("pointsatx", "pointsAtX"),
+	("pointsaty", "pointsAtY"),
+	("pointsatz", "pointsAtZ"),
+	("preservealpha", "preserveAlpha"),
+	("preserveaspectratio", "preserveAspectRatio"),
+	("primitiveunits", "primitiveUnits"),
+	("refx", "refX"),
+	("refy", "refY"),
+	("repeatcount", "repeatCount"),
+	("repeatdur", "repeatDur"),
+	("requiredextensions", "requiredExtensions"),
+	("requiredfeatures", "requiredFeatures"),
+	("specularconstant", "specularConstant"),
+	("specularexponent", "specularExponent"),
+	("spreadmethod", "spreadMethod"),
+	("startoffset", "startOffset"),
+	("stddeviation", "stdDeviation"),
+	("stitchtiles", "stitchTiles"),
+	("surfacescale", "surfaceScale"),
+	("systemlanguage", "systemLanguage"),
+	("tablevalues", "tableValues"),
+	("targetx", "targetX"),
+	("targety", "targetY"),
+	("textlength", "textLength"),
+	("viewbox", "viewBox"),
+	("viewtarget", "viewTarget"),
+	("xchannelselector", "xChannelSelector"),
+	("ychannelselector", "yChannelSelector"),
+	("zoomandpan", "zoomAndPan"),
+];
+
+// https://html.spec.whatwg.org/multipage/parsing.html#adjust-mathml-attributes
+static MATHML_ATTRIBUTES: &[(&str, &str)] = &[("definitionurl", "definitionURL")];
+
+// Attributes on foreign elements which are in a namespace of their own
+// https://html.spec.whatwg.org/multipage/parsing.html#adjust-foreign-attributes
+static FOREIGN_ATTRIBUTES: &[(&str, &str)] = &[
+	("xlink:actuate", dom::XLINK_NAMESPACE),
+	("xlink:arcrole", dom::XLINK_NAMESPACE),
+	("xlink:href", dom::XLINK_NAMESPACE),
+	("xlink:role", dom::XLINK_NAMESPACE),
+	("xlink:show", dom::XLINK_NAMESPACE),
+	("xlink:title", dom::XLINK_NAMESPACE),
+	("xlink:type", dom::XLINK_NAMESPACE),
+	("xml:lang", dom::XML_NAMESPACE),
+	("xml:space", dom::XML_NAMESPACE),
+	("xmlns", dom::XMLNS_NAMESPACE),
+	("xmlns:xlink", dom::XMLNS_NAMESPACE),
+];
+
+fn lookup(table: &[(&str, &'static str)], name: &str) -> Option<&'static str> {
+	table
+		.iter()
+		.find(|&&(from, _)| from == name)
+		.map(|&(_, to)| to)
+}
+
+fn adjust_attributes(tag: &mut Tag, table: &[(&str, &'static str)]) {
+	for attribute in &mut tag.attributes {
+		if let Some(name) = lookup(table, &attribute.0) {
+			attribute.0 = name.to_string();
+		}
+	}
+}
+
+pub fn adjust_svg_tag_name(tag: &mut Tag) {
+	if let Some(name) = svg_tag_name(&tag.name) {
+		tag.name = name.to_string();
+	}
+}
+
+// The mixed-case form of a lowercased SVG tag name, if it has one
+pub fn svg_tag_name(name: &str) -> Option<&'static str> {
+	lookup(SVG_TAG_NAMES, name)
+}
+
+// The mixed-case form of a lowercased attribute name on an SVG or MathML element, if it has one
+pub fn attribute_name(namespace: &str, name: &str) -> Option<&'static str> {
+	match namespace {
+		dom::SVG_NAMESPACE => lookup(SVG_ATTRIBUTES, name),
+		dom::MATHML_NAMESPACE => lookup(MATHML_ATTRIBUTES, name),
+		_ => None,
+	}
+}
+
+pub fn adjust_svg_attributes(tag: &mut Tag) {
+	adjust_attributes(tag, SVG_ATTRIBUTES);
+}
+
+pub fn adjust_mathml_attributes(tag: &mut Tag) {
+	adjust_attributes(tag, MATHML_ATTRIBUTES);
+}
+
+// The namespace an attribute of a foreign element is in, if any
+pub fn attribute_namespace(name: &str) -> Option<&'static str> {
+	lookup(FOREIGN_ATTRIBUTES, name)
+}
+
+#[cfg(test)]
+mod foreign_tests {
+	use super::*;
+
+	#[test]
+	fn adjusts_names_to_their_mixed_case_forms() {
+		let mut tag = Tag::new("foreignobject");
+		tag.attributes.push(("viewbox".into(), "0 0 1 1".into()));
+		tag.attributes.push(("definitionurl".into(), "x".into()));
+		adjust_svg_tag_name(&mut tag);
+		adjust_svg_attributes(&mut tag);
+		assert_eq!(tag.name, "foreignObject");
+		assert_eq!(tag.attribute("viewBox"), Some("0 0 1 1"));
+		assert_eq!(tag.attribute("definitionurl"), Some("x"));
+
+		adjust_mathml_attributes(&mut tag);
+		assert_eq!(tag.attribute("definitionURL"), Some("x"));
+	}
+
+	#[test]
+	fn finds_foreign_attribute_namespaces() {
+		assert_eq!(
+			attribute_namespace("xlink:href"),
+			Some(dom::XLINK_NAMESPACE)
+		);
+		assert_eq!(attribute_namespace("xmlns"), Some(dom::XMLNS_NAMESPACE));
+		assert_eq!(attribute_namespace("href"), None);
+	}
+}
@@ -0,0 +1,257 @@
+use encoding::Encoding;
+
+// Finding a `<meta charset>` before the document has been decoded
+// https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+
+// Only the start of the document is looked at
+const PRESCAN_LENGTH: usize = 1024;
+
+fn is_whitespace(byte: u8) -> bool {
+	matches!(byte, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+struct Prescanner<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Prescanner<'a> {
+	fn peek(&self) -> Option<u8> {
+		self.bytes.get(self.pos).cloned()
+	}
+
+	fn starts_with_ignore_case(&self, s: &[u8]) -> bool {
+		self.bytes[self.pos..]
+			.get(..s.len())
+			.is_some_and(|next| next.eq_ignore_ascii_case(s))
+	}
+
+	fn skip_while<F>(&mut self, test: F)
+	where
+		F: Fn(u8) -> bool,
+	{
+		while self.peek().is_some_and(&test) {
+			self.pos += 1;
+		}
+	}
+
+	// Move past the next occurrence of `s`, or to the end if there isn't one
+	fn skip_past(&mut self, s: &[u8]) {
+		match self.bytes[self.pos..]
+			.windows(s.len())
+			.position(|window| window == s)
+		{
+			Some(i) => self.pos += i + s.len(),
+			None => self.pos = self.bytes.len(),
+		}
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#concept-get-attributes-when-sniffing
+	fn attribute(&mut self) -> Option<(String, String)> {
+		self.skip_while(|byte| is_whitespace(byte) || byte == b'/');
+		let mut name = Vec::new();
+		let mut value = Vec::new();
+		loop {
+			match self.peek()? {
+				b'>' if name.is_empty() => return None,
+				b'=' if !name.is_empty() => {
+					self.pos += 1;
+					break;
+				}
+				b'/' | b'>' => return Some(attribute(name, value)),
+				byte if is_whitespace(byte) => {
+					self.skip_while(is_whitespace);
+					if self.peek() != Some(b'=') {
+						return Some(attribute(name, value));
+					}
+					self.pos += 1;
+					break;
+				}
+				byte => {
+					name.push(byte.to_ascii_lowercase());
+					self.pos += 1;
+				}
+			}
+		}
+
+		self.skip_while(is_whitespace);
+		match self.peek()? {
+			quote @ b'"' | quote @ b'\'' => {
+				self.pos += 1;
+				loop {
+					let byte = self.peek()?;
+					self.pos += 1;
+					if byte == quote {
+						break;
+					}
+					value.push(byte.to_ascii_lowercase());
+				}
+			}
+			b'>' => {}
+			_ => {
+				while let Some(byte) = self.peek() {
+					if is_whitespace(byte) || byte == b'>' {
+						break;
+					}
+					value.push(byte.to_ascii_lowercase());
+					self.pos += 1;
+				}
+			}
+		}
+		Some(attribute(name, value))
+	}
+
+	// Work out the encoding a `<meta>` tag declares, once its name has been consumed
+	fn meta(&mut self) -> Option<Encoding> {
+		let mut seen = Vec::new();
+		let mut got_pragma = false;
+		let mut need_pragma = None;
+		let mut charset = None;
+		while let Some((name, value)) = self.attribute() {
+			if seen.contains(&name) {
+				continue;
+			}
+			match name.as_str() {
+				"http-equiv" if value == "content-type" => got_pragma = true,
+				"content" if charset.is_none() => {
+					if let Some(encoding) = charset_from_content(&value) {
+						charset = Some(encoding);
+						need_pragma = Some(true);
+					}
+				}
+				"charset" => {
+					charset = Encoding::for_label(&value);
+					need_pragma = Some(false);
+				}
+				_ => {}
+			}
+			seen.push(name);
+		}
+
+		match need_pragma {
+			None => None,
+			Some(true) if !got_pragma => None,
+			// The meta tag was readable as ASCII, so the document can't really be UTF-16
+			_ => charset.map(|encoding| {
+				if encoding.is_utf16() {
+					Encoding::Utf8
+				} else {
+					encoding
+				}
+			}),
+		}
+	}
+
+	fn scan(&mut self) -> Option<Encoding> {
+		while self.pos < self.bytes.len() {
+			if self.starts_with_ignore_case(b"<!--") {
+				self.pos += 2;
+				self.skip_past(b"-->");
+			} else if self.starts_with_ignore_case(b"<meta")
+				&& self
+					.bytes
+					.get(self.pos + 5)
+					.is_some_and(|&byte| is_whitespace(byte) || byte == b'/')
+			{
+				self.pos += 5;
+				if let Some(encoding) = self.meta() {
+					return Some(encoding);
+				}
+			} else if self.is_tag_start() {
+				self.skip_while(|byte| !is_whitespace(byte) && byte != b'>');
+				while self.attribute().is_some() {}
+			} else if self.starts_with_ignore_case(b"<!")
+				|| self.starts_with_ignore_case(b"</")
+				|| self.starts_with_ignore_case(b"<?")
+			{
+				self.skip_past(b">");
+			} else {
+				self.pos += 1;
+			}
+		}
+		None
+	}
+
+	// Is the next thing a start or end tag, like `<p` or `</p`?
+	fn is_tag_start(&self) -> bool {
+		let rest = &self.bytes[self.pos..];
+		match rest {
+			[b'<', b'/', letter, ..] | [b'<', letter, ..] => letter.is_ascii_alphabetic(),
+			_ => false,
+		}
+	}
+}
+
+fn attribute(name: Vec<u8>, value: Vec<u8>) -> (String, String) {
+	(
+		String::from_utf8_lossy(&name).into_owned(),
+		String::from_utf8_lossy(&value).into_owned(),
+	)
+}
+
+// Pull the charset out of a `content` attribute like `text/html; charset=utf-8`
+// https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn charset_from_content(content: &str) -> Option<Encoding> {
+	let mut rest = content;
+	loop {
+		let i = rest.find("charset")?;
+		rest = rest[i + "charset".len()..].trim_start_matches(|c: char| is_whitespace(c as u8));
+		if let Some(value) = rest.strip_prefix('=') {
+			rest = value.trim_start_matches(|c: char| is_whitespace(c as u8));
+			break;
+		}
+	}
+	let label = match rest.chars().next()? {
+		quote @ '"' | quote @ '\'' => {
+			let end = rest[1..].find(quote)?;
+			&rest[1..=end]
+		}
+		_ => rest
+			.split(|c: char| is_whitespace(c as u8) || c == ';')
+			.next()
+			.unwrap_or(""),
+	};
+	Encoding::for_label(label)
+}
+
+// Look for a `<meta>` tag declaring the document's encoding
+pub fn prescan(bytes: &[u8]) -> Option<Encoding> {
+	let length = bytes.len().min(PRESCAN_LENGTH);
+	Prescanner {
+		bytes: &bytes[..length],
+		pos: 0,
+	}
+	.scan()
+}
+
+#[cfg(test)]
+mod prescan_tests {
+	use super::*;
+
+	#[test]
+	fn finds_meta_charset() {
+		assert_eq!(
+			prescan(b"<!DOCTYPE html><title>x</title><META CHARSET='latin1'>"),
+			Some(Encoding::Windows1252)
+		);
+		assert_eq!(prescan(b"<meta charset=utf-16>"), Some(Encoding::Utf8));
+		assert_eq!(prescan(b"<meta charset=unknown>"), None);
+	}
+
+	#[test]
+	fn finds_http_equiv_content_type() {
+		let html = b"<meta content=\"text/html; charset=windows-1252\" http-equiv=Content-Type>";
+		assert_eq!(prescan(html), Some(Encoding::Windows1252));
+
+		// The pragma is required for `content` to count
+		assert_eq!(prescan(b"<meta content='charset=utf-8'>"), None);
+	}
+
+	#[test]
+	fn skips_comments_and_attribute_values() {
+		assert_eq!(
+			prescan(b"<!-- <meta charset=latin1> --><p title='<meta charset=latin1>'>"),
+			None
+		);
+	}
+}
@@ -0,0 +1,142 @@
+use dom::{DocumentType, QuirksMode};
+
+// Deciding the quirks mode of a document from its doctype
+// https://html.spec.whatwg.org/multipage/parsing.html#the-initial-insertion-mode
+
+// Public identifiers which put the document into quirks mode
+static QUIRKY_PUBLIC_IDS: &[&str] = &[
+	"-//W3O//DTD W3 HTML Strict 3.0//EN//",
+	"-/W3C/DTD HTML 4.0 Transitional/EN",
+	"HTML",
+];
+
+// Prefixes of public identifiers which put the document into quirks mode
+static QUIRKY_PUBLIC_ID_PREFIXES: &[&str] = &[
+	"+//Silmaril//dtd html Pro v0r11 19970101//",
+	"-//AS//DTD HTML 3.0 asWedit + extensions//",
+	"-//AdvaSoft Ltd//DTD HTML 3.0 asWedit + extensions//",
+	"-//IETF//DTD HTML 2.0 Level 1//",
+	"-//IETF//DTD HTML 2.0 Level 2//",
+	"-//IETF//DTD HTML 2.0 Strict Level 1//",
+	"-//IETF//DTD HTML 2.0 Strict Level 2//",
+	"-//IETF//DTD HTML 2.0 Strict//",
+	"-//IETF//DTD HTML 2.0//",
+	"-//IETF//DTD HTML 2.1E//",
+	"-//IETF//DTD HTML 3.0//",
+	"-//IETF//DTD HTML 3.2 Final//",
+	"-//IETF//DTD HTML 3.2//",
+	"-//IETF//DTD HTML 3//",
+	"-//IETF//DTD HTML Level 0//",
+	"-//IETF//DTD HTML Level 1//",
+	"-//IETF//DTD HTML Level 2//",
+	"-//IETF//DTD HTML Level 3//",
+	"-//IETF//DTD HTML Strict Level 0//",
+	"-//IETF//DTD HTML Strict Level 1//",
+	"-//IETF//DTD HTML Strict Level 2//",
+	"-//IETF//DTD HTML Strict Level 3//",
+	"-//IETF//DTD HTML Strict//",
+	"-//IETF//DTD HTML//",
+	"-//Metrius//DTD Metrius Presentational//",
+	"-//Microsoft//DTD Internet Explorer 2.0 HTML Strict//",
+	"-//Microsoft//DTD Internet Explorer 2.0 HTML//",
+	"-//Microsoft//DTD Internet Explorer 2.0 Tables//",
+	"-//Microsoft//DTD Internet Explorer 3.0 HTML Strict//",
+	"-//Microsoft//DTD Internet Explorer 3.0 HTML//",
+	"-//Microsoft//DTD Internet Explorer 3.0 Tables//",
+	"-//Netscape Comm. Corp.//DTD HTML//",
+	"-//Netscape Comm. Corp.//DTD Strict HTML//",
+	"-//O'Reilly and Associates//DTD HTML 2.0//",
+	"-//O'Reilly and Associates//DTD HTML Extended 1.0//",
+	"-//O'Reilly and Associates//DTD HTML Extended Relaxed 1.0//",
+	"-//SQ//DTD HTML 2.0 HoTMetaL + extensions//",
+	"-//SoftQuad Software//DTD HoTMetaL PRO 6.0::19990601::extensions to HTML 4.0//",
+	"-//SoftQuad//DTD HoTMetaL PRO 4.0::19971010::extensions to HTML 4.0//",
+	"-//Spyglass//DTD HTML 2.0 Extended//",
+	"-//Sun Microsystems Corp.//DTD HotJava HTML//",
+	"-//Sun Microsystems Corp.//DTD HotJava Strict HTML//",
+	"-//W3C//DTD HTML 3 1995-03-24//",
+	"-//W3C//DTD HTML 3.2 Draft//",
+	"-//W3C//DTD HTML 3.2 Final//",
+	"-//W3C//DTD HTML 3.2//",
+	"-//W3C//DTD HTML 3.2S Draft//",
+	"-//W3C//DTD HTML 4.0 Frameset//",
+	"-//W3C//DTD HTML 4.0 Transitional//",
+	"-//W3C//DTD HTML Experimental 19960712//",
+	"-//W3C//DTD HTML Experimental 970421//",
+	"-//W3C//DTD W3 HTML//",
+	"-//W3O//DTD W3 HTML 3.0//",
+	"-//WebTechs//DTD Mozilla HTML 2.0//",
+	"-//WebTechs//DTD Mozilla HTML//",
+];
+
+// Prefixes which mean quirks mode without a system identifier, and limited quirks mode with one
+static HTML4_TRANSITIONAL_PREFIXES: &[&str] = &[
+	"-//W3C//DTD HTML 4.01 Frameset//",
+	"-//W3C//DTD HTML 4.01 Transitional//",
+];
+
+// Prefixes of public identifiers which put the document into limited quirks mode
+static LIMITED_QUIRKS_PREFIXES: &[&str] = &[
+	"-//W3C//DTD XHTML 1.0 Frameset//",
+	"-//W3C//DTD XHTML 1.0 Transitional//",
+];
+
+const QUIRKY_SYSTEM_ID: &str = "http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd";
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+	s.len() >= prefix.len()
+		&& s.is_char_boundary(prefix.len())
+		&& s[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn has_prefix(id: &str, prefixes: &[&str]) -> bool {
+	prefixes
+		.iter()
+		.any(|prefix| starts_with_ignore_case(id, prefix))
+}
+
+// The mode a document with the given doctype should be rendered in. Documents without a doctype
+// and doctypes which were malformed (`force_quirks`) are always rendered in quirks mode.
+pub fn quirks_mode(doctype: Option<&DocumentType>, force_quirks: bool) -> QuirksMode {
+	let doctype = match doctype {
+		Some(doctype) if !force_quirks => doctype,
+		_ => return QuirksMode::Quirks,
+	};
+	if doctype.name != "html" {
+		return QuirksMode::Quirks;
+	}
+
+	let public_id = doctype.public_id.as_deref();
+	let system_id = doctype.system_id.as_deref();
+	if let Some(system_id) = system_id {
+		if system_id.eq_ignore_ascii_case(QUIRKY_SYSTEM_ID) {
+			return QuirksMode::Quirks;
+		}
+	}
+
+	let public_id = match public_id {
+		Some(public_id) => public_id,
+		None => return QuirksMode::NoQuirks,
+	};
+	if QUIRKY_PUBLIC_IDS
+		.iter()
+		.any(|id| public_id.eq_ignore_ascii_case(id))
+		|| has_prefix(public_id, QUIRKY_PUBLIC_ID_PREFIXES)
+	{
+		return QuirksMode::Quirks;
+	}
+
+	if has_prefix(public_id, HTML4_TRANSITIONAL_PREFIXES) {
+		return if system_id.is_none() {
+			QuirksMode::Quirks
+		} else {
+			QuirksMode::LimitedQuirks
+		};
+	}
+
+	if has_prefix(public_id, LIMITED_QUIRKS_PREFIXES) {
+		return QuirksMode::LimitedQuirks;
+	}
+
+	QuirksMode::NoQuirks
+}
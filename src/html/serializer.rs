@@ -0,0 +1,357 @@
+use dom::{self, Document, NodeId, NodeType};
+use html;
+use std::fmt::Write;
+
+// Turning a DOM tree back into HTML
+// https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
+
+// Elements whose whitespace matters, so pretty printing leaves their contents alone
+static PREFORMATTED_ELEMENTS: &[&str] = &["listing", "pre", "textarea"];
+
+const INDENT: &str = "  ";
+
+// Void elements, plus the obsolete ones the serializer also never writes an end tag for
+fn is_void(tag_name: &str) -> bool {
+	html::is_void_element(tag_name) || matches!(tag_name, "basefont" | "bgsound" | "frame")
+}
+
+// Elements whose text is written out as it is, without escaping
+fn is_raw_text(tag_name: &str) -> bool {
+	html::is_raw_text_element(tag_name) || tag_name == "plaintext"
+}
+
+// The node and its descendants, like `outerHTML`
+pub fn outer_html(document: &Document, id: NodeId) -> String {
+	let mut out = String::new();
+	write_node(document, id, None, &mut out);
+	out
+}
+
+// Only the node's descendants, like `innerHTML`
+pub fn inner_html(document: &Document, id: NodeId) -> String {
+	let mut out = String::new();
+	write_children(document, id, &mut out);
+	out
+}
+
+pub fn document_html(document: &Document) -> String {
+	inner_html(document, document.document_node())
+}
+
+// Like `outer_html`, but with each element's children indented on their own lines. This changes
+// the whitespace in the document, so it's meant for people to read rather than for round trips
+pub fn pretty_html(document: &Document, id: NodeId) -> String {
+	let mut out = String::new();
+	write_pretty(document, id, None, 0, &mut out);
+	out
+}
+
+pub fn pretty_document_html(document: &Document) -> String {
+	let lines: Vec<String> = document
+		.children(document.document_node())
+		.map(|id| pretty_html(document, id))
+		.collect();
+	lines.join("\n")
+}
+
+fn write_node(document: &Document, id: NodeId, parent: Option<&str>, out: &mut String) {
+	match document.node(id).node_type {
+		NodeType::Element(ref data) => {
+			write_start_tag(data, out);
+			if !(data.is_html() && is_void(&data.tag_name)) {
+				write_children(document, id, out);
+				let _ = write!(out, "</{}>", data.qualified_name());
+			}
+		}
+		_ => write_leaf(document, id, parent, out),
+	}
+}
+
+fn write_children(document: &Document, id: NodeId, out: &mut String) {
+	let name = tag_name(document, id);
+	for child in children(document, id) {
+		write_node(document, child, name, out);
+	}
+}
+
+// A template's contents are written out as though they were its children
+fn children(document: &Document, id: NodeId) -> dom::Children<'_> {
+	document.children(document.template_contents(id).unwrap_or(id))
+}
+
+fn write_start_tag(data: &dom::ElementData, out: &mut String) {
+	out.push('<');
+	out.push_str(&data.qualified_name());
+
+	// Attributes are kept in a hash map, so sort them to get the same output every time
+	let mut attributes: Vec<_> = data.attributes.iter().collect();
+	attributes.sort();
+	for (name, value) in attributes {
+		let _ = write!(out, " {}=\"{}\"", name, escape(value, true));
+	}
+	out.push('>');
+}
+
+// Everything that isn't an element
+fn write_leaf(document: &Document, id: NodeId, parent: Option<&str>, out: &mut String) {
+	match document.node(id).node_type {
+		NodeType::Text(ref text) => write_text(text, parent, out),
+		NodeType::Comment(ref text) => {
+			let _ = write!(out, "<!--{}-->", text);
+		}
+		NodeType::CData(ref data) => {
+			let _ = write!(out, "<![CDATA[{}]]>", data);
+		}
+		NodeType::ProcessingInstruction {
+			ref target,
+			ref data,
+		} => {
+			let _ = write!(out, "<?{} {}>", target, data);
+		}
+		// Only the name is kept, as the spec says
+		NodeType::Doctype(ref doctype) => {
+			let _ = write!(out, "<!DOCTYPE {}>", doctype.name);
+		}
+		NodeType::Document | NodeType::DocumentFragment => write_children(document, id, out),
+		NodeType::Element(_) => write_node(document, id, parent, out),
+	}
+}
+
+fn write_text(text: &str, parent: Option<&str>, out: &mut String) {
+	if parent.is_some_and(is_raw_text) {
+		out.push_str(text);
+	} else {
+		out.push_str(&escape(text, false));
+	}
+}
+
+fn write_pretty(
+	document: &Document,
+	id: NodeId,
+	parent: Option<&str>,
+	depth: usize,
+	out: &mut String,
+) {
+	let indent = INDENT.repeat(depth);
+	let data = match document.node(id).node_type {
+		NodeType::Element(ref data) => data,
+		NodeType::Text(ref text) => {
+			out.push_str(&indent);
+			write_text(
+				text.trim_matches(|c: char| c.is_ascii_whitespace()),
+				parent,
+				out,
+			);
+			return;
+		}
+		NodeType::Document | NodeType::DocumentFragment => {
+			let children = document
+				.children(id)
+				.filter(|&child| !is_whitespace(document, child));
+			for (i, child) in children.enumerate() {
+				if i > 0 {
+					out.push('\n');
+				}
+				write_pretty(document, child, None, depth, out);
+			}
+			return;
+		}
+		_ => {
+			out.push_str(&indent);
+			write_leaf(document, id, parent, out);
+			return;
+		}
+	};
+
+	out.push_str(&indent);
+	let name = data.tag_name.as_str();
+	let keep_contents = data.is_html()
+		&& (is_void(name) || is_raw_text(name) || PREFORMATTED_ELEMENTS.contains(&name));
+	let has_elements = children(document, id).any(|child| !is_text(document, child));
+	if keep_contents || !has_elements {
+		write_node(document, id, parent, out);
+		return;
+	}
+
+	write_start_tag(data, out);
+	for child in children(document, id) {
+		if is_whitespace(document, child) {
+			continue;
+		}
+		out.push('\n');
+		write_pretty(document, child, tag_name(document, id), depth + 1, out);
+	}
+	let _ = write!(out, "\n{}</{}>", indent, data.qualified_name());
+}
+
+fn tag_name(document: &Document, id: NodeId) -> Option<&str> {
+	document
+		.element(id)
+		.filter(|data| data.is_html())
+		.map(|data| data.tag_name.as_str())
+}
+
+fn is_text(document: &Document, id: NodeId) -> bool {
+	matches!(document.node(id).node_type, NodeType::Text(_))
+}
+
+fn is_whitespace(document: &Document, id: NodeId) -> bool {
+	match document.node(id).node_type {
+		NodeType::Text(ref text) => text.chars().all(|c| c.is_ascii_whitespace()),
+		_ => false,
+	}
+}
+
+// https://html.spec.whatwg.org/multipage/parsing.html#escapingString
+fn escape(text: &str, in_attribute: bool) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'\u{A0}' => escaped.push_str("&nbsp;"),
+			'"' if in_attribute => escaped.push_str("&quot;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+#[cfg(test)]
+mod serializer_tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	fn attrs(pairs: &[(&str, &str)]) -> dom::AttrMap {
+		pairs
+			.iter()
+			.map(|&(name, value)| (name.to_string(), value.to_string()))
+			.collect()
+	}
+
+	// Put a node in a document of its own
+	fn create(node: dom::Node) -> (Document, NodeId) {
+		let mut document = Document::new(Vec::new(), dom::QuirksMode::NoQuirks);
+		let id = document.create_node(node);
+		(document, id)
+	}
+
+	// Parsing the serialized tree should give back the same tree
+	fn assert_round_trips(source: &str) {
+		let (document, errors) = html::parse_whatwg(source.into());
+		assert_eq!(errors, vec![], "{}", source);
+		let (reparsed, _) = html::parse_whatwg(document_html(&document));
+		assert_eq!(document, reparsed);
+	}
+
+	#[test]
+	fn serializes_elements_and_attributes() {
+		let node = dom::elem(
+			"p".into(),
+			attrs(&[("title", "\"a\" & <b>"), ("class", "x")]),
+			vec![
+				dom::text("1 < 2 & 3\u{A0}".into()),
+				dom::elem("br".into(), HashMap::new(), vec![]),
+				dom::elem("span".into(), HashMap::new(), vec![]),
+				dom::comment(" note ".into()),
+			],
+		);
+		let (document, node) = create(node);
+		assert_eq!(
+			outer_html(&document, node),
+			"<p class=\"x\" title=\"&quot;a&quot; &amp; &lt;b&gt;\">1 &lt; 2 &amp; 3&nbsp;<br><span></span><!-- note --></p>"
+		);
+		assert_eq!(
+			inner_html(&document, node),
+			"1 &lt; 2 &amp; 3&nbsp;<br><span></span><!-- note -->"
+		);
+	}
+
+	#[test]
+	fn leaves_raw_text_unescaped() {
+		let script = dom::elem(
+			"script".into(),
+			HashMap::new(),
+			vec![dom::text("if (a < b && c) {}".into())],
+		);
+		let (document, script) = create(script);
+		assert_eq!(
+			outer_html(&document, script),
+			"<script>if (a < b && c) {}</script>"
+		);
+	}
+
+	#[test]
+	fn round_trips_documents() {
+		assert_round_trips("<!DOCTYPE html><html><head><title>A &amp; B</title></head><body><p class=\"a\">One<br>Two</p><!-- c --></body></html>");
+		assert_round_trips(
+			"<!DOCTYPE html><table><tr><td>&lt;x&gt;</td></tr></table><script>a < b</script>",
+		);
+		assert_round_trips("<!DOCTYPE html><svg viewBox=\"0 0 1 1\"><clipPath><circle/></clipPath><foreignObject><br></foreignObject></svg>");
+		assert_round_trips("<!DOCTYPE html><svg><text><![CDATA[a < b]]></text></svg>");
+		assert_round_trips("<!DOCTYPE html><ul>\n  <li title='\"q\"'>a&nbsp;b</li>\n</ul><textarea>&lt;/textarea&gt;</textarea>");
+	}
+
+	#[test]
+	fn round_trips_through_the_simple_parser() {
+		let (document, _) = html::parse_document(
+			"<div id=\"a\"><p>x &lt; y</p><img src=\"a&amp;b\"><style>p > a {}</style></div>"
+				.into(),
+		)
+		.unwrap();
+		let (reparsed, _) = html::parse_document(document_html(&document)).unwrap();
+		assert_eq!(reparsed, document);
+	}
+
+	#[test]
+	fn serializes_processing_instructions() {
+		let node = dom::elem(
+			"div".into(),
+			HashMap::new(),
+			vec![dom::processing_instruction(
+				"xml-stylesheet".into(),
+				"href=\"a.css\"".into(),
+			)],
+		);
+		let (document, node) = create(node);
+		assert_eq!(
+			outer_html(&document, node),
+			"<div><?xml-stylesheet href=\"a.css\"></div>"
+		);
+	}
+
+	#[test]
+	fn pretty_prints_nested_elements() {
+		let (document, _) = html::parse_whatwg(
+			"<!DOCTYPE html><body><div>\n<p>One <b>two</b></p><pre>  keep\n me</pre></div>".into(),
+		);
+		assert_eq!(
+			pretty_document_html(&document),
+			"<!DOCTYPE html>
+<html>
+  <head></head>
+  <body>
+    <div>
+      <p>
+        One
+        <b>two</b>
+      </p>
+      <pre>  keep
+ me</pre>
+    </div>
+  </body>
+</html>"
+		);
+
+		let (document, _) = html::parse_whatwg("<div><b>a</b>&nbsp;<p>x</p></div>".into());
+		let div = document
+			.query_selector(document.document_node(), "div")
+			.unwrap()
+			.unwrap();
+		assert_eq!(
+			pretty_html(&document, div),
+			"<div>\n  <b>a</b>\n  &nbsp;\n  <p>x</p>\n</div>"
+		);
+	}
+}
@@ -0,0 +1,1468 @@
+use html::char_refs;
+use parser::{LinePos, ParseError, ParseErrorKind, Parser};
+use std::collections::VecDeque;
+use std::mem;
+
+// The HTML tokenizer state machine
+// https://html.spec.whatwg.org/multipage/parsing.html#tokenization
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Doctype {
+	pub name: Option<String>,
+	pub public_id: Option<String>,
+	pub system_id: Option<String>,
+	pub force_quirks: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tag {
+	pub name: String,
+	// In source order, with duplicates already dropped
+	pub attributes: Vec<(String, String)>,
+	pub self_closing: bool,
+}
+
+impl Tag {
+	pub fn new(name: &str) -> Tag {
+		Tag {
+			name: name.to_string(),
+			attributes: Vec::new(),
+			self_closing: false,
+		}
+	}
+
+	pub fn attribute(&self, name: &str) -> Option<&str> {
+		self.attributes
+			.iter()
+			.find(|(attr, _)| attr == name)
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+	Doctype(Doctype),
+	StartTag(Tag),
+	EndTag(Tag),
+	Comment(String),
+	Character(char),
+	// The contents of a CDATA section, which is only recognised in foreign content
+	CData(String),
+	Eof,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+	Data,
+	Rcdata,
+	Rawtext,
+	ScriptData,
+	Plaintext,
+	TagOpen,
+	EndTagOpen,
+	TagName,
+	RcdataLessThanSign,
+	RcdataEndTagOpen,
+	RcdataEndTagName,
+	RawtextLessThanSign,
+	RawtextEndTagOpen,
+	RawtextEndTagName,
+	ScriptDataLessThanSign,
+	ScriptDataEndTagOpen,
+	ScriptDataEndTagName,
+	ScriptDataEscapeStart,
+	ScriptDataEscapeStartDash,
+	ScriptDataEscaped,
+	ScriptDataEscapedDash,
+	ScriptDataEscapedDashDash,
+	ScriptDataEscapedLessThanSign,
+	ScriptDataEscapedEndTagOpen,
+	ScriptDataEscapedEndTagName,
+	ScriptDataDoubleEscapeStart,
+	ScriptDataDoubleEscaped,
+	ScriptDataDoubleEscapedDash,
+	ScriptDataDoubleEscapedDashDash,
+	ScriptDataDoubleEscapedLessThanSign,
+	ScriptDataDoubleEscapeEnd,
+	BeforeAttributeName,
+	AttributeName,
+	AfterAttributeName,
+	BeforeAttributeValue,
+	AttributeValueDoubleQuoted,
+	AttributeValueSingleQuoted,
+	AttributeValueUnquoted,
+	AfterAttributeValueQuoted,
+	SelfClosingStartTag,
+	BogusComment,
+	MarkupDeclarationOpen,
+	CommentStart,
+	CommentStartDash,
+	Comment,
+	CommentLessThanSign,
+	CommentLessThanSignBang,
+	CommentLessThanSignBangDash,
+	CommentLessThanSignBangDashDash,
+	CommentEndDash,
+	CommentEnd,
+	CommentEndBang,
+	Doctype,
+	BeforeDoctypeName,
+	DoctypeName,
+	AfterDoctypeName,
+	AfterDoctypePublicKeyword,
+	BeforeDoctypePublicIdentifier,
+	DoctypePublicIdentifierDoubleQuoted,
+	DoctypePublicIdentifierSingleQuoted,
+	AfterDoctypePublicIdentifier,
+	BetweenDoctypePublicAndSystemIdentifiers,
+	AfterDoctypeSystemKeyword,
+	BeforeDoctypeSystemIdentifier,
+	DoctypeSystemIdentifierDoubleQuoted,
+	DoctypeSystemIdentifierSingleQuoted,
+	AfterDoctypeSystemIdentifier,
+	BogusDoctype,
+	CdataSection,
+	CdataSectionBracket,
+	CdataSectionEnd,
+}
+
+fn is_whitespace(c: char) -> bool {
+	matches!(c, '\t' | '\n' | '\x0C' | ' ')
+}
+
+// Newlines are normalised before tokenizing
+// https://html.spec.whatwg.org/multipage/parsing.html#preprocessing-the-input-stream
+pub fn normalize_newlines(input: &str) -> String {
+	input.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+pub struct Tokenizer {
+	input: Parser,
+	pub state: State,
+
+	// Where the last character was consumed from, so it can be reconsumed
+	last_pos: LinePos,
+
+	tokens: VecDeque<Token>,
+	pub errors: Vec<ParseError>,
+
+	// The tokens being built up
+	tag: Tag,
+	tag_is_end: bool,
+	attribute_is_duplicate: bool,
+	comment: String,
+	doctype: Doctype,
+	cdata: String,
+	temporary_buffer: String,
+
+	// Used to decide whether an end tag closes a raw text element
+	last_start_tag: Option<String>,
+
+	// Whether `<![CDATA[` starts a CDATA section, which is only allowed in foreign content
+	pub allow_cdata: bool,
+}
+
+impl Tokenizer {
+	pub fn new(input: &str) -> Tokenizer {
+		let input = Parser::new(normalize_newlines(input));
+		let last_pos = input.pos();
+		Tokenizer {
+			input,
+			state: State::Data,
+			last_pos,
+			tokens: VecDeque::new(),
+			errors: Vec::new(),
+			tag: Tag::new(""),
+			tag_is_end: false,
+			attribute_is_duplicate: false,
+			comment: String::new(),
+			doctype: Doctype {
+				name: None,
+				public_id: None,
+				system_id: None,
+				force_quirks: false,
+			},
+			cdata: String::new(),
+			temporary_buffer: String::new(),
+			last_start_tag: None,
+			allow_cdata: false,
+		}
+	}
+
+	// Pretend a start tag with this name was the last one seen, for tokenizing fragments
+	pub fn set_last_start_tag(&mut self, name: Option<String>) {
+		self.last_start_tag = name;
+	}
+
+	// The position of the next character to be tokenized
+	pub fn pos(&self) -> LinePos {
+		self.input.pos()
+	}
+
+	// Produce the next token. Once the input is exhausted this keeps returning `Token::Eof`.
+	pub fn next_token(&mut self) -> Token {
+		loop {
+			if let Some(token) = self.tokens.pop_front() {
+				return token;
+			}
+			self.step();
+		}
+	}
+
+	fn error(&mut self, code: &'static str) {
+		let err = self
+			.input
+			.error_at(self.last_pos, ParseErrorKind::Spec(code), None, None);
+		self.errors.push(err);
+	}
+
+	fn consume(&mut self) -> Option<char> {
+		self.last_pos = self.input.pos();
+		if self.input.eof() {
+			None
+		} else {
+			Some(self.input.consume_char())
+		}
+	}
+
+	// Put the last character back, so the next state sees it again
+	fn reconsume_in(&mut self, state: State) {
+		self.input.rewind(self.last_pos);
+		self.state = state;
+	}
+
+	fn emit(&mut self, token: Token) {
+		self.tokens.push_back(token);
+	}
+
+	fn emit_char(&mut self, c: char) {
+		self.emit(Token::Character(c));
+	}
+
+	fn emit_cdata(&mut self) {
+		if !self.cdata.is_empty() {
+			let cdata = mem::take(&mut self.cdata);
+			self.emit(Token::CData(cdata));
+		}
+	}
+
+	fn emit_str(&mut self, s: &str) {
+		for c in s.chars() {
+			self.emit_char(c);
+		}
+	}
+
+	fn emit_eof(&mut self) {
+		self.emit(Token::Eof);
+	}
+
+	fn new_tag(&mut self, is_end: bool) {
+		self.tag = Tag::new("");
+		self.tag_is_end = is_end;
+	}
+
+	fn emit_tag(&mut self) {
+		self.finish_attribute_name();
+		let tag = ::std::mem::replace(&mut self.tag, Tag::new(""));
+		if self.tag_is_end {
+			if !tag.attributes.is_empty() {
+				self.error("end-tag-with-attributes");
+			}
+			if tag.self_closing {
+				self.error("end-tag-with-trailing-solidus");
+			}
+			self.emit(Token::EndTag(tag));
+		} else {
+			self.last_start_tag = Some(tag.name.clone());
+			self.emit(Token::StartTag(tag));
+		}
+	}
+
+	fn new_attribute(&mut self, name: &str) {
+		self.finish_attribute_name();
+		self.tag.attributes.push((name.to_string(), String::new()));
+		self.attribute_is_duplicate = false;
+	}
+
+	// Once an attribute's name is complete, drop it if the tag already has one by that name
+	fn finish_attribute_name(&mut self) {
+		if self.attribute_is_duplicate {
+			return;
+		}
+		if let Some((name, _)) = self.tag.attributes.last() {
+			let count = self
+				.tag
+				.attributes
+				.iter()
+				.filter(|(other, _)| other == name)
+				.count();
+			if count > 1 {
+				self.error("duplicate-attribute");
+				self.attribute_is_duplicate = true;
+			}
+		}
+	}
+
+	fn push_to_attribute_name(&mut self, c: char) {
+		if let Some(attribute) = self.tag.attributes.last_mut() {
+			attribute.0.push(c);
+		}
+	}
+
+	fn push_to_attribute_value(&mut self, s: &str) {
+		if let Some(attribute) = self.tag.attributes.last_mut() {
+			attribute.1.push_str(s);
+		}
+	}
+
+	// Remove a duplicate attribute once its value has been read
+	fn drop_duplicate_attribute(&mut self) {
+		if self.attribute_is_duplicate {
+			self.tag.attributes.pop();
+			self.attribute_is_duplicate = false;
+		}
+	}
+
+	fn new_doctype(&mut self) {
+		self.doctype = Doctype {
+			name: None,
+			public_id: None,
+			system_id: None,
+			force_quirks: false,
+		};
+	}
+
+	fn emit_doctype(&mut self) {
+		let doctype = self.doctype.clone();
+		self.emit(Token::Doctype(doctype));
+	}
+
+	fn emit_comment(&mut self) {
+		let comment = ::std::mem::take(&mut self.comment);
+		self.emit(Token::Comment(comment));
+	}
+
+	fn is_appropriate_end_tag(&self) -> bool {
+		self.last_start_tag.as_ref() == Some(&self.tag.name)
+	}
+
+	// Decode a character reference after an `&`, returning `None` if there isn't one
+	fn consume_character_reference(&mut self, in_attribute: bool) -> Option<String> {
+		let mut decoded = String::new();
+		let consumed = char_refs::decode_reference(self.input.rest(), in_attribute, &mut decoded)?;
+		let missing_semicolon = !self.input.rest()[..consumed].ends_with(';');
+		let end = self.input.pos().pos + consumed;
+		while self.input.pos().pos < end {
+			self.input.consume_char();
+		}
+		if missing_semicolon {
+			self.error("missing-semicolon-after-character-reference");
+		}
+		Some(decoded)
+	}
+
+	// Handle the end tag name states of the RCDATA, RAWTEXT and script data states
+	fn end_tag_name(&mut self, c: Option<char>, text_state: State) {
+		match c {
+			Some(c) if is_whitespace(c) && self.is_appropriate_end_tag() => {
+				self.state = State::BeforeAttributeName
+			}
+			Some('/') if self.is_appropriate_end_tag() => self.state = State::SelfClosingStartTag,
+			Some('>') if self.is_appropriate_end_tag() => {
+				self.state = State::Data;
+				self.emit_tag();
+			}
+			Some(c) if c.is_ascii_alphabetic() => {
+				self.tag.name.push(c.to_ascii_lowercase());
+				self.temporary_buffer.push(c);
+			}
+			_ => {
+				self.emit_str("</");
+				let buffer = self.temporary_buffer.clone();
+				self.emit_str(&buffer);
+				self.reconsume_in(text_state);
+			}
+		}
+	}
+
+	// Run the state machine for a single character
+	fn step(&mut self) {
+		let c = self.consume();
+		match self.state {
+			State::Data => match c {
+				Some('&') => match self.consume_character_reference(false) {
+					Some(decoded) => self.emit_str(&decoded),
+					None => self.emit_char('&'),
+				},
+				Some('<') => self.state = State::TagOpen,
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\0');
+				}
+				Some(c) => self.emit_char(c),
+				None => self.emit_eof(),
+			},
+			State::Rcdata => match c {
+				Some('&') => match self.consume_character_reference(false) {
+					Some(decoded) => self.emit_str(&decoded),
+					None => self.emit_char('&'),
+				},
+				Some('<') => self.state = State::RcdataLessThanSign,
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => self.emit_char(c),
+				None => self.emit_eof(),
+			},
+			State::Rawtext => match c {
+				Some('<') => self.state = State::RawtextLessThanSign,
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => self.emit_char(c),
+				None => self.emit_eof(),
+			},
+			State::ScriptData => match c {
+				Some('<') => self.state = State::ScriptDataLessThanSign,
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => self.emit_char(c),
+				None => self.emit_eof(),
+			},
+			State::Plaintext => match c {
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => self.emit_char(c),
+				None => self.emit_eof(),
+			},
+			State::TagOpen => match c {
+				Some('!') => self.state = State::MarkupDeclarationOpen,
+				Some('/') => self.state = State::EndTagOpen,
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.new_tag(false);
+					self.reconsume_in(State::TagName);
+				}
+				Some('?') => {
+					self.error("unexpected-question-mark-instead-of-tag-name");
+					self.comment.clear();
+					self.reconsume_in(State::BogusComment);
+				}
+				None => {
+					self.error("eof-before-tag-name");
+					self.emit_char('<');
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.error("invalid-first-character-of-tag-name");
+					self.emit_char('<');
+					self.reconsume_in(State::Data);
+				}
+			},
+			State::EndTagOpen => match c {
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.new_tag(true);
+					self.reconsume_in(State::TagName);
+				}
+				Some('>') => {
+					self.error("missing-end-tag-name");
+					self.state = State::Data;
+				}
+				None => {
+					self.error("eof-before-tag-name");
+					self.emit_str("</");
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.error("invalid-first-character-of-tag-name");
+					self.comment.clear();
+					self.reconsume_in(State::BogusComment);
+				}
+			},
+			State::TagName => match c {
+				Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+				Some('/') => self.state = State::SelfClosingStartTag,
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_tag();
+				}
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.tag.name.push('\u{FFFD}');
+				}
+				Some(c) => self.tag.name.push(c.to_ascii_lowercase()),
+				None => {
+					self.error("eof-in-tag");
+					self.emit_eof();
+				}
+			},
+			State::RcdataLessThanSign => match c {
+				Some('/') => {
+					self.temporary_buffer.clear();
+					self.state = State::RcdataEndTagOpen;
+				}
+				_ => {
+					self.emit_char('<');
+					self.reconsume_in(State::Rcdata);
+				}
+			},
+			State::RcdataEndTagOpen => match c {
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.new_tag(true);
+					self.reconsume_in(State::RcdataEndTagName);
+				}
+				_ => {
+					self.emit_str("</");
+					self.reconsume_in(State::Rcdata);
+				}
+			},
+			State::RcdataEndTagName => self.end_tag_name(c, State::Rcdata),
+			State::RawtextLessThanSign => match c {
+				Some('/') => {
+					self.temporary_buffer.clear();
+					self.state = State::RawtextEndTagOpen;
+				}
+				_ => {
+					self.emit_char('<');
+					self.reconsume_in(State::Rawtext);
+				}
+			},
+			State::RawtextEndTagOpen => match c {
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.new_tag(true);
+					self.reconsume_in(State::RawtextEndTagName);
+				}
+				_ => {
+					self.emit_str("</");
+					self.reconsume_in(State::Rawtext);
+				}
+			},
+			State::RawtextEndTagName => self.end_tag_name(c, State::Rawtext),
+			State::ScriptDataLessThanSign => match c {
+				Some('/') => {
+					self.temporary_buffer.clear();
+					self.state = State::ScriptDataEndTagOpen;
+				}
+				Some('!') => {
+					self.state = State::ScriptDataEscapeStart;
+					self.emit_str("<!");
+				}
+				_ => {
+					self.emit_char('<');
+					self.reconsume_in(State::ScriptData);
+				}
+			},
+			State::ScriptDataEndTagOpen => match c {
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.new_tag(true);
+					self.reconsume_in(State::ScriptDataEndTagName);
+				}
+				_ => {
+					self.emit_str("</");
+					self.reconsume_in(State::ScriptData);
+				}
+			},
+			State::ScriptDataEndTagName => self.end_tag_name(c, State::ScriptData),
+			State::ScriptDataEscapeStart => match c {
+				Some('-') => {
+					self.state = State::ScriptDataEscapeStartDash;
+					self.emit_char('-');
+				}
+				_ => self.reconsume_in(State::ScriptData),
+			},
+			State::ScriptDataEscapeStartDash => match c {
+				Some('-') => {
+					self.state = State::ScriptDataEscapedDashDash;
+					self.emit_char('-');
+				}
+				_ => self.reconsume_in(State::ScriptData),
+			},
+			State::ScriptDataEscaped => match c {
+				Some('-') => {
+					self.state = State::ScriptDataEscapedDash;
+					self.emit_char('-');
+				}
+				Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => self.emit_char(c),
+				None => {
+					self.error("eof-in-script-html-comment-like-text");
+					self.emit_eof();
+				}
+			},
+			State::ScriptDataEscapedDash | State::ScriptDataEscapedDashDash => match c {
+				Some('-') => {
+					self.state = State::ScriptDataEscapedDashDash;
+					self.emit_char('-');
+				}
+				Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+				Some('>') if self.state == State::ScriptDataEscapedDashDash => {
+					self.state = State::ScriptData;
+					self.emit_char('>');
+				}
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.state = State::ScriptDataEscaped;
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => {
+					self.state = State::ScriptDataEscaped;
+					self.emit_char(c);
+				}
+				None => {
+					self.error("eof-in-script-html-comment-like-text");
+					self.emit_eof();
+				}
+			},
+			State::ScriptDataEscapedLessThanSign => match c {
+				Some('/') => {
+					self.temporary_buffer.clear();
+					self.state = State::ScriptDataEscapedEndTagOpen;
+				}
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.temporary_buffer.clear();
+					self.emit_char('<');
+					self.reconsume_in(State::ScriptDataDoubleEscapeStart);
+				}
+				_ => {
+					self.emit_char('<');
+					self.reconsume_in(State::ScriptDataEscaped);
+				}
+			},
+			State::ScriptDataEscapedEndTagOpen => match c {
+				Some(c) if c.is_ascii_alphabetic() => {
+					self.new_tag(true);
+					self.reconsume_in(State::ScriptDataEscapedEndTagName);
+				}
+				_ => {
+					self.emit_str("</");
+					self.reconsume_in(State::ScriptDataEscaped);
+				}
+			},
+			State::ScriptDataEscapedEndTagName => self.end_tag_name(c, State::ScriptDataEscaped),
+			State::ScriptDataDoubleEscapeStart | State::ScriptDataDoubleEscapeEnd => {
+				let (matched, otherwise) = if self.state == State::ScriptDataDoubleEscapeStart {
+					(State::ScriptDataDoubleEscaped, State::ScriptDataEscaped)
+				} else {
+					(State::ScriptDataEscaped, State::ScriptDataDoubleEscaped)
+				};
+				match c {
+					Some(c) if is_whitespace(c) || c == '/' || c == '>' => {
+						self.state = if self.temporary_buffer == "script" {
+							matched
+						} else {
+							otherwise
+						};
+						self.emit_char(c);
+					}
+					Some(c) if c.is_ascii_alphabetic() => {
+						self.temporary_buffer.push(c.to_ascii_lowercase());
+						self.emit_char(c);
+					}
+					_ => self.reconsume_in(otherwise),
+				}
+			}
+			State::ScriptDataDoubleEscaped => match c {
+				Some('-') => {
+					self.state = State::ScriptDataDoubleEscapedDash;
+					self.emit_char('-');
+				}
+				Some('<') => {
+					self.state = State::ScriptDataDoubleEscapedLessThanSign;
+					self.emit_char('<');
+				}
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.emit_char('\u{FFFD}');
+				}
+				Some(c) => self.emit_char(c),
+				None => {
+					self.error("eof-in-script-html-comment-like-text");
+					self.emit_eof();
+				}
+			},
+			State::ScriptDataDoubleEscapedDash | State::ScriptDataDoubleEscapedDashDash => {
+				match c {
+					Some('-') => {
+						self.state = State::ScriptDataDoubleEscapedDashDash;
+						self.emit_char('-');
+					}
+					Some('<') => {
+						self.state = State::ScriptDataDoubleEscapedLessThanSign;
+						self.emit_char('<');
+					}
+					Some('>') if self.state == State::ScriptDataDoubleEscapedDashDash => {
+						self.state = State::ScriptData;
+						self.emit_char('>');
+					}
+					Some('\0') => {
+						self.error("unexpected-null-character");
+						self.state = State::ScriptDataDoubleEscaped;
+						self.emit_char('\u{FFFD}');
+					}
+					Some(c) => {
+						self.state = State::ScriptDataDoubleEscaped;
+						self.emit_char(c);
+					}
+					None => {
+						self.error("eof-in-script-html-comment-like-text");
+						self.emit_eof();
+					}
+				}
+			}
+			State::ScriptDataDoubleEscapedLessThanSign => match c {
+				Some('/') => {
+					self.temporary_buffer.clear();
+					self.state = State::ScriptDataDoubleEscapeEnd;
+					self.emit_char('/');
+				}
+				_ => self.reconsume_in(State::ScriptDataDoubleEscaped),
+			},
+			State::BeforeAttributeName => match c {
+				Some(c) if is_whitespace(c) => {}
+				Some('/') | Some('>') | None => self.reconsume_in(State::AfterAttributeName),
+				Some('=') => {
+					self.error("unexpected-equals-sign-before-attribute-name");
+					self.new_attribute("=");
+					self.state = State::AttributeName;
+				}
+				Some(_) => {
+					self.new_attribute("");
+					self.reconsume_in(State::AttributeName);
+				}
+			},
+			State::AttributeName => match c {
+				Some(c) if is_whitespace(c) || c == '/' || c == '>' => {
+					self.finish_attribute_name();
+					self.reconsume_in(State::AfterAttributeName);
+				}
+				None => {
+					self.finish_attribute_name();
+					self.reconsume_in(State::AfterAttributeName);
+				}
+				Some('=') => {
+					self.finish_attribute_name();
+					self.state = State::BeforeAttributeValue;
+				}
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.push_to_attribute_name('\u{FFFD}');
+				}
+				Some(c) => {
+					if c == '"' || c == '\'' || c == '<' {
+						self.error("unexpected-character-in-attribute-name");
+					}
+					self.push_to_attribute_name(c.to_ascii_lowercase());
+				}
+			},
+			State::AfterAttributeName => match c {
+				Some(c) if is_whitespace(c) => {}
+				Some('/') => {
+					self.drop_duplicate_attribute();
+					self.state = State::SelfClosingStartTag;
+				}
+				Some('=') => self.state = State::BeforeAttributeValue,
+				Some('>') => {
+					self.drop_duplicate_attribute();
+					self.state = State::Data;
+					self.emit_tag();
+				}
+				None => {
+					self.error("eof-in-tag");
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.drop_duplicate_attribute();
+					self.new_attribute("");
+					self.reconsume_in(State::AttributeName);
+				}
+			},
+			State::BeforeAttributeValue => match c {
+				Some(c) if is_whitespace(c) => {}
+				Some('"') => self.state = State::AttributeValueDoubleQuoted,
+				Some('\'') => self.state = State::AttributeValueSingleQuoted,
+				Some('>') => {
+					self.error("missing-attribute-value");
+					self.drop_duplicate_attribute();
+					self.state = State::Data;
+					self.emit_tag();
+				}
+				_ => self.reconsume_in(State::AttributeValueUnquoted),
+			},
+			State::AttributeValueDoubleQuoted | State::AttributeValueSingleQuoted => {
+				let quote = if self.state == State::AttributeValueDoubleQuoted {
+					'"'
+				} else {
+					'\''
+				};
+				match c {
+					Some(c) if c == quote => {
+						self.drop_duplicate_attribute();
+						self.state = State::AfterAttributeValueQuoted;
+					}
+					Some('&') => {
+						let decoded = self
+							.consume_character_reference(true)
+							.unwrap_or_else(|| "&".to_string());
+						self.push_to_attribute_value(&decoded);
+					}
+					Some('\0') => {
+						self.error("unexpected-null-character");
+						self.push_to_attribute_value("\u{FFFD}");
+					}
+					Some(c) => self.push_to_attribute_value(c.encode_utf8(&mut [0; 4])),
+					None => {
+						self.error("eof-in-tag");
+						self.emit_eof();
+					}
+				}
+			}
+			State::AttributeValueUnquoted => match c {
+				Some(c) if is_whitespace(c) => {
+					self.drop_duplicate_attribute();
+					self.state = State::BeforeAttributeName;
+				}
+				Some('&') => {
+					let decoded = self
+						.consume_character_reference(true)
+						.unwrap_or_else(|| "&".to_string());
+					self.push_to_attribute_value(&decoded);
+				}
+				Some('>') => {
+					self.drop_duplicate_attribute();
+					self.state = State::Data;
+					self.emit_tag();
+				}
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.push_to_attribute_value("\u{FFFD}");
+				}
+				Some(c) => {
+					if matches!(c, '"' | '\'' | '<' | '=' | '`') {
+						self.error("unexpected-character-in-unquoted-attribute-value");
+					}
+					self.push_to_attribute_value(c.encode_utf8(&mut [0; 4]));
+				}
+				None => {
+					self.error("eof-in-tag");
+					self.emit_eof();
+				}
+			},
+			State::AfterAttributeValueQuoted => match c {
+				Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+				Some('/') => self.state = State::SelfClosingStartTag,
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_tag();
+				}
+				None => {
+					self.error("eof-in-tag");
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.error("missing-whitespace-between-attributes");
+					self.reconsume_in(State::BeforeAttributeName);
+				}
+			},
+			State::SelfClosingStartTag => match c {
+				Some('>') => {
+					self.tag.self_closing = true;
+					self.state = State::Data;
+					self.emit_tag();
+				}
+				None => {
+					self.error("eof-in-tag");
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.error("unexpected-solidus-in-tag");
+					self.reconsume_in(State::BeforeAttributeName);
+				}
+			},
+			State::BogusComment => match c {
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_comment();
+				}
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.comment.push('\u{FFFD}');
+				}
+				Some(c) => self.comment.push(c),
+				None => {
+					self.emit_comment();
+					self.emit_eof();
+				}
+			},
+			State::MarkupDeclarationOpen => {
+				// Look at what follows the `<!` without consuming it
+				self.input.rewind(self.last_pos);
+				if self.input.starts_with("--") {
+					self.input.consume_string("--").unwrap();
+					self.comment.clear();
+					self.state = State::CommentStart;
+				} else if self.input.starts_with_ignore_case("DOCTYPE") {
+					self.input.consume_string_ignore_case("DOCTYPE").unwrap();
+					self.state = State::Doctype;
+				} else if self.input.starts_with("[CDATA[") {
+					self.input.consume_string("[CDATA[").unwrap();
+					if self.allow_cdata {
+						self.state = State::CdataSection;
+					} else {
+						self.error("cdata-in-html-content");
+						self.comment = "[CDATA[".to_string();
+						self.state = State::BogusComment;
+					}
+				} else {
+					self.error("incorrectly-opened-comment");
+					self.comment.clear();
+					self.state = State::BogusComment;
+				}
+			}
+			State::CommentStart => match c {
+				Some('-') => self.state = State::CommentStartDash,
+				Some('>') => {
+					self.error("abrupt-closing-of-empty-comment");
+					self.state = State::Data;
+					self.emit_comment();
+				}
+				_ => self.reconsume_in(State::Comment),
+			},
+			State::CommentStartDash => match c {
+				Some('-') => self.state = State::CommentEnd,
+				Some('>') => {
+					self.error("abrupt-closing-of-empty-comment");
+					self.state = State::Data;
+					self.emit_comment();
+				}
+				None => {
+					self.error("eof-in-comment");
+					self.emit_comment();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.comment.push('-');
+					self.reconsume_in(State::Comment);
+				}
+			},
+			State::Comment => match c {
+				Some('<') => {
+					self.comment.push('<');
+					self.state = State::CommentLessThanSign;
+				}
+				Some('-') => self.state = State::CommentEndDash,
+				Some('\0') => {
+					self.error("unexpected-null-character");
+					self.comment.push('\u{FFFD}');
+				}
+				Some(c) => self.comment.push(c),
+				None => {
+					self.error("eof-in-comment");
+					self.emit_comment();
+					self.emit_eof();
+				}
+			},
+			State::CommentLessThanSign => match c {
+				Some('!') => {
+					self.comment.push('!');
+					self.state = State::CommentLessThanSignBang;
+				}
+				Some('<') => self.comment.push('<'),
+				_ => self.reconsume_in(State::Comment),
+			},
+			State::CommentLessThanSignBang => match c {
+				Some('-') => self.state = State::CommentLessThanSignBangDash,
+				_ => self.reconsume_in(State::Comment),
+			},
+			State::CommentLessThanSignBangDash => match c {
+				Some('-') => self.state = State::CommentLessThanSignBangDashDash,
+				_ => self.reconsume_in(State::CommentEndDash),
+			},
+			State::CommentLessThanSignBangDashDash => match c {
+				Some('>') | None => self.reconsume_in(State::CommentEnd),
+				Some(_) => {
+					self.error("nested-comment");
+					self.reconsume_in(State::CommentEnd);
+				}
+			},
+			State::CommentEndDash => match c {
+				Some('-') => self.state = State::CommentEnd,
+				None => {
+					self.error("eof-in-comment");
+					self.emit_comment();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.comment.push('-');
+					self.reconsume_in(State::Comment);
+				}
+			},
+			State::CommentEnd => match c {
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_comment();
+				}
+				Some('!') => self.state = State::CommentEndBang,
+				Some('-') => self.comment.push('-'),
+				None => {
+					self.error("eof-in-comment");
+					self.emit_comment();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.comment.push_str("--");
+					self.reconsume_in(State::Comment);
+				}
+			},
+			State::CommentEndBang => match c {
+				Some('-') => {
+					self.comment.push_str("--!");
+					self.state = State::CommentEndDash;
+				}
+				Some('>') => {
+					self.error("incorrectly-closed-comment");
+					self.state = State::Data;
+					self.emit_comment();
+				}
+				None => {
+					self.error("eof-in-comment");
+					self.emit_comment();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.comment.push_str("--!");
+					self.reconsume_in(State::Comment);
+				}
+			},
+			State::Doctype => match c {
+				Some(c) if is_whitespace(c) => self.state = State::BeforeDoctypeName,
+				Some('>') => self.reconsume_in(State::BeforeDoctypeName),
+				None => {
+					self.error("eof-in-doctype");
+					self.new_doctype();
+					self.doctype.force_quirks = true;
+					self.emit_doctype();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.error("missing-whitespace-before-doctype-name");
+					self.reconsume_in(State::BeforeDoctypeName);
+				}
+			},
+			State::BeforeDoctypeName => match c {
+				Some(c) if is_whitespace(c) => {}
+				Some('>') => {
+					self.error("missing-doctype-name");
+					self.new_doctype();
+					self.doctype.force_quirks = true;
+					self.state = State::Data;
+					self.emit_doctype();
+				}
+				None => {
+					self.error("eof-in-doctype");
+					self.new_doctype();
+					self.doctype.force_quirks = true;
+					self.emit_doctype();
+					self.emit_eof();
+				}
+				Some(c) => {
+					let c = if c == '\0' {
+						self.error("unexpected-null-character");
+						'\u{FFFD}'
+					} else {
+						c.to_ascii_lowercase()
+					};
+					self.new_doctype();
+					self.doctype.name = Some(c.to_string());
+					self.state = State::DoctypeName;
+				}
+			},
+			State::DoctypeName => match c {
+				Some(c) if is_whitespace(c) => self.state = State::AfterDoctypeName,
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_doctype();
+				}
+				None => {
+					self.error("eof-in-doctype");
+					self.doctype.force_quirks = true;
+					self.emit_doctype();
+					self.emit_eof();
+				}
+				Some(c) => {
+					let c = if c == '\0' {
+						self.error("unexpected-null-character");
+						'\u{FFFD}'
+					} else {
+						c.to_ascii_lowercase()
+					};
+					if let Some(ref mut name) = self.doctype.name {
+						name.push(c);
+					}
+				}
+			},
+			State::AfterDoctypeName => match c {
+				Some(c) if is_whitespace(c) => {}
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_doctype();
+				}
+				None => {
+					self.error("eof-in-doctype");
+					self.doctype.force_quirks = true;
+					self.emit_doctype();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.input.rewind(self.last_pos);
+					if self.input.starts_with_ignore_case("PUBLIC") {
+						self.input.consume_string_ignore_case("PUBLIC").unwrap();
+						self.state = State::AfterDoctypePublicKeyword;
+					} else if self.input.starts_with_ignore_case("SYSTEM") {
+						self.input.consume_string_ignore_case("SYSTEM").unwrap();
+						self.state = State::AfterDoctypeSystemKeyword;
+					} else {
+						self.error("invalid-character-sequence-after-doctype-name");
+						self.doctype.force_quirks = true;
+						self.state = State::BogusDoctype;
+					}
+				}
+			},
+			State::AfterDoctypePublicKeyword | State::AfterDoctypeSystemKeyword => {
+				let public = self.state == State::AfterDoctypePublicKeyword;
+				match c {
+					Some(c) if is_whitespace(c) => {
+						self.state = if public {
+							State::BeforeDoctypePublicIdentifier
+						} else {
+							State::BeforeDoctypeSystemIdentifier
+						}
+					}
+					Some(quote @ '"') | Some(quote @ '\'') => {
+						self.error(if public {
+							"missing-whitespace-after-doctype-public-keyword"
+						} else {
+							"missing-whitespace-after-doctype-system-keyword"
+						});
+						self.start_doctype_identifier(public, quote);
+					}
+					_ => self.missing_doctype_identifier(c, public),
+				}
+			}
+			State::BeforeDoctypePublicIdentifier | State::BeforeDoctypeSystemIdentifier => {
+				let public = self.state == State::BeforeDoctypePublicIdentifier;
+				match c {
+					Some(c) if is_whitespace(c) => {}
+					Some(quote @ '"') | Some(quote @ '\'') => {
+						self.start_doctype_identifier(public, quote)
+					}
+					_ => self.missing_doctype_identifier(c, public),
+				}
+			}
+			State::DoctypePublicIdentifierDoubleQuoted
+			| State::DoctypePublicIdentifierSingleQuoted
+			| State::DoctypeSystemIdentifierDoubleQuoted
+			| State::DoctypeSystemIdentifierSingleQuoted => {
+				let public = matches!(
+					self.state,
+					State::DoctypePublicIdentifierDoubleQuoted
+						| State::DoctypePublicIdentifierSingleQuoted
+				);
+				let quote = if matches!(
+					self.state,
+					State::DoctypePublicIdentifierDoubleQuoted
+						| State::DoctypeSystemIdentifierDoubleQuoted
+				) {
+					'"'
+				} else {
+					'\''
+				};
+				match c {
+					Some(c) if c == quote => {
+						self.state = if public {
+							State::AfterDoctypePublicIdentifier
+						} else {
+							State::AfterDoctypeSystemIdentifier
+						}
+					}
+					Some('>') => {
+						self.error(if public {
+							"abrupt-doctype-public-identifier"
+						} else {
+							"abrupt-doctype-system-identifier"
+						});
+						self.doctype.force_quirks = true;
+						self.state = State::Data;
+						self.emit_doctype();
+					}
+					None => {
+						self.error("eof-in-doctype");
+						self.doctype.force_quirks = true;
+						self.emit_doctype();
+						self.emit_eof();
+					}
+					Some(c) => {
+						let c = if c == '\0' {
+							self.error("unexpected-null-character");
+							'\u{FFFD}'
+						} else {
+							c
+						};
+						let id = if public {
+							&mut self.doctype.public_id
+						} else {
+							&mut self.doctype.system_id
+						};
+						if let Some(ref mut id) = *id {
+							id.push(c);
+						}
+					}
+				}
+			}
+			State::AfterDoctypePublicIdentifier
+			| State::BetweenDoctypePublicAndSystemIdentifiers => {
+				let after = self.state == State::AfterDoctypePublicIdentifier;
+				match c {
+					Some(c) if is_whitespace(c) => {
+						self.state = State::BetweenDoctypePublicAndSystemIdentifiers
+					}
+					Some('>') => {
+						self.state = State::Data;
+						self.emit_doctype();
+					}
+					Some(quote @ '"') | Some(quote @ '\'') => {
+						if after {
+							self.error(
+								"missing-whitespace-between-doctype-public-and-system-identifiers",
+							);
+						}
+						self.start_doctype_identifier(false, quote);
+					}
+					None => {
+						self.error("eof-in-doctype");
+						self.doctype.force_quirks = true;
+						self.emit_doctype();
+						self.emit_eof();
+					}
+					Some(_) => {
+						self.error("missing-quote-before-doctype-system-identifier");
+						self.doctype.force_quirks = true;
+						self.reconsume_in(State::BogusDoctype);
+					}
+				}
+			}
+			State::AfterDoctypeSystemIdentifier => match c {
+				Some(c) if is_whitespace(c) => {}
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_doctype();
+				}
+				None => {
+					self.error("eof-in-doctype");
+					self.doctype.force_quirks = true;
+					self.emit_doctype();
+					self.emit_eof();
+				}
+				Some(_) => {
+					self.error("unexpected-character-after-doctype-system-identifier");
+					self.reconsume_in(State::BogusDoctype);
+				}
+			},
+			State::BogusDoctype => match c {
+				Some('>') => {
+					self.state = State::Data;
+					self.emit_doctype();
+				}
+				Some('\0') => self.error("unexpected-null-character"),
+				Some(_) => {}
+				None => {
+					self.emit_doctype();
+					self.emit_eof();
+				}
+			},
+			State::CdataSection => match c {
+				Some(']') => self.state = State::CdataSectionBracket,
+				Some(c) => self.cdata.push(c),
+				None => {
+					self.error("eof-in-cdata");
+					self.emit_cdata();
+					self.emit_eof();
+				}
+			},
+			State::CdataSectionBracket => match c {
+				Some(']') => self.state = State::CdataSectionEnd,
+				_ => {
+					self.cdata.push(']');
+					self.reconsume_in(State::CdataSection);
+				}
+			},
+			State::CdataSectionEnd => match c {
+				Some(']') => self.cdata.push(']'),
+				Some('>') => {
+					self.emit_cdata();
+					self.state = State::Data;
+				}
+				_ => {
+					self.cdata.push_str("]]");
+					self.reconsume_in(State::CdataSection);
+				}
+			},
+		}
+	}
+
+	// Begin a quoted public or system identifier
+	fn start_doctype_identifier(&mut self, public: bool, quote: char) {
+		let double = quote == '"';
+		if public {
+			self.doctype.public_id = Some(String::new());
+			self.state = if double {
+				State::DoctypePublicIdentifierDoubleQuoted
+			} else {
+				State::DoctypePublicIdentifierSingleQuoted
+			};
+		} else {
+			self.doctype.system_id = Some(String::new());
+			self.state = if double {
+				State::DoctypeSystemIdentifierDoubleQuoted
+			} else {
+				State::DoctypeSystemIdentifierSingleQuoted
+			};
+		}
+	}
+
+	// Handle a doctype keyword which isn't followed by a quoted identifier
+	fn missing_doctype_identifier(&mut self, c: Option<char>, public: bool) {
+		match c {
+			Some('>') => {
+				self.error(if public {
+					"missing-doctype-public-identifier"
+				} else {
+					"missing-doctype-system-identifier"
+				});
+				self.doctype.force_quirks = true;
+				self.state = State::Data;
+				self.emit_doctype();
+			}
+			None => {
+				self.error("eof-in-doctype");
+				self.doctype.force_quirks = true;
+				self.emit_doctype();
+				self.emit_eof();
+			}
+			Some(_) => {
+				self.error(if public {
+					"missing-quote-before-doctype-public-identifier"
+				} else {
+					"missing-quote-before-doctype-system-identifier"
+				});
+				self.doctype.force_quirks = true;
+				self.reconsume_in(State::BogusDoctype);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tokenizer_tests {
+	use super::*;
+
+	fn tokenize(input: &str) -> Vec<Token> {
+		let mut tokenizer = Tokenizer::new(input);
+		let mut tokens = Vec::new();
+		loop {
+			let token = tokenizer.next_token();
+			if token == Token::Eof {
+				return tokens;
+			}
+			tokens.push(token);
+		}
+	}
+
+	#[test]
+	fn tokenizes_tags_and_attributes() {
+		let tokens = tokenize("<A HREF=x b='&amp;' c=\"1\" b=2/><br/></p>");
+		assert_eq!(
+			tokens,
+			vec![
+				Token::StartTag(Tag {
+					name: "a".into(),
+					attributes: vec![
+						("href".into(), "x".into()),
+						("b".into(), "&".into()),
+						("c".into(), "1".into()),
+					],
+					self_closing: false,
+				}),
+				Token::StartTag(Tag {
+					name: "br".into(),
+					attributes: vec![],
+					self_closing: true,
+				}),
+				Token::EndTag(Tag::new("p")),
+			]
+		);
+	}
+
+	#[test]
+	fn tokenizes_comments_and_doctypes() {
+		let tokens = tokenize("<!DOCTYPE html SYSTEM 'about:legacy-compat'><!-- a -- b --><!x>");
+		assert_eq!(
+			tokens,
+			vec![
+				Token::Doctype(Doctype {
+					name: Some("html".into()),
+					public_id: None,
+					system_id: Some("about:legacy-compat".into()),
+					force_quirks: false,
+				}),
+				Token::Comment(" a -- b ".into()),
+				Token::Comment("x".into()),
+			]
+		);
+	}
+
+	#[test]
+	fn tokenizes_raw_text_until_the_appropriate_end_tag() {
+		let mut tokenizer = Tokenizer::new("a</b></script >");
+		tokenizer.state = State::ScriptData;
+		tokenizer.set_last_start_tag(Some("script".into()));
+		let mut text = String::new();
+		loop {
+			match tokenizer.next_token() {
+				Token::Character(c) => text.push(c),
+				token => {
+					assert_eq!(token, Token::EndTag(Tag::new("script")));
+					break;
+				}
+			}
+		}
+		assert_eq!(text, "a</b>");
+	}
+
+	#[test]
+	fn reports_errors() {
+		let mut tokenizer = Tokenizer::new("<div a a>\r\n<");
+		while tokenizer.next_token() != Token::Eof {}
+		let codes: Vec<_> = tokenizer
+			.errors
+			.iter()
+			.map(|err| err.kind.clone())
+			.collect();
+		assert_eq!(
+			codes,
+			vec![
+				ParseErrorKind::Spec("duplicate-attribute"),
+				ParseErrorKind::Spec("eof-before-tag-name"),
+			]
+		);
+		assert_eq!(tokenizer.errors[1].line, 2);
+	}
+}
@@ -0,0 +1,2656 @@
+use dom::{self, NodeId};
+use html::foreign::{self, MATHML_TEXT_INTEGRATION_POINTS, SVG_HTML_INTEGRATION_POINTS};
+use html::quirks;
+use html::tokenizer::{self, Tag, Token, Tokenizer};
+use parser::{ParseError, ParseErrorKind};
+use std::collections::HashMap;
+use std::mem;
+
+// Building a tree from the tokenizer's output the way browsers do
+// https://html.spec.whatwg.org/multipage/parsing.html#tree-construction
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InsertionMode {
+	Initial,
+	BeforeHtml,
+	BeforeHead,
+	InHead,
+	InHeadNoscript,
+	AfterHead,
+	InBody,
+	Text,
+	InTable,
+	InTableText,
+	InCaption,
+	InColumnGroup,
+	InTableBody,
+	InRow,
+	InCell,
+	InSelect,
+	InSelectInTable,
+	InTemplate,
+	AfterBody,
+	InFrameset,
+	AfterFrameset,
+	AfterAfterBody,
+	AfterAfterFrameset,
+}
+
+// Elements with special parsing rules
+// https://html.spec.whatwg.org/multipage/parsing.html#special
+const SPECIAL_ELEMENTS: &[&str] = &[
+	"address",
+	"applet",
+	"area",
+	"article",
+	"aside",
+	"base",
+	"basefont",
+	"bgsound",
+	"blockquote",
+	"body",
+	"br",
+	"button",
+	"caption",
+	"center",
+	"col",
+	"colgroup",
+	"dd",
+	"details",
+	"dir",
+	"div",
+	"dl",
+	"dt",
+	"embed",
+	"fieldset",
+	"figcaption",
+	"figure",
+	"footer",
+	"form",
+	"frame",
+	"frameset",
+	"h1",
+	"h2",
+	"h3",
+	"h4",
+	"h5",
+	"h6",
+	"head",
+	"header",
+	"hgroup",
+	"hr",
+	"html",
+	"iframe",
+	"img",
+	"input",
+	"keygen",
+	"li",
+	"link",
+	"listing",
+	"main",
+	"marquee",
+	"menu",
+	"meta",
+	"nav",
+	"noembed",
+	"noframes",
+	"noscript",
+	"object",
+	"ol",
+	"p",
+	"param",
+	"plaintext",
+	"pre",
+	"script",
+	"search",
+	"section",
+	"select",
+	"source",
+	"style",
+	"summary",
+	"table",
+	"tbody",
+	"td",
+	"template",
+	"textarea",
+	"tfoot",
+	"th",
+	"thead",
+	"title",
+	"tr",
+	"track",
+	"ul",
+	"wbr",
+	"xmp",
+];
+
+const FORMATTING_ELEMENTS: &[&str] = &[
+	"a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+];
+
+const HEADINGS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+// Elements which are closed by the end of whatever contains them
+const IMPLIED_END_TAGS: &[&str] = &[
+	"dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+const THOROUGHLY_IMPLIED_END_TAGS: &[&str] = &[
+	"caption", "colgroup", "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+	"tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+// Elements which bound the "has an element in scope" checks
+const SCOPE_BOUNDARIES: &[&str] = &[
+	"applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+];
+
+const TABLE_SCOPE_BOUNDARIES: &[&str] = &["html", "table", "template"];
+
+// HTML start tags which break out of SVG and MathML
+const BREAKOUT_ELEMENTS: &[&str] = &[
+	"b",
+	"big",
+	"blockquote",
+	"body",
+	"br",
+	"center",
+	"code",
+	"dd",
+	"div",
+	"dl",
+	"dt",
+	"em",
+	"embed",
+	"h1",
+	"h2",
+	"h3",
+	"h4",
+	"h5",
+	"h6",
+	"head",
+	"hr",
+	"i",
+	"img",
+	"li",
+	"listing",
+	"menu",
+	"meta",
+	"nobr",
+	"ol",
+	"p",
+	"pre",
+	"ruby",
+	"s",
+	"small",
+	"span",
+	"strike",
+	"strong",
+	"sub",
+	"sup",
+	"table",
+	"tt",
+	"u",
+	"ul",
+	"var",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+	Default,
+	ListItem,
+	Button,
+	Table,
+	Select,
+}
+
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+	names.contains(&name)
+}
+
+fn is_whitespace(c: char) -> bool {
+	matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
+// Namespaces are always one of the `dom` constants
+type Namespace = &'static str;
+
+// An entry in the list of active formatting elements
+#[derive(Clone)]
+enum Formatting {
+	Marker,
+	// The element, and the token it was created from so that it can be recreated
+	Element(NodeId, Tag),
+}
+
+pub struct TreeBuilder {
+	tokenizer: Tokenizer,
+
+	// The document being built. Nodes are put straight into it as they're created
+	document: dom::Document,
+
+	mode: InsertionMode,
+	original_mode: InsertionMode,
+	template_modes: Vec<InsertionMode>,
+
+	open_elements: Vec<NodeId>,
+	active_formatting: Vec<Formatting>,
+	head: Option<NodeId>,
+	form: Option<NodeId>,
+
+	frameset_ok: bool,
+	foster_parenting: bool,
+	scripting: bool,
+	ignore_next_line_feed: bool,
+	pending_table_text: String,
+
+	// The element a fragment is being parsed inside of, which isn't part of the tree
+	context: Option<NodeId>,
+
+	pub errors: Vec<ParseError>,
+}
+
+impl TreeBuilder {
+	pub fn new(input: &str) -> TreeBuilder {
+		TreeBuilder {
+			tokenizer: Tokenizer::new(input),
+			document: dom::Document::new(Vec::new(), dom::QuirksMode::NoQuirks),
+			mode: InsertionMode::Initial,
+			original_mode: InsertionMode::Initial,
+			template_modes: Vec::new(),
+			open_elements: Vec::new(),
+			active_formatting: Vec::new(),
+			head: None,
+			form: None,
+			frameset_ok: true,
+			foster_parenting: false,
+			scripting: false,
+			ignore_next_line_feed: false,
+			pending_table_text: String::new(),
+			context: None,
+			errors: Vec::new(),
+		}
+	}
+
+	// Set up to parse a fragment of HTML as if it were the contents of a `context` element in
+	// `namespace`
+	// https://html.spec.whatwg.org/multipage/parsing.html#parsing-html-fragments
+	pub fn new_fragment(input: &str, namespace: &str, context: &str) -> TreeBuilder {
+		let mut builder = TreeBuilder::new(input);
+		let (namespace, context) = match namespace {
+			dom::SVG_NAMESPACE => {
+				let mut tag = Tag::new(context);
+				foreign::adjust_svg_tag_name(&mut tag);
+				(dom::SVG_NAMESPACE, tag.name)
+			}
+			dom::MATHML_NAMESPACE => (dom::MATHML_NAMESPACE, context.to_string()),
+			_ => (dom::HTML_NAMESPACE, context.to_ascii_lowercase()),
+		};
+		let html_context = if namespace == dom::HTML_NAMESPACE {
+			context.as_str()
+		} else {
+			""
+		};
+		builder.tokenizer.state = match html_context {
+			"title" | "textarea" => tokenizer::State::Rcdata,
+			"style" | "xmp" | "iframe" | "noembed" | "noframes" => tokenizer::State::Rawtext,
+			"noscript" if builder.scripting => tokenizer::State::Rawtext,
+			"script" => tokenizer::State::ScriptData,
+			"plaintext" => tokenizer::State::Plaintext,
+			_ => tokenizer::State::Data,
+		};
+		if html_context == "template" {
+			builder.template_modes.push(InsertionMode::InTemplate);
+		}
+		builder.context = Some(builder.create_element(Tag::new(&context), namespace));
+
+		let html = builder.create_element(Tag::new("html"), dom::HTML_NAMESPACE);
+		let document_node = builder.document.document_node();
+		builder.append(html, document_node);
+		builder.open_elements.push(html);
+		builder.reset_insertion_mode();
+		builder
+	}
+
+	// Parse the whole input, returning the finished document
+	pub fn build(mut self) -> (dom::Document, Vec<ParseError>) {
+		self.run();
+		let errors = self.take_errors();
+		(self.document, errors)
+	}
+
+	// Parse the whole input as a fragment. The nodes it contains are moved into a document fragment
+	// in the document they were parsed in, and the fragment is returned along with the document.
+	pub fn build_fragment(mut self) -> (dom::Document, NodeId, Vec<ParseError>) {
+		self.run();
+		let html = self.open_elements[0];
+		let fragment = self.document.create_node(dom::fragment(Vec::new()));
+		while let Some(child) = self.document.first_child(html) {
+			self.append(child, fragment);
+		}
+		self.document.detach(html);
+		let errors = self.take_errors();
+		(self.document, fragment, errors)
+	}
+
+	// The errors from the tokenizer and the tree builder, in the order they were found
+	fn take_errors(&mut self) -> Vec<ParseError> {
+		let mut errors = mem::take(&mut self.tokenizer.errors);
+		errors.append(&mut self.errors);
+		errors.sort_by_key(|err| err.pos);
+		errors
+	}
+
+	fn run(&mut self) {
+		loop {
+			// CDATA sections are only allowed in SVG and MathML
+			self.tokenizer.allow_cdata = self
+				.adjusted_current_node()
+				.is_some_and(|node| self.namespace(node) != Some(dom::HTML_NAMESPACE));
+			let token = self.tokenizer.next_token();
+			let eof = token == Token::Eof;
+			self.process(token);
+			if eof {
+				break;
+			}
+		}
+	}
+
+	fn error(&mut self, code: &'static str) {
+		let pos = self.tokenizer.pos();
+		self.errors.push(ParseError {
+			kind: ParseErrorKind::Spec(code),
+			pos: pos.pos,
+			line: pos.line,
+			column: pos.column,
+			expected: None,
+			found: None,
+		});
+	}
+
+	// Tree inspection
+
+	// The tag name of an HTML element, or an empty string for other nodes. Most of the parsing
+	// rules only apply to HTML elements, so SVG and MathML elements don't have names here
+	fn name(&self, node: NodeId) -> &str {
+		match self.document.element(node) {
+			Some(data) if data.is_html() => &data.tag_name,
+			_ => "",
+		}
+	}
+
+	fn namespace(&self, node: NodeId) -> Option<Namespace> {
+		let namespace = self.document.element(node)?.namespace.as_deref()?;
+		[
+			dom::HTML_NAMESPACE,
+			dom::MATHML_NAMESPACE,
+			dom::SVG_NAMESPACE,
+		]
+		.iter()
+		.find(|&&known| known == namespace)
+		.cloned()
+	}
+
+	// Is `node` an element in `namespace` named one of `names`?
+	fn is_named(&self, node: NodeId, namespace: Namespace, names: &[&str]) -> bool {
+		match self.document.element(node) {
+			Some(data) => data.is_in(namespace) && is_one_of(&data.tag_name, names),
+			None => false,
+		}
+	}
+
+	// The SVG and MathML elements which are special and bound scopes
+	fn is_foreign_boundary(&self, node: NodeId) -> bool {
+		self.is_named(node, dom::MATHML_NAMESPACE, MATHML_TEXT_INTEGRATION_POINTS)
+			|| self.is_named(node, dom::MATHML_NAMESPACE, &["annotation-xml"])
+			|| self.is_named(node, dom::SVG_NAMESPACE, SVG_HTML_INTEGRATION_POINTS)
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#special
+	fn is_special(&self, node: NodeId) -> bool {
+		is_one_of(self.name(node), SPECIAL_ELEMENTS) || self.is_foreign_boundary(node)
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#mathml-text-integration-point
+	fn is_mathml_text_integration_point(&self, node: NodeId) -> bool {
+		self.is_named(node, dom::MATHML_NAMESPACE, MATHML_TEXT_INTEGRATION_POINTS)
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#html-integration-point
+	fn is_html_integration_point(&self, node: NodeId) -> bool {
+		match self.document.element(node) {
+			Some(data)
+				if data.is_in(dom::MATHML_NAMESPACE) && data.tag_name == "annotation-xml" =>
+			{
+				data.attributes.get("encoding").is_some_and(|encoding| {
+					encoding.eq_ignore_ascii_case("text/html")
+						|| encoding.eq_ignore_ascii_case("application/xhtml+xml")
+				})
+			}
+			_ => self.is_named(node, dom::SVG_NAMESPACE, SVG_HTML_INTEGRATION_POINTS),
+		}
+	}
+
+	// The current node, except that the context element stands in for the root of a fragment
+	// https://html.spec.whatwg.org/multipage/parsing.html#adjusted-current-node
+	fn adjusted_current_node(&self) -> Option<NodeId> {
+		match self.context {
+			Some(context) if self.open_elements.len() == 1 => Some(context),
+			_ => self.open_elements.last().cloned(),
+		}
+	}
+
+	fn current_node(&self) -> NodeId {
+		*self
+			.open_elements
+			.last()
+			.expect("the stack of open elements is empty")
+	}
+
+	fn current_name(&self) -> &str {
+		match self.open_elements.last() {
+			Some(&node) => self.name(node),
+			None => "",
+		}
+	}
+
+	fn is_open(&self, node: NodeId) -> bool {
+		self.open_elements.contains(&node)
+	}
+
+	fn has_open(&self, name: &str) -> bool {
+		self.open_elements
+			.iter()
+			.any(|&node| self.name(node) == name)
+	}
+
+	fn is_boundary(&self, node: NodeId, scope: Scope) -> bool {
+		let name = self.name(node);
+		let default = is_one_of(name, SCOPE_BOUNDARIES) || self.is_foreign_boundary(node);
+		match scope {
+			Scope::Default => default,
+			Scope::ListItem => default || name == "ol" || name == "ul",
+			Scope::Button => default || name == "button",
+			Scope::Table => is_one_of(name, TABLE_SCOPE_BOUNDARIES),
+			Scope::Select => name != "optgroup" && name != "option",
+		}
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#has-an-element-in-scope
+	fn in_scope_where<F>(&self, scope: Scope, target: F) -> bool
+	where
+		F: Fn(NodeId) -> bool,
+	{
+		for &node in self.open_elements.iter().rev() {
+			if target(node) {
+				return true;
+			}
+			if self.is_boundary(node, scope) {
+				return false;
+			}
+		}
+		false
+	}
+
+	fn in_scope(&self, name: &str, scope: Scope) -> bool {
+		self.in_scope_where(scope, |node| self.name(node) == name)
+	}
+
+	fn any_in_scope(&self, names: &[&str], scope: Scope) -> bool {
+		self.in_scope_where(scope, |node| is_one_of(self.name(node), names))
+	}
+
+	// Tree mutation
+
+	// Create an element for a tag, which isn't in the tree yet. A template's contents go in a
+	// separate fragment, so they aren't rendered.
+	fn create_element(&mut self, tag: Tag, namespace: Namespace) -> NodeId {
+		let mut attributes = HashMap::new();
+		let mut attribute_namespaces = HashMap::new();
+		for (name, value) in tag.attributes {
+			if namespace != dom::HTML_NAMESPACE {
+				if let Some(attribute_namespace) = foreign::attribute_namespace(&name) {
+					attribute_namespaces.insert(name.clone(), attribute_namespace.to_string());
+				}
+			}
+			attributes.entry(name).or_insert(value);
+		}
+		let mut element = if namespace == dom::HTML_NAMESPACE && tag.name == "template" {
+			dom::template(attributes, Vec::new())
+		} else {
+			dom::elem_ns(Some(namespace.into()), tag.name, attributes, Vec::new())
+		};
+		if let dom::NodeType::Element(ref mut data) = element.node_type {
+			data.attribute_namespaces = attribute_namespaces;
+		}
+		self.document.create_node(element)
+	}
+
+	// Insert `node` into `parent`, before `before` if it's given, otherwise at the end. The node is
+	// moved if it's already in the tree.
+	fn insert_at(&mut self, node: NodeId, parent: NodeId, before: Option<NodeId>) {
+		self.document
+			.insert_before(parent, node, before)
+			.expect("the tree builder only makes valid trees");
+	}
+
+	fn append(&mut self, node: NodeId, parent: NodeId) {
+		self.insert_at(node, parent, None);
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#appropriate-place-for-inserting-a-node
+	fn appropriate_place(&self, override_target: Option<NodeId>) -> (NodeId, Option<NodeId>) {
+		let target = override_target.unwrap_or_else(|| self.current_node());
+		let (parent, before) = if self.foster_parenting
+			&& is_one_of(
+				self.name(target),
+				&["table", "tbody", "tfoot", "thead", "tr"],
+			) {
+			let last_template = self
+				.open_elements
+				.iter()
+				.rposition(|&node| self.name(node) == "template");
+			let last_table = self
+				.open_elements
+				.iter()
+				.rposition(|&node| self.name(node) == "table");
+			match (last_template, last_table) {
+				(Some(template), table) if table.is_none_or(|table| template > table) => {
+					(self.open_elements[template], None)
+				}
+				(_, None) => (self.open_elements[0], None),
+				(_, Some(table)) => {
+					let table_node = self.open_elements[table];
+					match self.document.parent(table_node) {
+						Some(parent) => (parent, Some(table_node)),
+						None => (self.open_elements[table - 1], None),
+					}
+				}
+			}
+		} else {
+			(target, None)
+		};
+
+		// Nodes inserted into a template go in its contents
+		match self.document.template_contents(parent) {
+			Some(contents) => (contents, None),
+			None => (parent, before),
+		}
+	}
+
+	fn insert_comment(&mut self, comment: String, parent: Option<NodeId>) {
+		let (parent, before) = match parent {
+			Some(parent) => (parent, None),
+			None => self.appropriate_place(None),
+		};
+		let node = self.document.create_node(dom::comment(comment));
+		self.insert_at(node, parent, before);
+	}
+
+	// Insert a comment at the top of the document, outside the root element
+	fn insert_document_comment(&mut self, comment: String) {
+		let document_node = self.document.document_node();
+		self.insert_comment(comment, Some(document_node));
+	}
+
+	fn insert_character(&mut self, c: char) {
+		let (parent, before) = self.appropriate_place(None);
+		if let dom::NodeType::Document = self.document.node(parent).node_type {
+			return;
+		}
+
+		// Merge with an adjacent text node
+		let previous = match before {
+			Some(before) => self.document.previous_sibling(before),
+			None => self.document.last_child(parent),
+		};
+		if let Some(previous) = previous {
+			if let dom::NodeType::Text(_) = self.document.node(previous).node_type {
+				self.document
+					.append_data(previous, c.encode_utf8(&mut [0; 4]));
+				return;
+			}
+		}
+		let node = self.document.create_node(dom::text(c.to_string()));
+		self.insert_at(node, parent, before);
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#insert-a-foreign-element
+	fn insert_foreign_element(&mut self, tag: Tag, namespace: Namespace) -> NodeId {
+		let (parent, before) = self.appropriate_place(None);
+		let node = self.create_element(tag, namespace);
+		self.insert_at(node, parent, before);
+		self.open_elements.push(node);
+		node
+	}
+
+	fn insert_element(&mut self, tag: Tag) -> NodeId {
+		self.insert_foreign_element(tag, dom::HTML_NAMESPACE)
+	}
+
+	// Insert an element which can never have children
+	fn insert_void_element(&mut self, tag: Tag) {
+		self.insert_element(tag);
+		self.open_elements.pop();
+	}
+
+	fn insert_element_named(&mut self, name: &str) -> NodeId {
+		self.insert_element(Tag::new(name))
+	}
+
+	// Add any attributes the element doesn't already have
+	fn merge_attributes(&mut self, node: NodeId, tag: Tag) {
+		for (name, value) in tag.attributes {
+			let missing = self
+				.document
+				.element(node)
+				.is_some_and(|data| !data.attributes.contains_key(&name));
+			if missing {
+				self.document
+					.set_attribute(node, &name, &value)
+					.expect("attributes are only merged into elements");
+			}
+		}
+	}
+
+	// The stack of open elements
+
+	fn pop_until<F>(&mut self, test: F)
+	where
+		F: Fn(&str) -> bool,
+	{
+		while let Some(node) = self.open_elements.pop() {
+			if test(self.name(node)) {
+				break;
+			}
+		}
+	}
+
+	fn pop_until_named(&mut self, name: &str) {
+		self.pop_until(|node| node == name);
+	}
+
+	fn remove_from_stack(&mut self, node: NodeId) {
+		self.open_elements.retain(|&open| open != node);
+	}
+
+	fn generate_implied_end_tags_except(&mut self, except: &str) {
+		while is_one_of(self.current_name(), IMPLIED_END_TAGS) && self.current_name() != except {
+			self.open_elements.pop();
+		}
+	}
+
+	fn generate_implied_end_tags(&mut self) {
+		self.generate_implied_end_tags_except("");
+	}
+
+	fn generate_all_implied_end_tags_thoroughly(&mut self) {
+		while is_one_of(self.current_name(), THOROUGHLY_IMPLIED_END_TAGS) {
+			self.open_elements.pop();
+		}
+	}
+
+	fn close_p_element(&mut self) {
+		self.generate_implied_end_tags_except("p");
+		if self.current_name() != "p" {
+			self.error("unexpected-end-tag");
+		}
+		self.pop_until_named("p");
+	}
+
+	fn close_p_element_in_button_scope(&mut self) {
+		if self.in_scope("p", Scope::Button) {
+			self.close_p_element();
+		}
+	}
+
+	// Close the element named `name`, which is known to be in scope
+	fn close_element(&mut self, name: &str) {
+		self.generate_implied_end_tags();
+		if self.current_name() != name {
+			self.error("unexpected-end-tag");
+		}
+		self.pop_until_named(name);
+	}
+
+	fn clear_stack_back_to(&mut self, names: &[&str]) {
+		while !is_one_of(self.current_name(), names) {
+			self.open_elements.pop();
+		}
+	}
+
+	fn clear_stack_back_to_table_context(&mut self) {
+		self.clear_stack_back_to(&["table", "template", "html"]);
+	}
+
+	fn clear_stack_back_to_table_body_context(&mut self) {
+		self.clear_stack_back_to(&["tbody", "tfoot", "thead", "template", "html"]);
+	}
+
+	fn clear_stack_back_to_table_row_context(&mut self) {
+		self.clear_stack_back_to(&["tr", "template", "html"]);
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#reset-the-insertion-mode-appropriately
+	fn reset_insertion_mode(&mut self) {
+		for (i, &node) in self.open_elements.iter().enumerate().rev() {
+			let last = i == 0;
+
+			// When parsing a fragment, the context element stands in for the root
+			let node = match self.context {
+				Some(context) if last => context,
+				_ => node,
+			};
+			let mode = match self.name(node) {
+				"select" if !last => {
+					let in_table = self.open_elements[..i]
+						.iter()
+						.rev()
+						.map(|&ancestor| self.name(ancestor))
+						.take_while(|&ancestor| ancestor != "template")
+						.any(|ancestor| ancestor == "table");
+					if in_table {
+						InsertionMode::InSelectInTable
+					} else {
+						InsertionMode::InSelect
+					}
+				}
+				"select" => InsertionMode::InSelect,
+				"td" | "th" if !last => InsertionMode::InCell,
+				"tr" => InsertionMode::InRow,
+				"tbody" | "thead" | "tfoot" => InsertionMode::InTableBody,
+				"caption" => InsertionMode::InCaption,
+				"colgroup" => InsertionMode::InColumnGroup,
+				"table" => InsertionMode::InTable,
+				"template" => *self
+					.template_modes
+					.last()
+					.expect("template elements have a template insertion mode"),
+				"head" if !last => InsertionMode::InHead,
+				"body" => InsertionMode::InBody,
+				"frameset" => InsertionMode::InFrameset,
+				"html" if self.head.is_none() => InsertionMode::BeforeHead,
+				"html" => InsertionMode::AfterHead,
+				_ if last => InsertionMode::InBody,
+				_ => continue,
+			};
+			self.mode = mode;
+			return;
+		}
+		self.mode = InsertionMode::InBody;
+	}
+
+	// The list of active formatting elements
+
+	fn formatting_index(&self, node: NodeId) -> Option<usize> {
+		self.active_formatting
+			.iter()
+			.position(|entry| match *entry {
+				Formatting::Element(element, _) => element == node,
+				Formatting::Marker => false,
+			})
+	}
+
+	// The last element named `name` after the last marker
+	fn formatting_element_named(&self, name: &str) -> Option<NodeId> {
+		for entry in self.active_formatting.iter().rev() {
+			match *entry {
+				Formatting::Marker => return None,
+				Formatting::Element(node, _) if self.name(node) == name => return Some(node),
+				_ => {}
+			}
+		}
+		None
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#push-onto-the-list-of-active-formatting-elements
+	fn push_formatting_element(&mut self, node: NodeId, tag: Tag) {
+		// Only three identical elements are kept after the last marker
+		let same = |other: &Tag| {
+			other.name == tag.name
+				&& other.attributes.len() == tag.attributes.len()
+				&& other
+					.attributes
+					.iter()
+					.all(|(name, value)| tag.attribute(name) == Some(value.as_str()))
+		};
+		let mut matches = Vec::new();
+		for (i, entry) in self.active_formatting.iter().enumerate().rev() {
+			match *entry {
+				Formatting::Marker => break,
+				Formatting::Element(_, ref other) if same(other) => matches.push(i),
+				_ => {}
+			}
+		}
+		if matches.len() >= 3 {
+			self.active_formatting.remove(*matches.last().unwrap());
+		}
+		self.active_formatting.push(Formatting::Element(node, tag));
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#reconstruct-the-active-formatting-elements
+	fn reconstruct_active_formatting_elements(&mut self) {
+		let needs_reconstructing = |builder: &TreeBuilder, entry: &Formatting| match *entry {
+			Formatting::Marker => false,
+			Formatting::Element(node, _) => !builder.is_open(node),
+		};
+		match self.active_formatting.last() {
+			Some(entry) if needs_reconstructing(self, entry) => {}
+			_ => return,
+		}
+		let mut first = self.active_formatting.len() - 1;
+		while first > 0 && needs_reconstructing(self, &self.active_formatting[first - 1]) {
+			first -= 1;
+		}
+		for i in first..self.active_formatting.len() {
+			let tag = match self.active_formatting[i] {
+				Formatting::Element(_, ref tag) => tag.clone(),
+				Formatting::Marker => unreachable!(),
+			};
+			let node = self.insert_element(tag.clone());
+			self.active_formatting[i] = Formatting::Element(node, tag);
+		}
+	}
+
+	fn clear_active_formatting_to_last_marker(&mut self) {
+		while let Some(entry) = self.active_formatting.pop() {
+			if let Formatting::Marker = entry {
+				break;
+			}
+		}
+	}
+
+	fn remove_formatting_element(&mut self, node: NodeId) {
+		if let Some(i) = self.formatting_index(node) {
+			self.active_formatting.remove(i);
+		}
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#adoption-agency-algorithm
+	fn adoption_agency(&mut self, subject: &str) {
+		let current = self.current_node();
+		if self.name(current) == subject && self.formatting_index(current).is_none() {
+			self.open_elements.pop();
+			return;
+		}
+
+		for _ in 0..8 {
+			let formatting_element = match self.formatting_element_named(subject) {
+				Some(node) => node,
+				None => return self.any_other_end_tag(subject),
+			};
+			let formatting_stack_index = match self
+				.open_elements
+				.iter()
+				.position(|&node| node == formatting_element)
+			{
+				Some(index) => index,
+				None => {
+					self.error("adoption-agency-1.3");
+					self.remove_formatting_element(formatting_element);
+					return;
+				}
+			};
+			if !self.in_scope_where(Scope::Default, |node| node == formatting_element) {
+				self.error("adoption-agency-4.4");
+				return;
+			}
+			if formatting_element != self.current_node() {
+				self.error("adoption-agency-1.3");
+			}
+
+			let furthest_block = self.open_elements[formatting_stack_index + 1..]
+				.iter()
+				.position(|&node| self.is_special(node))
+				.map(|i| i + formatting_stack_index + 1);
+			let furthest_block_index = match furthest_block {
+				Some(index) => index,
+				None => {
+					self.open_elements.truncate(formatting_stack_index);
+					self.remove_formatting_element(formatting_element);
+					return;
+				}
+			};
+			let furthest_block = self.open_elements[furthest_block_index];
+			let common_ancestor = self.open_elements[formatting_stack_index - 1];
+			let mut bookmark = self.formatting_index(formatting_element).unwrap();
+
+			let mut node_index = furthest_block_index;
+			let mut last_node = furthest_block;
+			let mut inner_loop_counter = 0;
+			loop {
+				inner_loop_counter += 1;
+				node_index -= 1;
+				let mut node = self.open_elements[node_index];
+				if node == formatting_element {
+					break;
+				}
+				let mut formatting_index = self.formatting_index(node);
+				if inner_loop_counter > 3 {
+					if let Some(i) = formatting_index {
+						self.active_formatting.remove(i);
+						if i < bookmark {
+							bookmark -= 1;
+						}
+						formatting_index = None;
+					}
+				}
+				let formatting_index = match formatting_index {
+					Some(i) => i,
+					None => {
+						self.open_elements.remove(node_index);
+						continue;
+					}
+				};
+
+				// Replace the node with a copy
+				let tag = match self.active_formatting[formatting_index] {
+					Formatting::Element(_, ref tag) => tag.clone(),
+					Formatting::Marker => unreachable!(),
+				};
+				let copy = self.create_element(tag.clone(), dom::HTML_NAMESPACE);
+				self.active_formatting[formatting_index] = Formatting::Element(copy, tag);
+				self.open_elements[node_index] = copy;
+				node = copy;
+
+				if last_node == furthest_block {
+					bookmark = formatting_index + 1;
+				}
+				self.append(last_node, node);
+				last_node = node;
+			}
+
+			let (parent, before) = self.appropriate_place(Some(common_ancestor));
+			self.insert_at(last_node, parent, before);
+
+			let tag =
+				match self.active_formatting[self.formatting_index(formatting_element).unwrap()] {
+					Formatting::Element(_, ref tag) => tag.clone(),
+					Formatting::Marker => unreachable!(),
+				};
+			let copy = self.create_element(tag.clone(), dom::HTML_NAMESPACE);
+			while let Some(child) = self.document.first_child(furthest_block) {
+				self.append(child, copy);
+			}
+			self.append(copy, furthest_block);
+
+			let formatting_index = self.formatting_index(formatting_element).unwrap();
+			self.active_formatting.remove(formatting_index);
+			if formatting_index < bookmark {
+				bookmark -= 1;
+			}
+			self.active_formatting
+				.insert(bookmark, Formatting::Element(copy, tag));
+
+			self.remove_from_stack(formatting_element);
+			let furthest_block_index = self
+				.open_elements
+				.iter()
+				.position(|&node| node == furthest_block)
+				.unwrap();
+			self.open_elements.insert(furthest_block_index + 1, copy);
+		}
+	}
+
+	// Token processing
+
+	fn process(&mut self, token: Token) {
+		if self.ignore_next_line_feed {
+			self.ignore_next_line_feed = false;
+			if token == Token::Character('\n') {
+				return;
+			}
+		}
+		if self.is_foreign_content(&token) {
+			self.in_foreign_content(token);
+		} else {
+			let mode = self.mode;
+			self.process_in(mode, token);
+		}
+	}
+
+	// Should the token be handled by the rules for SVG and MathML, rather than the insertion mode?
+	// https://html.spec.whatwg.org/multipage/parsing.html#tree-construction-dispatcher
+	fn is_foreign_content(&self, token: &Token) -> bool {
+		let node = match self.adjusted_current_node() {
+			Some(node) if self.namespace(node) != Some(dom::HTML_NAMESPACE) => node,
+			_ => return false,
+		};
+		match *token {
+			Token::StartTag(ref tag)
+				if self.is_mathml_text_integration_point(node)
+					&& tag.name != "mglyph"
+					&& tag.name != "malignmark" =>
+			{
+				false
+			}
+			Token::Character(_) if self.is_mathml_text_integration_point(node) => false,
+			Token::StartTag(ref tag)
+				if tag.name == "svg"
+					&& self.is_named(node, dom::MATHML_NAMESPACE, &["annotation-xml"]) =>
+			{
+				false
+			}
+			Token::StartTag(_) | Token::Character(_) if self.is_html_integration_point(node) => {
+				false
+			}
+			Token::Eof => false,
+			_ => true,
+		}
+	}
+
+	fn process_in(&mut self, mode: InsertionMode, token: Token) {
+		match mode {
+			InsertionMode::Initial => self.initial(token),
+			InsertionMode::BeforeHtml => self.before_html(token),
+			InsertionMode::BeforeHead => self.before_head(token),
+			InsertionMode::InHead => self.in_head(token),
+			InsertionMode::InHeadNoscript => self.in_head_noscript(token),
+			InsertionMode::AfterHead => self.after_head(token),
+			InsertionMode::InBody => self.in_body(token),
+			InsertionMode::Text => self.text(token),
+			InsertionMode::InTable => self.in_table(token),
+			InsertionMode::InTableText => self.in_table_text(token),
+			InsertionMode::InCaption => self.in_caption(token),
+			InsertionMode::InColumnGroup => self.in_column_group(token),
+			InsertionMode::InTableBody => self.in_table_body(token),
+			InsertionMode::InRow => self.in_row(token),
+			InsertionMode::InCell => self.in_cell(token),
+			InsertionMode::InSelect => self.in_select(token),
+			InsertionMode::InSelectInTable => self.in_select_in_table(token),
+			InsertionMode::InTemplate => self.in_template(token),
+			InsertionMode::AfterBody => self.after_body(token),
+			InsertionMode::InFrameset => self.in_frameset(token),
+			InsertionMode::AfterFrameset => self.after_frameset(token),
+			InsertionMode::AfterAfterBody => self.after_after_body(token),
+			InsertionMode::AfterAfterFrameset => self.after_after_frameset(token),
+		}
+	}
+
+	fn reprocess_in(&mut self, mode: InsertionMode, token: Token) {
+		self.mode = mode;
+		self.process_in(mode, token);
+	}
+
+	// Parse the contents of an element as text, up to its end tag
+	fn parse_text_element(&mut self, tag: Tag, state: tokenizer::State) {
+		self.insert_element(tag);
+		self.tokenizer.state = state;
+		self.original_mode = self.mode;
+		self.mode = InsertionMode::Text;
+	}
+
+	fn initial(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => {}
+			Token::Comment(comment) => self.insert_document_comment(comment),
+			Token::Doctype(doctype) => {
+				let legacy_compat = doctype.system_id.as_deref() == Some("about:legacy-compat");
+				if doctype.name.as_deref() != Some("html")
+					|| doctype.public_id.is_some()
+					|| (doctype.system_id.is_some() && !legacy_compat)
+				{
+					self.error("unknown-doctype");
+				}
+				let doctype_type = dom::DocumentType {
+					name: doctype.name.unwrap_or_default(),
+					public_id: doctype.public_id,
+					system_id: doctype.system_id,
+				};
+				self.document.mode = quirks::quirks_mode(Some(&doctype_type), doctype.force_quirks);
+				let node = self.document.create_node(dom::doctype(
+					doctype_type.name,
+					doctype_type.public_id,
+					doctype_type.system_id,
+				));
+				let document_node = self.document.document_node();
+				self.append(node, document_node);
+				self.mode = InsertionMode::BeforeHtml;
+			}
+			token => {
+				self.error("missing-doctype");
+				self.document.mode = dom::QuirksMode::Quirks;
+				self.reprocess_in(InsertionMode::BeforeHtml, token);
+			}
+		}
+	}
+
+	fn before_html(&mut self, token: Token) {
+		match token {
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::Comment(comment) => self.insert_document_comment(comment),
+			Token::Character(c) if is_whitespace(c) => {}
+			Token::StartTag(tag) if tag.name == "html" => {
+				let node = self.create_element(tag, dom::HTML_NAMESPACE);
+				let document_node = self.document.document_node();
+				self.append(node, document_node);
+				self.open_elements.push(node);
+				self.mode = InsertionMode::BeforeHead;
+			}
+			Token::EndTag(ref tag) if !is_one_of(&tag.name, &["head", "body", "html", "br"]) => {
+				self.error("unexpected-end-tag");
+			}
+			token => {
+				let node = self.create_element(Tag::new("html"), dom::HTML_NAMESPACE);
+				let document_node = self.document.document_node();
+				self.append(node, document_node);
+				self.open_elements.push(node);
+				self.reprocess_in(InsertionMode::BeforeHead, token);
+			}
+		}
+	}
+
+	fn before_head(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => {}
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(tag) if tag.name == "head" => {
+				self.head = Some(self.insert_element(tag));
+				self.mode = InsertionMode::InHead;
+			}
+			Token::EndTag(ref tag) if !is_one_of(&tag.name, &["head", "body", "html", "br"]) => {
+				self.error("unexpected-end-tag");
+			}
+			token => {
+				self.head = Some(self.insert_element_named("head"));
+				self.reprocess_in(InsertionMode::InHead, token);
+			}
+		}
+	}
+
+	fn in_head(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(tag)
+				if is_one_of(&tag.name, &["base", "basefont", "bgsound", "link", "meta"]) =>
+			{
+				self.insert_void_element(tag)
+			}
+			Token::StartTag(tag) if tag.name == "title" => {
+				self.parse_text_element(tag, tokenizer::State::Rcdata)
+			}
+			Token::StartTag(tag)
+				if tag.name == "noframes"
+					|| tag.name == "style"
+					|| (tag.name == "noscript" && self.scripting) =>
+			{
+				self.parse_text_element(tag, tokenizer::State::Rawtext)
+			}
+			Token::StartTag(tag) if tag.name == "noscript" => {
+				self.insert_element(tag);
+				self.mode = InsertionMode::InHeadNoscript;
+			}
+			Token::StartTag(tag) if tag.name == "script" => {
+				self.parse_text_element(tag, tokenizer::State::ScriptData)
+			}
+			Token::EndTag(ref tag) if tag.name == "head" => {
+				self.open_elements.pop();
+				self.mode = InsertionMode::AfterHead;
+			}
+			Token::StartTag(tag) if tag.name == "template" => {
+				self.insert_element(tag);
+				self.active_formatting.push(Formatting::Marker);
+				self.frameset_ok = false;
+				self.mode = InsertionMode::InTemplate;
+				self.template_modes.push(InsertionMode::InTemplate);
+			}
+			Token::EndTag(ref tag) if tag.name == "template" => {
+				if !self.has_open("template") {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.generate_all_implied_end_tags_thoroughly();
+				if self.current_name() != "template" {
+					self.error("unexpected-end-tag");
+				}
+				self.pop_until_named("template");
+				self.clear_active_formatting_to_last_marker();
+				self.template_modes.pop();
+				self.reset_insertion_mode();
+			}
+			Token::StartTag(ref tag) if tag.name == "head" => self.error("unexpected-start-tag"),
+			Token::EndTag(ref tag) if !is_one_of(&tag.name, &["body", "html", "br"]) => {
+				self.error("unexpected-end-tag")
+			}
+			token => {
+				self.open_elements.pop();
+				self.reprocess_in(InsertionMode::AfterHead, token);
+			}
+		}
+	}
+
+	fn in_head_noscript(&mut self, token: Token) {
+		match token {
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::EndTag(ref tag) if tag.name == "noscript" => {
+				self.open_elements.pop();
+				self.mode = InsertionMode::InHead;
+			}
+			Token::Character(c) if is_whitespace(c) => self.in_head(token),
+			Token::Comment(_) => self.in_head(token),
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&["basefont", "bgsound", "link", "meta", "noframes", "style"],
+				) =>
+			{
+				self.in_head(token)
+			}
+			Token::StartTag(ref tag) if tag.name == "head" || tag.name == "noscript" => {
+				self.error("unexpected-start-tag")
+			}
+			Token::EndTag(ref tag) if tag.name != "br" => self.error("unexpected-end-tag"),
+			token => {
+				self.error("unexpected-token-in-noscript");
+				self.open_elements.pop();
+				self.reprocess_in(InsertionMode::InHead, token);
+			}
+		}
+	}
+
+	fn after_head(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(tag) if tag.name == "body" => {
+				self.insert_element(tag);
+				self.frameset_ok = false;
+				self.mode = InsertionMode::InBody;
+			}
+			Token::StartTag(tag) if tag.name == "frameset" => {
+				self.insert_element(tag);
+				self.mode = InsertionMode::InFrameset;
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"base", "basefont", "bgsound", "link", "meta", "noframes", "script",
+						"style", "template", "title",
+					],
+				) =>
+			{
+				self.error("unexpected-start-tag");
+				let head = self.head.expect("the head has been created");
+				self.open_elements.push(head);
+				self.in_head(token);
+				self.remove_from_stack(head);
+			}
+			Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::StartTag(ref tag) if tag.name == "head" => self.error("unexpected-start-tag"),
+			Token::EndTag(ref tag) if !is_one_of(&tag.name, &["body", "html", "br"]) => {
+				self.error("unexpected-end-tag")
+			}
+			token => {
+				self.insert_element_named("body");
+				self.reprocess_in(InsertionMode::InBody, token);
+			}
+		}
+	}
+
+	fn in_body(&mut self, token: Token) {
+		match token {
+			// CDATA sections are only recognised in foreign content, and are just text elsewhere
+			Token::CData(data) => {
+				for c in data.chars() {
+					self.in_body(Token::Character(c));
+				}
+			}
+			Token::Character('\0') => self.error("unexpected-null-character"),
+			Token::Character(c) => {
+				self.reconstruct_active_formatting_elements();
+				self.insert_character(c);
+				if !is_whitespace(c) {
+					self.frameset_ok = false;
+				}
+			}
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(tag) if tag.name == "html" => {
+				self.error("unexpected-start-tag");
+				if !self.has_open("template") {
+					let html = self.open_elements[0];
+					self.merge_attributes(html, tag);
+				}
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"base", "basefont", "bgsound", "link", "meta", "noframes", "script",
+						"style", "template", "title",
+					],
+				) =>
+			{
+				self.in_head(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::StartTag(tag) if tag.name == "body" => {
+				self.error("unexpected-start-tag");
+				if self.open_elements.len() > 1
+					&& self.name(self.open_elements[1]) == "body"
+					&& !self.has_open("template")
+				{
+					self.frameset_ok = false;
+					let body = self.open_elements[1];
+					self.merge_attributes(body, tag);
+				}
+			}
+			Token::StartTag(tag) if tag.name == "frameset" => {
+				self.error("unexpected-start-tag");
+				if self.open_elements.len() > 1
+					&& self.name(self.open_elements[1]) == "body"
+					&& self.frameset_ok
+				{
+					let body = self.open_elements[1];
+					self.document.detach(body);
+					self.open_elements.truncate(1);
+					self.insert_element(tag);
+					self.mode = InsertionMode::InFrameset;
+				}
+			}
+			Token::Eof => {
+				if !self.template_modes.is_empty() {
+					self.in_template(Token::Eof);
+				}
+			}
+			Token::EndTag(ref tag) if tag.name == "body" || tag.name == "html" => {
+				if !self.in_scope("body", Scope::Default) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.mode = InsertionMode::AfterBody;
+				if tag.name == "html" {
+					self.after_body(token);
+				}
+			}
+			Token::StartTag(tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"address",
+						"article",
+						"aside",
+						"blockquote",
+						"center",
+						"details",
+						"dialog",
+						"dir",
+						"div",
+						"dl",
+						"fieldset",
+						"figcaption",
+						"figure",
+						"footer",
+						"header",
+						"hgroup",
+						"main",
+						"menu",
+						"nav",
+						"ol",
+						"p",
+						"search",
+						"section",
+						"summary",
+						"ul",
+					],
+				) =>
+			{
+				self.close_p_element_in_button_scope();
+				self.insert_element(tag);
+			}
+			Token::StartTag(tag) if is_one_of(&tag.name, HEADINGS) => {
+				self.close_p_element_in_button_scope();
+				if is_one_of(self.current_name(), HEADINGS) {
+					self.error("unexpected-start-tag");
+					self.open_elements.pop();
+				}
+				self.insert_element(tag);
+			}
+			Token::StartTag(tag) if tag.name == "pre" || tag.name == "listing" => {
+				self.close_p_element_in_button_scope();
+				self.insert_element(tag);
+				self.ignore_next_line_feed = true;
+				self.frameset_ok = false;
+			}
+			Token::StartTag(tag) if tag.name == "form" => {
+				let in_template = self.has_open("template");
+				if self.form.is_some() && !in_template {
+					self.error("unexpected-start-tag");
+					return;
+				}
+				self.close_p_element_in_button_scope();
+				let node = self.insert_element(tag);
+				if !in_template {
+					self.form = Some(node);
+				}
+			}
+			Token::StartTag(tag) if is_one_of(&tag.name, &["li", "dd", "dt"]) => {
+				self.frameset_ok = false;
+				let closes: &[&str] = if tag.name == "li" {
+					&["li"]
+				} else {
+					&["dd", "dt"]
+				};
+				for i in (0..self.open_elements.len()).rev() {
+					let name = self.name(self.open_elements[i]).to_string();
+					if is_one_of(&name, closes) {
+						self.generate_implied_end_tags_except(&name);
+						if self.current_name() != name {
+							self.error("unexpected-start-tag");
+						}
+						self.pop_until_named(&name);
+						break;
+					}
+					if self.is_special(self.open_elements[i])
+						&& !is_one_of(&name, &["address", "div", "p"])
+					{
+						break;
+					}
+				}
+				self.close_p_element_in_button_scope();
+				self.insert_element(tag);
+			}
+			Token::StartTag(tag) if tag.name == "plaintext" => {
+				self.close_p_element_in_button_scope();
+				self.insert_element(tag);
+				self.tokenizer.state = tokenizer::State::Plaintext;
+			}
+			Token::StartTag(tag) if tag.name == "button" => {
+				if self.in_scope("button", Scope::Default) {
+					self.error("unexpected-start-tag");
+					self.generate_implied_end_tags();
+					self.pop_until_named("button");
+				}
+				self.reconstruct_active_formatting_elements();
+				self.insert_element(tag);
+				self.frameset_ok = false;
+			}
+			Token::EndTag(tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"address",
+						"article",
+						"aside",
+						"blockquote",
+						"button",
+						"center",
+						"details",
+						"dialog",
+						"dir",
+						"div",
+						"dl",
+						"fieldset",
+						"figcaption",
+						"figure",
+						"footer",
+						"header",
+						"hgroup",
+						"listing",
+						"main",
+						"menu",
+						"nav",
+						"ol",
+						"pre",
+						"search",
+						"section",
+						"summary",
+						"ul",
+					],
+				) =>
+			{
+				if !self.in_scope(&tag.name, Scope::Default) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.close_element(&tag.name);
+			}
+			Token::EndTag(ref tag) if tag.name == "form" => {
+				if self.has_open("template") {
+					if !self.in_scope("form", Scope::Default) {
+						self.error("unexpected-end-tag");
+						return;
+					}
+					self.close_element("form");
+					return;
+				}
+				let node = self.form.take();
+				let node = match node {
+					Some(node) if self.in_scope_where(Scope::Default, |open| open == node) => node,
+					_ => {
+						self.error("unexpected-end-tag");
+						return;
+					}
+				};
+				self.generate_implied_end_tags();
+				if self.current_node() != node {
+					self.error("unexpected-end-tag");
+				}
+				self.remove_from_stack(node);
+			}
+			Token::EndTag(ref tag) if tag.name == "p" => {
+				if !self.in_scope("p", Scope::Button) {
+					self.error("unexpected-end-tag");
+					self.insert_element_named("p");
+				}
+				self.close_p_element();
+			}
+			Token::EndTag(ref tag) if tag.name == "li" => {
+				if !self.in_scope("li", Scope::ListItem) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.generate_implied_end_tags_except("li");
+				if self.current_name() != "li" {
+					self.error("unexpected-end-tag");
+				}
+				self.pop_until_named("li");
+			}
+			Token::EndTag(tag) if tag.name == "dd" || tag.name == "dt" => {
+				if !self.in_scope(&tag.name, Scope::Default) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.generate_implied_end_tags_except(&tag.name);
+				if self.current_name() != tag.name {
+					self.error("unexpected-end-tag");
+				}
+				self.pop_until_named(&tag.name);
+			}
+			Token::EndTag(tag) if is_one_of(&tag.name, HEADINGS) => {
+				if !self.any_in_scope(HEADINGS, Scope::Default) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.generate_implied_end_tags();
+				if self.current_name() != tag.name {
+					self.error("unexpected-end-tag");
+				}
+				self.pop_until(|name| is_one_of(name, HEADINGS));
+			}
+			Token::StartTag(tag) if tag.name == "a" => {
+				if let Some(a) = self.formatting_element_named("a") {
+					self.error("unexpected-start-tag");
+					self.adoption_agency("a");
+					self.remove_formatting_element(a);
+					self.remove_from_stack(a);
+				}
+				self.reconstruct_active_formatting_elements();
+				let node = self.insert_element(tag.clone());
+				self.push_formatting_element(node, tag);
+			}
+			Token::StartTag(tag) if is_one_of(&tag.name, FORMATTING_ELEMENTS) => {
+				self.reconstruct_active_formatting_elements();
+				if tag.name == "nobr" && self.in_scope("nobr", Scope::Default) {
+					self.error("unexpected-start-tag");
+					self.adoption_agency("nobr");
+					self.reconstruct_active_formatting_elements();
+				}
+				let node = self.insert_element(tag.clone());
+				self.push_formatting_element(node, tag);
+			}
+			Token::EndTag(tag) if is_one_of(&tag.name, FORMATTING_ELEMENTS) => {
+				self.adoption_agency(&tag.name)
+			}
+			Token::StartTag(tag) if is_one_of(&tag.name, &["applet", "marquee", "object"]) => {
+				self.reconstruct_active_formatting_elements();
+				self.insert_element(tag);
+				self.active_formatting.push(Formatting::Marker);
+				self.frameset_ok = false;
+			}
+			Token::EndTag(tag) if is_one_of(&tag.name, &["applet", "marquee", "object"]) => {
+				if !self.in_scope(&tag.name, Scope::Default) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.close_element(&tag.name);
+				self.clear_active_formatting_to_last_marker();
+			}
+			Token::StartTag(tag) if tag.name == "table" => {
+				if self.document.mode != dom::QuirksMode::Quirks {
+					self.close_p_element_in_button_scope();
+				}
+				self.insert_element(tag);
+				self.frameset_ok = false;
+				self.mode = InsertionMode::InTable;
+			}
+			Token::EndTag(ref tag) if tag.name == "br" => {
+				self.error("unexpected-end-tag");
+				self.in_body(Token::StartTag(Tag::new("br")));
+			}
+			Token::StartTag(tag)
+				if is_one_of(
+					&tag.name,
+					&["area", "br", "embed", "img", "keygen", "wbr", "input"],
+				) =>
+			{
+				self.reconstruct_active_formatting_elements();
+				let hidden = tag.name == "input"
+					&& tag
+						.attribute("type")
+						.is_some_and(|kind| kind.eq_ignore_ascii_case("hidden"));
+				self.insert_void_element(tag);
+				if !hidden {
+					self.frameset_ok = false;
+				}
+			}
+			Token::StartTag(tag) if is_one_of(&tag.name, &["param", "source", "track"]) => {
+				self.insert_void_element(tag)
+			}
+			Token::StartTag(tag) if tag.name == "hr" => {
+				self.close_p_element_in_button_scope();
+				self.insert_void_element(tag);
+				self.frameset_ok = false;
+			}
+			Token::StartTag(mut tag) if tag.name == "image" => {
+				self.error("unexpected-start-tag");
+				tag.name = "img".to_string();
+				self.in_body(Token::StartTag(tag));
+			}
+			Token::StartTag(tag) if tag.name == "textarea" => {
+				self.ignore_next_line_feed = true;
+				self.frameset_ok = false;
+				self.parse_text_element(tag, tokenizer::State::Rcdata);
+			}
+			Token::StartTag(tag) if tag.name == "xmp" => {
+				self.close_p_element_in_button_scope();
+				self.reconstruct_active_formatting_elements();
+				self.frameset_ok = false;
+				self.parse_text_element(tag, tokenizer::State::Rawtext);
+			}
+			Token::StartTag(tag) if tag.name == "iframe" => {
+				self.frameset_ok = false;
+				self.parse_text_element(tag, tokenizer::State::Rawtext);
+			}
+			Token::StartTag(tag)
+				if tag.name == "noembed" || (tag.name == "noscript" && self.scripting) =>
+			{
+				self.parse_text_element(tag, tokenizer::State::Rawtext)
+			}
+			Token::StartTag(mut tag) if tag.name == "math" || tag.name == "svg" => {
+				self.reconstruct_active_formatting_elements();
+				let namespace = if tag.name == "math" {
+					foreign::adjust_mathml_attributes(&mut tag);
+					dom::MATHML_NAMESPACE
+				} else {
+					foreign::adjust_svg_attributes(&mut tag);
+					dom::SVG_NAMESPACE
+				};
+				let self_closing = tag.self_closing;
+				self.insert_foreign_element(tag, namespace);
+				if self_closing {
+					self.open_elements.pop();
+				}
+			}
+			Token::StartTag(tag) if tag.name == "select" => {
+				self.reconstruct_active_formatting_elements();
+				self.insert_element(tag);
+				self.frameset_ok = false;
+				self.mode = match self.mode {
+					InsertionMode::InTable
+					| InsertionMode::InCaption
+					| InsertionMode::InTableBody
+					| InsertionMode::InRow
+					| InsertionMode::InCell => InsertionMode::InSelectInTable,
+					_ => InsertionMode::InSelect,
+				};
+			}
+			Token::StartTag(tag) if tag.name == "optgroup" || tag.name == "option" => {
+				if self.current_name() == "option" {
+					self.open_elements.pop();
+				}
+				self.reconstruct_active_formatting_elements();
+				self.insert_element(tag);
+			}
+			Token::StartTag(tag) if tag.name == "rb" || tag.name == "rtc" => {
+				if self.in_scope("ruby", Scope::Default) {
+					self.generate_implied_end_tags();
+					if self.current_name() != "ruby" {
+						self.error("unexpected-start-tag");
+					}
+				}
+				self.insert_element(tag);
+			}
+			Token::StartTag(tag) if tag.name == "rp" || tag.name == "rt" => {
+				if self.in_scope("ruby", Scope::Default) {
+					self.generate_implied_end_tags_except("rtc");
+					if self.current_name() != "ruby" && self.current_name() != "rtc" {
+						self.error("unexpected-start-tag");
+					}
+				}
+				self.insert_element(tag);
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"caption", "col", "colgroup", "frame", "head", "tbody", "td", "tfoot",
+						"th", "thead", "tr",
+					],
+				) =>
+			{
+				self.error("unexpected-start-tag")
+			}
+			Token::StartTag(tag) => {
+				self.reconstruct_active_formatting_elements();
+				self.insert_element(tag);
+			}
+			Token::EndTag(tag) => self.any_other_end_tag(&tag.name),
+		}
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inbody:anything-else-2
+	fn any_other_end_tag(&mut self, name: &str) {
+		for i in (0..self.open_elements.len()).rev() {
+			let node = self.open_elements[i];
+			if self.name(node) == name {
+				self.generate_implied_end_tags_except(name);
+				if self.current_node() != node {
+					self.error("unexpected-end-tag");
+				}
+				self.open_elements.truncate(i);
+				return;
+			}
+			if self.is_special(node) {
+				self.error("unexpected-end-tag");
+				return;
+			}
+		}
+	}
+
+	fn text(&mut self, token: Token) {
+		match token {
+			Token::Character(c) => self.insert_character(c),
+			Token::Eof => {
+				self.error("eof-in-element-that-can-contain-only-text");
+				self.open_elements.pop();
+				let mode = self.original_mode;
+				self.reprocess_in(mode, token);
+			}
+			_ => {
+				self.open_elements.pop();
+				self.mode = self.original_mode;
+			}
+		}
+	}
+
+	fn in_table(&mut self, token: Token) {
+		match token {
+			Token::Character(_)
+				if is_one_of(
+					self.current_name(),
+					&["table", "tbody", "template", "tfoot", "thead", "tr"],
+				) =>
+			{
+				self.pending_table_text.clear();
+				self.original_mode = self.mode;
+				self.reprocess_in(InsertionMode::InTableText, token);
+			}
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(tag) if tag.name == "caption" => {
+				self.clear_stack_back_to_table_context();
+				self.active_formatting.push(Formatting::Marker);
+				self.insert_element(tag);
+				self.mode = InsertionMode::InCaption;
+			}
+			Token::StartTag(tag) if tag.name == "colgroup" => {
+				self.clear_stack_back_to_table_context();
+				self.insert_element(tag);
+				self.mode = InsertionMode::InColumnGroup;
+			}
+			Token::StartTag(ref tag) if tag.name == "col" => {
+				self.clear_stack_back_to_table_context();
+				self.insert_element_named("colgroup");
+				self.reprocess_in(InsertionMode::InColumnGroup, token);
+			}
+			Token::StartTag(tag) if is_one_of(&tag.name, &["tbody", "tfoot", "thead"]) => {
+				self.clear_stack_back_to_table_context();
+				self.insert_element(tag);
+				self.mode = InsertionMode::InTableBody;
+			}
+			Token::StartTag(ref tag) if is_one_of(&tag.name, &["td", "th", "tr"]) => {
+				self.clear_stack_back_to_table_context();
+				self.insert_element_named("tbody");
+				self.reprocess_in(InsertionMode::InTableBody, token);
+			}
+			Token::StartTag(ref tag) if tag.name == "table" => {
+				self.error("unexpected-start-tag");
+				if self.in_scope("table", Scope::Table) {
+					self.pop_until_named("table");
+					self.reset_insertion_mode();
+					let mode = self.mode;
+					self.process_in(mode, token);
+				}
+			}
+			Token::EndTag(ref tag) if tag.name == "table" => {
+				if !self.in_scope("table", Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.pop_until_named("table");
+				self.reset_insertion_mode();
+			}
+			Token::EndTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"body", "caption", "col", "colgroup", "html", "tbody", "td", "tfoot", "th",
+						"thead", "tr",
+					],
+				) =>
+			{
+				self.error("unexpected-end-tag")
+			}
+			Token::StartTag(ref tag) if is_one_of(&tag.name, &["style", "script", "template"]) => {
+				self.in_head(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::StartTag(tag)
+				if tag.name == "input"
+					&& tag
+						.attribute("type")
+						.is_some_and(|kind| kind.eq_ignore_ascii_case("hidden")) =>
+			{
+				self.error("unexpected-start-tag");
+				self.insert_void_element(tag);
+			}
+			Token::StartTag(tag) if tag.name == "form" => {
+				self.error("unexpected-start-tag");
+				if !self.has_open("template") && self.form.is_none() {
+					self.form = Some(self.insert_element(tag));
+					self.open_elements.pop();
+				}
+			}
+			Token::Eof => self.in_body(token),
+			token => {
+				self.error("unexpected-token-in-table");
+				self.foster_parenting = true;
+				self.in_body(token);
+				self.foster_parenting = false;
+			}
+		}
+	}
+
+	fn in_table_text(&mut self, token: Token) {
+		match token {
+			Token::Character('\0') => self.error("unexpected-null-character"),
+			Token::Character(c) => self.pending_table_text.push(c),
+			token => {
+				let text = mem::take(&mut self.pending_table_text);
+				if text.chars().any(|c| !is_whitespace(c)) {
+					self.error("unexpected-character-in-table");
+					self.foster_parenting = true;
+					for c in text.chars() {
+						self.in_body(Token::Character(c));
+					}
+					self.foster_parenting = false;
+				} else {
+					for c in text.chars() {
+						self.insert_character(c);
+					}
+				}
+				let mode = self.original_mode;
+				self.reprocess_in(mode, token);
+			}
+		}
+	}
+
+	// Close the caption, which is known to be in table scope
+	fn close_caption(&mut self) {
+		self.generate_implied_end_tags();
+		if self.current_name() != "caption" {
+			self.error("unexpected-end-tag");
+		}
+		self.pop_until_named("caption");
+		self.clear_active_formatting_to_last_marker();
+		self.mode = InsertionMode::InTable;
+	}
+
+	fn in_caption(&mut self, token: Token) {
+		match token {
+			Token::EndTag(ref tag) if tag.name == "caption" => {
+				if !self.in_scope("caption", Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.close_caption();
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"caption", "col", "colgroup", "tbody", "td", "tfoot", "th", "thead", "tr",
+					],
+				) =>
+			{
+				if !self.in_scope("caption", Scope::Table) {
+					self.error("unexpected-start-tag");
+					return;
+				}
+				self.close_caption();
+				self.in_table(token);
+			}
+			Token::EndTag(ref tag) if tag.name == "table" => {
+				if !self.in_scope("caption", Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.close_caption();
+				self.in_table(token);
+			}
+			Token::EndTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"body", "col", "colgroup", "html", "tbody", "td", "tfoot", "th", "thead",
+						"tr",
+					],
+				) =>
+			{
+				self.error("unexpected-end-tag")
+			}
+			token => self.in_body(token),
+		}
+	}
+
+	fn in_column_group(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(tag) if tag.name == "col" => self.insert_void_element(tag),
+			Token::EndTag(ref tag) if tag.name == "colgroup" => {
+				if self.current_name() != "colgroup" {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.open_elements.pop();
+				self.mode = InsertionMode::InTable;
+			}
+			Token::EndTag(ref tag) if tag.name == "col" => self.error("unexpected-end-tag"),
+			Token::StartTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::Eof => self.in_body(token),
+			token => {
+				if self.current_name() != "colgroup" {
+					self.error("unexpected-token-in-column-group");
+					return;
+				}
+				self.open_elements.pop();
+				self.reprocess_in(InsertionMode::InTable, token);
+			}
+		}
+	}
+
+	fn in_table_body(&mut self, token: Token) {
+		match token {
+			Token::StartTag(tag) if tag.name == "tr" => {
+				self.clear_stack_back_to_table_body_context();
+				self.insert_element(tag);
+				self.mode = InsertionMode::InRow;
+			}
+			Token::StartTag(ref tag) if tag.name == "th" || tag.name == "td" => {
+				self.error("unexpected-start-tag");
+				self.clear_stack_back_to_table_body_context();
+				self.insert_element_named("tr");
+				self.reprocess_in(InsertionMode::InRow, token);
+			}
+			Token::EndTag(ref tag) if is_one_of(&tag.name, &["tbody", "tfoot", "thead"]) => {
+				if !self.in_scope(&tag.name, Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.clear_stack_back_to_table_body_context();
+				self.open_elements.pop();
+				self.mode = InsertionMode::InTable;
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&["caption", "col", "colgroup", "tbody", "tfoot", "thead"],
+				) =>
+			{
+				self.close_table_body(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "table" => self.close_table_body(token),
+			Token::EndTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"body", "caption", "col", "colgroup", "html", "td", "th", "tr",
+					],
+				) =>
+			{
+				self.error("unexpected-end-tag")
+			}
+			token => self.in_table(token),
+		}
+	}
+
+	// Close the current table section and reprocess the token in the table
+	fn close_table_body(&mut self, token: Token) {
+		if !self.any_in_scope(&["tbody", "thead", "tfoot"], Scope::Table) {
+			self.error("unexpected-token-in-table-body");
+			return;
+		}
+		self.clear_stack_back_to_table_body_context();
+		self.open_elements.pop();
+		self.reprocess_in(InsertionMode::InTable, token);
+	}
+
+	fn in_row(&mut self, token: Token) {
+		match token {
+			Token::StartTag(tag) if tag.name == "th" || tag.name == "td" => {
+				self.clear_stack_back_to_table_row_context();
+				self.insert_element(tag);
+				self.mode = InsertionMode::InCell;
+				self.active_formatting.push(Formatting::Marker);
+			}
+			Token::EndTag(ref tag) if tag.name == "tr" => {
+				if !self.in_scope("tr", Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.clear_stack_back_to_table_row_context();
+				self.open_elements.pop();
+				self.mode = InsertionMode::InTableBody;
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"caption", "col", "colgroup", "tbody", "tfoot", "thead", "tr",
+					],
+				) =>
+			{
+				self.close_row(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "table" => self.close_row(token),
+			Token::EndTag(ref tag) if is_one_of(&tag.name, &["tbody", "tfoot", "thead"]) => {
+				if !self.in_scope(&tag.name, Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.close_row(token);
+			}
+			Token::EndTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&["body", "caption", "col", "colgroup", "html", "td", "th"],
+				) =>
+			{
+				self.error("unexpected-end-tag")
+			}
+			token => self.in_table(token),
+		}
+	}
+
+	// Close the current row and reprocess the token in the table body
+	fn close_row(&mut self, token: Token) {
+		if !self.in_scope("tr", Scope::Table) {
+			self.error("unexpected-token-in-table-row");
+			return;
+		}
+		self.clear_stack_back_to_table_row_context();
+		self.open_elements.pop();
+		self.reprocess_in(InsertionMode::InTableBody, token);
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#close-the-cell
+	fn close_cell(&mut self) {
+		self.generate_implied_end_tags();
+		if self.current_name() != "td" && self.current_name() != "th" {
+			self.error("unexpected-end-tag");
+		}
+		self.pop_until(|name| name == "td" || name == "th");
+		self.clear_active_formatting_to_last_marker();
+		self.mode = InsertionMode::InRow;
+	}
+
+	fn in_cell(&mut self, token: Token) {
+		match token {
+			Token::EndTag(tag) if tag.name == "td" || tag.name == "th" => {
+				if !self.in_scope(&tag.name, Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.generate_implied_end_tags();
+				if self.current_name() != tag.name {
+					self.error("unexpected-end-tag");
+				}
+				self.pop_until_named(&tag.name);
+				self.clear_active_formatting_to_last_marker();
+				self.mode = InsertionMode::InRow;
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"caption", "col", "colgroup", "tbody", "td", "tfoot", "th", "thead", "tr",
+					],
+				) =>
+			{
+				if !self.any_in_scope(&["td", "th"], Scope::Table) {
+					self.error("unexpected-start-tag");
+					return;
+				}
+				self.close_cell();
+				self.in_row(token);
+			}
+			Token::EndTag(ref tag)
+				if is_one_of(&tag.name, &["body", "caption", "col", "colgroup", "html"]) =>
+			{
+				self.error("unexpected-end-tag")
+			}
+			Token::EndTag(ref tag)
+				if is_one_of(&tag.name, &["table", "tbody", "tfoot", "thead", "tr"]) =>
+			{
+				if !self.in_scope(&tag.name, Scope::Table) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.close_cell();
+				self.in_row(token);
+			}
+			token => self.in_body(token),
+		}
+	}
+
+	fn in_select(&mut self, token: Token) {
+		match token {
+			Token::CData(data) => {
+				for c in data.chars() {
+					self.in_select(Token::Character(c));
+				}
+			}
+			Token::Character('\0') => self.error("unexpected-null-character"),
+			Token::Character(c) => self.insert_character(c),
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(tag) if tag.name == "option" => {
+				if self.current_name() == "option" {
+					self.open_elements.pop();
+				}
+				self.insert_element(tag);
+			}
+			Token::StartTag(tag) if tag.name == "optgroup" || tag.name == "hr" => {
+				if self.current_name() == "option" {
+					self.open_elements.pop();
+				}
+				if self.current_name() == "optgroup" {
+					self.open_elements.pop();
+				}
+				if tag.name == "hr" {
+					self.insert_void_element(tag);
+				} else {
+					self.insert_element(tag);
+				}
+			}
+			Token::EndTag(ref tag) if tag.name == "optgroup" => {
+				let len = self.open_elements.len();
+				if self.current_name() == "option"
+					&& len > 1 && self.name(self.open_elements[len - 2]) == "optgroup"
+				{
+					self.open_elements.pop();
+				}
+				if self.current_name() == "optgroup" {
+					self.open_elements.pop();
+				} else {
+					self.error("unexpected-end-tag");
+				}
+			}
+			Token::EndTag(ref tag) if tag.name == "option" => {
+				if self.current_name() == "option" {
+					self.open_elements.pop();
+				} else {
+					self.error("unexpected-end-tag");
+				}
+			}
+			Token::EndTag(ref tag) if tag.name == "select" => {
+				if !self.in_scope("select", Scope::Select) {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.pop_until_named("select");
+				self.reset_insertion_mode();
+			}
+			Token::StartTag(ref tag) if tag.name == "select" => {
+				self.error("unexpected-start-tag");
+				if self.in_scope("select", Scope::Select) {
+					self.pop_until_named("select");
+					self.reset_insertion_mode();
+				}
+			}
+			Token::StartTag(ref tag) if is_one_of(&tag.name, &["input", "keygen", "textarea"]) => {
+				self.error("unexpected-start-tag");
+				if self.in_scope("select", Scope::Select) {
+					self.pop_until_named("select");
+					self.reset_insertion_mode();
+					let mode = self.mode;
+					self.process_in(mode, token);
+				}
+			}
+			Token::StartTag(ref tag) if tag.name == "script" || tag.name == "template" => {
+				self.in_head(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::Eof => self.in_body(token),
+			Token::StartTag(_) => self.error("unexpected-start-tag"),
+			Token::EndTag(_) => self.error("unexpected-end-tag"),
+		}
+	}
+
+	fn in_select_in_table(&mut self, token: Token) {
+		const TABLE_ELEMENTS: &[&str] = &[
+			"caption", "table", "tbody", "tfoot", "thead", "tr", "td", "th",
+		];
+		match token {
+			Token::StartTag(ref tag) if is_one_of(&tag.name, TABLE_ELEMENTS) => {
+				self.error("unexpected-start-tag");
+				self.pop_until_named("select");
+				self.reset_insertion_mode();
+				let mode = self.mode;
+				self.process_in(mode, token);
+			}
+			Token::EndTag(ref tag) if is_one_of(&tag.name, TABLE_ELEMENTS) => {
+				self.error("unexpected-end-tag");
+				if self.in_scope(&tag.name, Scope::Table) {
+					self.pop_until_named("select");
+					self.reset_insertion_mode();
+					let mode = self.mode;
+					self.process_in(mode, token);
+				}
+			}
+			token => self.in_select(token),
+		}
+	}
+
+	fn in_template(&mut self, token: Token) {
+		match token {
+			Token::Character(_) | Token::CData(_) | Token::Comment(_) | Token::Doctype(_) => {
+				self.in_body(token)
+			}
+			Token::StartTag(ref tag)
+				if is_one_of(
+					&tag.name,
+					&[
+						"base", "basefont", "bgsound", "link", "meta", "noframes", "script",
+						"style", "template", "title",
+					],
+				) =>
+			{
+				self.in_head(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "template" => self.in_head(token),
+			Token::StartTag(ref tag) => {
+				let mode = match tag.name.as_str() {
+					"caption" | "colgroup" | "tbody" | "tfoot" | "thead" => InsertionMode::InTable,
+					"col" => InsertionMode::InColumnGroup,
+					"tr" => InsertionMode::InTableBody,
+					"td" | "th" => InsertionMode::InRow,
+					_ => InsertionMode::InBody,
+				};
+				self.template_modes.pop();
+				self.template_modes.push(mode);
+				self.reprocess_in(mode, token);
+			}
+			Token::EndTag(_) => self.error("unexpected-end-tag"),
+			Token::Eof => {
+				if !self.has_open("template") {
+					return;
+				}
+				self.error("eof-in-template");
+				self.pop_until_named("template");
+				self.clear_active_formatting_to_last_marker();
+				self.template_modes.pop();
+				self.reset_insertion_mode();
+				let mode = self.mode;
+				self.process_in(mode, token);
+			}
+		}
+	}
+
+	fn after_body(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => self.in_body(token),
+			Token::Comment(comment) => {
+				let html = self.open_elements[0];
+				self.insert_comment(comment, Some(html));
+			}
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::EndTag(ref tag) if tag.name == "html" => {
+				if self.context.is_some() {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.mode = InsertionMode::AfterAfterBody
+			}
+			Token::Eof => {}
+			token => {
+				self.error("unexpected-token-after-body");
+				self.reprocess_in(InsertionMode::InBody, token);
+			}
+		}
+	}
+
+	fn in_frameset(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(tag) if tag.name == "frameset" => {
+				self.insert_element(tag);
+			}
+			Token::EndTag(ref tag) if tag.name == "frameset" => {
+				if self.open_elements.len() == 1 {
+					self.error("unexpected-end-tag");
+					return;
+				}
+				self.open_elements.pop();
+				if self.context.is_none() && self.current_name() != "frameset" {
+					self.mode = InsertionMode::AfterFrameset;
+				}
+			}
+			Token::StartTag(tag) if tag.name == "frame" => self.insert_void_element(tag),
+			Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+			Token::Eof => {
+				if self.open_elements.len() != 1 {
+					self.error("eof-in-frameset");
+				}
+			}
+			_ => self.error("unexpected-token-in-frameset"),
+		}
+	}
+
+	fn after_frameset(&mut self, token: Token) {
+		match token {
+			Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::EndTag(ref tag) if tag.name == "html" => {
+				self.mode = InsertionMode::AfterAfterFrameset
+			}
+			Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+			Token::Eof => {}
+			_ => self.error("unexpected-token-after-frameset"),
+		}
+	}
+
+	fn after_after_body(&mut self, token: Token) {
+		match token {
+			Token::Comment(comment) => self.insert_document_comment(comment),
+			Token::Doctype(_) => self.in_body(token),
+			Token::Character(c) if is_whitespace(c) => self.in_body(token),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::Eof => {}
+			token => {
+				self.error("unexpected-token-after-body");
+				self.reprocess_in(InsertionMode::InBody, token);
+			}
+		}
+	}
+
+	fn after_after_frameset(&mut self, token: Token) {
+		match token {
+			Token::Comment(comment) => self.insert_document_comment(comment),
+			Token::Doctype(_) => self.in_body(token),
+			Token::Character(c) if is_whitespace(c) => self.in_body(token),
+			Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+			Token::StartTag(ref tag) if tag.name == "noframes" => self.in_head(token),
+			Token::Eof => {}
+			_ => self.error("unexpected-token-after-frameset"),
+		}
+	}
+
+	// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign
+	fn in_foreign_content(&mut self, token: Token) {
+		match token {
+			Token::Character('\0') => {
+				self.error("unexpected-null-character");
+				self.insert_character('\u{FFFD}');
+			}
+			Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+			Token::Character(c) => {
+				self.insert_character(c);
+				self.frameset_ok = false;
+			}
+			Token::CData(data) => {
+				if !data.chars().all(is_whitespace) {
+					self.frameset_ok = false;
+				}
+				let (parent, before) = self.appropriate_place(None);
+				let node = self.document.create_node(dom::cdata(data));
+				self.insert_at(node, parent, before);
+			}
+			Token::Comment(comment) => self.insert_comment(comment, None),
+			Token::Doctype(_) => self.error("unexpected-doctype"),
+			Token::StartTag(ref tag)
+				if is_one_of(&tag.name, BREAKOUT_ELEMENTS)
+					|| (tag.name == "font"
+						&& ["color", "face", "size"]
+							.iter()
+							.any(|name| tag.attribute(name).is_some())) =>
+			{
+				self.break_out_of_foreign_content(token)
+			}
+			Token::EndTag(ref tag) if tag.name == "br" || tag.name == "p" => {
+				self.break_out_of_foreign_content(token)
+			}
+			Token::StartTag(mut tag) => {
+				let namespace = self
+					.adjusted_current_node()
+					.and_then(|node| self.namespace(node))
+					.expect("foreign content is inside an element");
+				if namespace == dom::MATHML_NAMESPACE {
+					foreign::adjust_mathml_attributes(&mut tag);
+				} else if namespace == dom::SVG_NAMESPACE {
+					foreign::adjust_svg_tag_name(&mut tag);
+					foreign::adjust_svg_attributes(&mut tag);
+				}
+				let self_closing = tag.self_closing;
+				self.insert_foreign_element(tag, namespace);
+				if self_closing {
+					self.open_elements.pop();
+				}
+			}
+			Token::EndTag(tag) => {
+				let mut index = self.open_elements.len() - 1;
+				if !self.has_foreign_name(self.open_elements[index], &tag.name) {
+					self.error("unexpected-end-tag");
+				}
+				while index > 0 {
+					let node = self.open_elements[index];
+					if self.has_foreign_name(node, &tag.name) {
+						self.open_elements.truncate(index);
+						return;
+					}
+					index -= 1;
+					if self.namespace(self.open_elements[index]) == Some(dom::HTML_NAMESPACE) {
+						let mode = self.mode;
+						return self.process_in(mode, Token::EndTag(tag));
+					}
+				}
+			}
+			Token::Eof => {
+				let mode = self.mode;
+				self.process_in(mode, token);
+			}
+		}
+	}
+
+	// Does an element's name match an end tag's, ignoring case?
+	fn has_foreign_name(&self, node: NodeId, name: &str) -> bool {
+		self.document
+			.element(node)
+			.is_some_and(|data| data.tag_name.eq_ignore_ascii_case(name))
+	}
+
+	// Close the SVG and MathML elements an HTML tag can't be inside of, then handle it as HTML
+	fn break_out_of_foreign_content(&mut self, token: Token) {
+		self.error("unexpected-html-element-in-foreign-content");
+		while let Some(&node) = self.open_elements.last() {
+			if self.namespace(node) == Some(dom::HTML_NAMESPACE)
+				|| self.is_mathml_text_integration_point(node)
+				|| self.is_html_integration_point(node)
+			{
+				break;
+			}
+			self.open_elements.pop();
+		}
+		let mode = self.mode;
+		self.process_in(mode, token);
+	}
+}
+
+#[cfg(test)]
+mod tree_builder_tests {
+	use super::*;
+
+	// A compact outline of a node's children, like `p(a) div(b)`. Template contents are in square
+	// brackets, like `template[p(a)]`
+	fn outline(document: &dom::Document, parent: NodeId) -> String {
+		let parts: Vec<String> = document
+			.children(parent)
+			.map(|id| match document.node(id).node_type {
+				dom::NodeType::Element(ref data) => match document.template_contents(id) {
+					Some(contents) => format!("{}[{}]", data.tag_name, outline(document, contents)),
+					None if document.first_child(id).is_none() => data.tag_name.clone(),
+					None => format!("{}({})", data.tag_name, outline(document, id)),
+				},
+				dom::NodeType::Text(ref text) => text.clone(),
+				dom::NodeType::Comment(ref comment) => format!("<!--{}-->", comment),
+				dom::NodeType::Doctype(ref doctype) => format!("<!DOCTYPE {}>", doctype.name),
+				dom::NodeType::CData(ref data) => format!("<![CDATA[{}]]>", data),
+				dom::NodeType::ProcessingInstruction {
+					ref target,
+					ref data,
+				} => format!("<?{} {}>", target, data),
+				dom::NodeType::Document | dom::NodeType::DocumentFragment => {
+					unreachable!("documents and fragments are never children")
+				}
+			})
+			.collect();
+		parts.join(" ")
+	}
+
+	// The body element of a document
+	fn find_body(document: &dom::Document) -> NodeId {
+		let html = document.root().unwrap();
+		document.children(html).nth(1).unwrap()
+	}
+
+	fn body(input: &str) -> String {
+		let (document, _) = TreeBuilder::new(input).build();
+		outline(&document, find_body(&document))
+	}
+
+	#[test]
+	fn creates_implied_elements() {
+		let (document, errors) = TreeBuilder::new("hello").build();
+		assert_eq!(document.mode, dom::QuirksMode::Quirks);
+		assert_eq!(errors[0].kind, ParseErrorKind::Spec("missing-doctype"));
+		assert_eq!(
+			outline(&document, document.document_node()),
+			"html(head body(hello))"
+		);
+	}
+
+	#[test]
+	fn keeps_the_doctype_and_comments() {
+		let (document, errors) =
+			TreeBuilder::new("<!DOCTYPE html><!--a--><title>x</title><!--b-->").build();
+		assert!(errors.is_empty());
+		assert_eq!(document.mode, dom::QuirksMode::NoQuirks);
+		assert_eq!(
+			outline(&document, document.document_node()),
+			"<!DOCTYPE html> <!--a--> html(head(title(x) <!--b-->) body)"
+		);
+	}
+
+	#[test]
+	fn closes_paragraphs_implicitly() {
+		assert_eq!(body("<p>a<p>b<div>c</div>"), "p(a) p(b) div(c)");
+		assert_eq!(body("<ul><li>a<li>b</ul>"), "ul(li(a) li(b))");
+	}
+
+	#[test]
+	fn reparents_misnested_formatting_elements() {
+		assert_eq!(body("<b>1<p>2</b>3</p>"), "b(1) p(b(2) 3)");
+		assert_eq!(body("<a>1<a>2"), "a(1) a(2)");
+	}
+
+	#[test]
+	fn foster_parents_content_out_of_tables() {
+		assert_eq!(
+			body("<table>a<tr><td>b</table>"),
+			"a table(tbody(tr(td(b))))"
+		);
+	}
+
+	#[test]
+	fn parses_raw_text_elements() {
+		assert_eq!(
+			body("<textarea>\n<b>&amp;</textarea><script>a<b</script>"),
+			"textarea(<b>&) script(a<b)"
+		);
+	}
+
+	#[test]
+	fn puts_svg_and_mathml_in_their_namespaces() {
+		let (document, _) = TreeBuilder::new(
+			"<svg viewbox='0 0 1 1' xlink:href=a><foreignobject><p>x</p></foreignobject></svg><math><mi>y",
+		)
+		.build();
+		let body_id = find_body(&document);
+		let svg = document
+			.element(document.first_child(body_id).unwrap())
+			.unwrap();
+		assert!(svg.is_in(dom::SVG_NAMESPACE));
+		assert_eq!(svg.attributes["viewBox"], "0 0 1 1");
+		assert_eq!(
+			svg.attribute_namespace("xlink:href"),
+			Some(dom::XLINK_NAMESPACE)
+		);
+		assert_eq!(
+			outline(&document, body_id),
+			"svg(foreignObject(p(x))) math(mi(y))"
+		);
+
+		// HTML which can't be inside SVG closes it
+		assert_eq!(body("<svg><g><p>a</p></g></svg>"), "svg(g) p(a)");
+	}
+
+	#[test]
+	fn keeps_cdata_sections_in_foreign_content() {
+		assert_eq!(body("<svg><![CDATA[a<b]]></svg>"), "svg(<![CDATA[a<b]]>)");
+		// Outside foreign content it's a bogus comment
+		assert_eq!(body("<p><![CDATA[x]]></p>"), "p(<!--[CDATA[x]]-->)");
+	}
+
+	#[test]
+	fn keeps_template_contents_out_of_the_tree() {
+		assert_eq!(
+			body("<p>a</p><template><td>b</td><style>p {}</style></template>"),
+			"p(a) template[td(b) style(p {})]"
+		);
+		assert_eq!(
+			body("<table><template><tr><td>a</template></table>"),
+			"table(template[tr(td(a))])"
+		);
+	}
+}
@@ -1,4 +1,5 @@
-use style::{Display, StyledNode};
+use dom::NodeType;
+use style::{self, Display, StyledNode, WhiteSpace};
 
 // CSS box model. All sizes are in px.
 
@@ -12,6 +13,12 @@ pub struct Dimensions {
 	pub padding: EdgeSizes,
 }
 
+impl Default for Dimensions {
+	fn default() -> Dimensions {
+		Dimensions::new()
+	}
+}
+
 impl Dimensions {
 	pub fn new() -> Dimensions {
 		Dimensions {
@@ -30,6 +37,12 @@ pub struct Rect {
 	pub height: f32,
 }
 
+impl Default for Rect {
+	fn default() -> Rect {
+		Rect::new()
+	}
+}
+
 impl Rect {
 	pub fn new() -> Rect {
 		Rect {
@@ -48,6 +61,12 @@ pub struct EdgeSizes {
 	pub bottom: f32,
 }
 
+impl Default for EdgeSizes {
+	fn default() -> EdgeSizes {
+		EdgeSizes::new()
+	}
+}
+
 impl EdgeSizes {
 	pub fn new() -> EdgeSizes {
 		EdgeSizes {
@@ -92,6 +111,87 @@ impl<'a> LayoutBox<'a> {
 			}
 		}
 	}
+
+	// The text of the box as it's rendered, like `innerText`. Boxes with `display: none` aren't
+	// in the layout tree, so their text is left out. Whitespace is collapsed the way
+	// `white-space` says, and blocks and `<br>`s start new lines.
+	// https://html.spec.whatwg.org/multipage/dom.html#the-innertext-idl-attribute
+	pub fn inner_text(&self) -> String {
+		let mut writer = TextWriter::default();
+		self.write_text(&mut writer);
+		writer.text
+	}
+
+	fn write_text(&self, writer: &mut TextWriter) {
+		let is_block = match self.box_type {
+			BoxType::BlockNode(_) | BoxType::AnonymousBlock => true,
+			BoxType::InlineNode(_) => false,
+		};
+		if is_block {
+			writer.end_line();
+		}
+		if let BoxType::BlockNode(styled) | BoxType::InlineNode(styled) = self.box_type {
+			match styled.node.node_type {
+				NodeType::Text(ref text) | NodeType::CData(ref text) => {
+					writer.push(text, styled.white_space())
+				}
+				NodeType::Element(ref data) if data.is_html() && data.tag_name == "br" => {
+					writer.line_break()
+				}
+				_ => {}
+			}
+		}
+		for child in &self.children {
+			child.write_text(writer);
+		}
+		if is_block {
+			writer.end_line();
+		}
+	}
+}
+
+// Builds up the text of a layout tree. Line breaks at the ends of blocks are only written once
+// more text follows, so there are none at the start or end, and never more than one in a row.
+#[derive(Default)]
+struct TextWriter {
+	text: String,
+	ending_line: bool,
+
+	// Were spaces collapsed in the last text written? If so, spaces at the end of it are dropped
+	// before a line break, but preformatted ones are kept.
+	collapsible_end: bool,
+}
+
+impl TextWriter {
+	fn end_line(&mut self) {
+		self.ending_line = !self.text.is_empty();
+	}
+
+	fn line_break(&mut self) {
+		self.ending_line = false;
+		if self.collapsible_end {
+			let trimmed = self.text.trim_end_matches(' ').len();
+			self.text.truncate(trimmed);
+		}
+		self.text.push('\n');
+	}
+
+	fn push(&mut self, text: &str, white_space: WhiteSpace) {
+		let mut text = &*style::collapse_whitespace(text, white_space);
+		let at_line_start =
+			self.ending_line || self.text.is_empty() || self.text.ends_with([' ', '\n']);
+		if white_space.collapses_spaces() && at_line_start {
+			text = text.trim_start_matches(' ');
+		}
+		if text.is_empty() {
+			return;
+		}
+		if self.ending_line {
+			self.line_break();
+		}
+		self.text.push_str(text);
+		self.collapsible_end = white_space.collapses_spaces();
+	}
 }
 
 pub enum BoxType<'a> {
@@ -110,7 +210,11 @@ pub fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
 	});
 
 	// Create the descendant boxes.
-	for child in &style_node.children {
+	for (i, child) in style_node.children.iter().enumerate() {
+		// Whitespace between blocks isn't rendered
+		if child.is_collapsible_whitespace() && !is_between_inlines(&style_node.children, i) {
+			continue;
+		}
 		match child.display() {
 			Display::Block => root.children.push(build_layout_tree(child)),
 			Display::Inline => root
@@ -123,3 +227,72 @@ pub fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
 
 	root
 }
+
+// Are the siblings either side of `children[index]` both laid out inline?
+fn is_between_inlines(children: &[StyledNode], index: usize) -> bool {
+	let is_inline = |child: &&StyledNode| match child.display() {
+		Display::Inline => Some(true),
+		Display::Block => Some(false),
+		Display::None => None,
+	};
+	let before = children[..index].iter().rev().find_map(|c| is_inline(&c));
+	let after = children[index + 1..].iter().find_map(|c| is_inline(&c));
+	before == Some(true) && after == Some(true)
+}
+
+#[cfg(test)]
+mod layout_tests {
+	use super::*;
+	use css;
+	use dom::Document;
+	use html;
+	use style;
+
+	fn parse(source: &str) -> Document {
+		html::parse_document(source.into()).unwrap().0
+	}
+
+	#[test]
+	fn skips_whitespace_between_blocks() {
+		let document = parse("<div> <p>a</p> <b>x</b> <i>y</i> <p>b</p> </div>");
+		let stylesheets =
+			vec![css::parse("div { display: block; } p { display: block; }".into()).unwrap()];
+		let styled = style::style_tree(&document, document.root().unwrap(), &stylesheets);
+		let layout = build_layout_tree(&styled);
+
+		// The first paragraph, the inline content with the space between it, and the last
+		let inline_children: Vec<usize> = layout
+			.children
+			.iter()
+			.map(|child| child.children.len())
+			.collect();
+		assert_eq!(inline_children, vec![1, 3, 1]);
+	}
+
+	#[test]
+	fn gets_rendered_text() {
+		let document = parse(
+			"<div> <p> a  <b>b</b> </p><p>c<br>d<span>e</span></p>f<i>hidden</i>\
+			 <pre> g\n  h</pre><!--comment--> </div>",
+		);
+		let stylesheets = vec![css::parse(
+			"div { display: block; } p { display: block; } pre { display: block; white-space: pre; }
+			i { display: none; }"
+				.into(),
+		)
+		.unwrap()];
+		let inner_text = |document: &Document| {
+			let styled = style::style_tree(document, document.root().unwrap(), &stylesheets);
+			build_layout_tree(&styled).inner_text()
+		};
+		assert_eq!(inner_text(&document), "a b\nc\nde\nf\n g\n  h");
+
+		// A non-breaking space is text, not collapsible whitespace
+		let document = parse("<div><b>a</b>&nbsp;<p>x</p></div>");
+		assert_eq!(inner_text(&document), "a\u{A0}\nx");
+
+		// Preformatted spaces are kept at the ends of lines
+		let document = parse("<pre>a  <br>b <p>c </p></pre>");
+		assert_eq!(inner_text(&document), "a  \nb \nc ");
+	}
+}
@@ -2,7 +2,9 @@ extern crate num;
 
 pub mod css;
 pub mod dom;
+pub mod encoding;
 pub mod html;
 pub mod layout;
 pub mod parser;
 pub mod style;
+pub mod xml;
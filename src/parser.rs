@@ -1,39 +1,182 @@
 use num::Num;
+use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 
+// A location in the input. `line` and `column` are 1-based, `column` counts characters
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct LinePos {
 	pub pos: usize,
 	pub line: usize,
 	pub column: usize,
 }
 
+// The region of the input a node, rule or declaration was parsed from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+	pub start: LinePos,
+	pub end: LinePos,
+}
+
+// The category of a parse failure
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+	// Input ended before the construct being parsed was complete
+	UnexpectedEof,
+	// A character other than the expected one was found
+	UnexpectedChar,
+	// A closing tag did not match the element it was supposed to close
+	MismatchedTag,
+	// A numeric literal could not be parsed
+	InvalidNumber,
+	// An element had the same attribute more than once
+	DuplicateAttribute,
+	// A doctype appeared somewhere other than the start of the document
+	MisplacedDoctype,
+	// A CSS namespace prefix was used without an `@namespace` rule declaring it
+	UndeclaredNamespace,
+	// An XML entity reference to an entity which was never declared
+	UndefinedEntity,
+	// XML entities nested too deeply or expanding to too much text
+	EntityLimit,
+	// A parse error defined by the HTML standard, identified by its code (e.g. `eof-in-tag`)
+	Spec(&'static str),
+}
+
+// A recoverable parse failure, with enough context to point at the bad input
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+	pub kind: ParseErrorKind,
+	// Byte offset into the input
+	pub pos: usize,
+	pub line: usize,
+	pub column: usize,
+	pub expected: Option<String>,
+	pub found: Option<String>,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let description = match self.kind {
+			ParseErrorKind::UnexpectedEof => "Unexpected end of input",
+			ParseErrorKind::UnexpectedChar => "Unexpected character",
+			ParseErrorKind::MismatchedTag => "Mismatched closing tag",
+			ParseErrorKind::InvalidNumber => "Invalid number",
+			ParseErrorKind::DuplicateAttribute => "Duplicate attribute",
+			ParseErrorKind::MisplacedDoctype => "Misplaced doctype",
+			ParseErrorKind::UndeclaredNamespace => "Undeclared namespace prefix",
+			ParseErrorKind::UndefinedEntity => "Undefined entity",
+			ParseErrorKind::EntityLimit => "Entity expansion limit exceeded",
+			ParseErrorKind::Spec(code) => code,
+		};
+		write!(f, "{} at {}:{}", description, self.line, self.column)?;
+		if let Some(ref expected) = self.expected {
+			write!(f, ", expected {:?}", expected)?;
+		}
+		if let Some(ref found) = self.found {
+			write!(f, ", found {:?}", found)?;
+		}
+		Ok(())
+	}
+}
+
+impl Error for ParseError {}
+
+impl ParseError {
+	// Translate an error from parsing part of a larger input, which started at `origin`, into a
+	// position in the larger input
+	pub fn relative_to(mut self, origin: LinePos) -> ParseError {
+		if self.line == 1 {
+			self.column += origin.column - 1;
+		}
+		self.line += origin.line - 1;
+		self.pos += origin.pos;
+		self
+	}
+}
+
 pub struct Parser {
 	pos: usize,
+	line: usize,
+	column: usize,
 	input: String,
 }
 
 impl Parser {
 	pub fn new(input: String) -> Parser {
-		Parser { pos: 0, input }
+		Parser {
+			pos: 0,
+			line: 1,
+			column: 1,
+			input,
+		}
 	}
 
 	// Gets the current position in the input
 	pub fn pos(&self) -> LinePos {
-		let line = self
-			.input
-			.chars()
-			.take(self.pos)
-			.fold(0, |acc, c| if c == '\n' { acc + 1 } else { acc });
-		let column = self
-			.input
-			.chars()
-			.rev()
-			.position(|c| c == '\n')
-			.unwrap_or(0);
 		LinePos {
 			pos: self.pos,
-			line,
-			column,
+			line: self.line,
+			column: self.column,
+		}
+	}
+
+	// Move back to a position previously returned by `pos`
+	pub fn rewind(&mut self, to: LinePos) {
+		self.pos = to.pos;
+		self.line = to.line;
+		self.column = to.column;
+	}
+
+	// The span from `start` up to the current position
+	pub fn span_from(&self, start: LinePos) -> Span {
+		Span {
+			start,
+			end: self.pos(),
+		}
+	}
+
+	// Build an error pointing at the current position
+	pub fn error(
+		&self,
+		kind: ParseErrorKind,
+		expected: Option<String>,
+		found: Option<String>,
+	) -> ParseError {
+		self.error_at(self.pos(), kind, expected, found)
+	}
+
+	// Build an error pointing at an earlier position
+	pub fn error_at(
+		&self,
+		pos: LinePos,
+		kind: ParseErrorKind,
+		expected: Option<String>,
+		found: Option<String>,
+	) -> ParseError {
+		ParseError {
+			kind,
+			pos: pos.pos,
+			line: pos.line,
+			column: pos.column,
+			expected,
+			found,
+		}
+	}
+
+	// Build an error describing what was expected versus what is next in the input
+	pub fn unexpected(&self, expected: &str) -> ParseError {
+		match self.peek() {
+			Some(c) => self.error(
+				ParseErrorKind::UnexpectedChar,
+				Some(expected.to_string()),
+				Some(c.to_string()),
+			),
+			None => self.error(
+				ParseErrorKind::UnexpectedEof,
+				Some(expected.to_string()),
+				None,
+			),
 		}
 	}
 
@@ -42,11 +185,33 @@ impl Parser {
 		self.input[self.pos..].chars().next().unwrap()
 	}
 
+	// Read the next character without consuming it, or `None` at the end of input
+	pub fn peek(&self) -> Option<char> {
+		self.input[self.pos..].chars().next()
+	}
+
+	// The input which hasn't been consumed yet
+	pub fn rest(&self) -> &str {
+		&self.input[self.pos..]
+	}
+
+	// Read the character `n` characters ahead without consuming anything
+	pub fn peek_nth(&self, n: usize) -> Option<char> {
+		self.input[self.pos..].chars().nth(n)
+	}
+
 	// Do the next characters start with a given string?
 	pub fn starts_with(&self, s: &str) -> bool {
 		self.input[self.pos..].starts_with(s)
 	}
 
+	// Do the next characters start with a given string, ignoring ASCII case?
+	pub fn starts_with_ignore_case(&self, s: &str) -> bool {
+		self.input[self.pos..]
+			.get(..s.len())
+			.is_some_and(|next| next.eq_ignore_ascii_case(s))
+	}
+
 	// Return true if all input is consumed
 	pub fn eof(&self) -> bool {
 		self.pos >= self.input.len()
@@ -54,20 +219,38 @@ impl Parser {
 
 	// Return the current character, and advance self.pos to the next character
 	pub fn consume_char(&mut self) -> char {
-		let mut iter = self.input[self.pos..].char_indices();
-		let (_, cur_char) = iter.next().unwrap();
-		let (next_pos, _) = iter.next().unwrap_or((1, ' '));
-		self.pos += next_pos;
+		let cur_char = self.next_char();
+		self.pos += cur_char.len_utf8();
+		if cur_char == '\n' {
+			self.line += 1;
+			self.column = 1;
+		} else {
+			self.column += 1;
+		}
 		cur_char
 	}
 
+	// Consume the next character if it is `expected`, otherwise fail without consuming
+	pub fn expect_char(&mut self, expected: char) -> Result<char, ParseError> {
+		match self.peek() {
+			Some(c) if c == expected => Ok(self.consume_char()),
+			_ => Err(self.unexpected(&expected.to_string())),
+		}
+	}
+
 	// Return the next set of characters which form a number
-	pub fn consume_number<T>(&mut self) -> Result<T, <T as FromStr>::Err>
+	pub fn consume_number<T>(&mut self) -> Result<T, ParseError>
 	where
 		T: Num + FromStr,
 	{
-		let val = self.consume_while(|c| char::is_numeric(c));
-		val.parse()
+		let val = self.consume_while(char::is_numeric);
+		val.parse().map_err(|_| {
+			self.error(
+				ParseErrorKind::InvalidNumber,
+				Some("number".to_string()),
+				Some(val),
+			)
+		})
 	}
 
 	// Consume characters until `test` returns false
@@ -84,14 +267,85 @@ impl Parser {
 
 	// Consume and discard zero or more whitespace characters
 	pub fn consume_whitespace(&mut self) {
-		self.consume_while(char::is_whitespace);
+		self.consume_while(|c| c.is_ascii_whitespace());
 	}
 
 	// Consume and discard a specific string
-	pub fn consume_string(&mut self, val: &str) {
-		for c in val.chars() {
-			let next = self.consume_char();
-			assert!(c == next);
+	pub fn consume_string(&mut self, val: &str) -> Result<(), ParseError> {
+		if !self.starts_with(val) {
+			return Err(self.unexpected(val));
+		}
+		for _ in val.chars() {
+			self.consume_char();
 		}
+		Ok(())
+	}
+
+	// Consume and discard a specific string, ignoring ASCII case
+	pub fn consume_string_ignore_case(&mut self, val: &str) -> Result<(), ParseError> {
+		if !self.starts_with_ignore_case(val) {
+			return Err(self.unexpected(val));
+		}
+		for _ in val.chars() {
+			self.consume_char();
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod parser_tests {
+	use super::*;
+
+	#[test]
+	fn consume_string_fails_without_consuming() {
+		let mut parser = Parser::new("abd".into());
+		let err = parser.consume_string("abc").unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedChar);
+		assert_eq!(err.expected, Some("abc".to_string()));
+		assert_eq!(err.found, Some("a".to_string()));
+		assert_eq!(parser.next_char(), 'a');
+	}
+
+	#[test]
+	fn expect_char_reports_eof() {
+		let mut parser = Parser::new("".into());
+		let err = parser.expect_char('>').unwrap_err();
+		assert_eq!(err.kind, ParseErrorKind::UnexpectedEof);
+		assert_eq!(err.found, None);
+	}
+
+	#[test]
+	fn tracks_line_and_column() {
+		let mut parser = Parser::new("ab\ncé\nd".into());
+		assert_eq!(
+			parser.pos(),
+			LinePos {
+				pos: 0,
+				line: 1,
+				column: 1
+			}
+		);
+		parser.consume_string("ab\nc").unwrap();
+		assert_eq!(
+			parser.pos(),
+			LinePos {
+				pos: 4,
+				line: 2,
+				column: 2
+			}
+		);
+		parser.consume_char();
+		parser.consume_char();
+		assert_eq!(
+			parser.pos(),
+			LinePos {
+				pos: 7,
+				line: 3,
+				column: 1
+			}
+		);
+		assert_eq!(parser.consume_char(), 'd');
+		assert!(parser.eof());
 	}
 }
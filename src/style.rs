@@ -1,5 +1,6 @@
 use css::{Rule, Selector, SimpleSelector, Specificity, Stylesheet, Value};
-use dom::{ElementData, Node, NodeType};
+use dom::traversal::Event;
+use dom::{Document, ElementData, NodeData, NodeId, NodeType};
 use std::collections::HashMap;
 
 // Map from CSS property names to values.
@@ -12,9 +13,39 @@ pub enum Display {
 	None,
 }
 
+// How whitespace in text is handled
+// https://drafts.csswg.org/css-text/#white-space-property
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhiteSpace {
+	Normal,
+	Nowrap,
+	Pre,
+	PreWrap,
+	PreLine,
+}
+
+impl WhiteSpace {
+	// Are runs of spaces and tabs collapsed into a single space?
+	pub fn collapses_spaces(self) -> bool {
+		matches!(
+			self,
+			WhiteSpace::Normal | WhiteSpace::Nowrap | WhiteSpace::PreLine
+		)
+	}
+
+	// Are newlines kept as line breaks?
+	pub fn preserves_newlines(self) -> bool {
+		matches!(
+			self,
+			WhiteSpace::Pre | WhiteSpace::PreWrap | WhiteSpace::PreLine
+		)
+	}
+}
+
 // A node with associated style data.
 pub struct StyledNode<'a> {
-	pub node: &'a Node,
+	pub id: NodeId,
+	pub node: &'a NodeData,
 	pub specified_values: PropertyMap,
 	pub children: Vec<StyledNode<'a>>,
 }
@@ -22,11 +53,15 @@ pub struct StyledNode<'a> {
 impl<'a> StyledNode<'a> {
 	// Return the specified value of a property if it exists, otherwise `None`.
 	pub fn value(&self, name: &str) -> Option<Value> {
-		self.specified_values.get(name).map(|v| v.clone())
+		self.specified_values.get(name).cloned()
 	}
 
-	// The value of the `display` property (defaults to inline).
+	// The value of the `display` property (defaults to inline). Processing instructions are
+	// instructions for software, so they're never displayed.
 	pub fn display(&self) -> Display {
+		if let NodeType::ProcessingInstruction { .. } = self.node.node_type {
+			return Display::None;
+		}
 		match self.value("display") {
 			Some(Value::Keyword(s)) => match &*s {
 				"block" => Display::Block,
@@ -36,17 +71,84 @@ impl<'a> StyledNode<'a> {
 			_ => Display::Inline,
 		}
 	}
+
+	// The value of the `white-space` property (defaults to normal).
+	pub fn white_space(&self) -> WhiteSpace {
+		match self.value("white-space") {
+			Some(Value::Keyword(s)) => match &*s {
+				"nowrap" => WhiteSpace::Nowrap,
+				"pre" => WhiteSpace::Pre,
+				"pre-wrap" => WhiteSpace::PreWrap,
+				"pre-line" => WhiteSpace::PreLine,
+				_ => WhiteSpace::Normal,
+			},
+			_ => WhiteSpace::Normal,
+		}
+	}
+
+	// Is this a text node which `white-space` collapses away entirely?
+	pub fn is_collapsible_whitespace(&self) -> bool {
+		match self.node.node_type {
+			NodeType::Text(ref text) | NodeType::CData(ref text) => {
+				let white_space = self.white_space();
+				text.chars().all(|c| c.is_ascii_whitespace())
+					&& white_space.collapses_spaces()
+					&& !(white_space.preserves_newlines() && text.contains('\n'))
+			}
+			_ => false,
+		}
+	}
+}
+
+// Collapse the whitespace in some text the way `white_space` says to
+pub fn collapse_whitespace(text: &str, white_space: WhiteSpace) -> String {
+	if !white_space.collapses_spaces() {
+		return text.to_string();
+	}
+	let mut result = String::with_capacity(text.len());
+	let mut pending_space = false;
+	for c in text.chars() {
+		if c == '\n' && white_space.preserves_newlines() {
+			// Spaces around a line break are removed
+			pending_space = false;
+			result.push('\n');
+		} else if c.is_ascii_whitespace() {
+			pending_space = !result.ends_with('\n');
+		} else {
+			if pending_space {
+				result.push(' ');
+				pending_space = false;
+			}
+			result.push(c);
+		}
+	}
+	if pending_space {
+		result.push(' ');
+	}
+	result
 }
 
-fn matches(elem: &ElementData, selector: &Selector) -> bool {
+// Does the selector match the element?
+pub fn matches(elem: &ElementData, selector: &Selector) -> bool {
 	match *selector {
 		Selector::Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector),
 	}
 }
 
 fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
-	// Check type selector
-	if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
+	// Check namespace
+	if !selector.namespace.matches(elem.namespace.as_deref()) {
+		return false;
+	}
+
+	// Check type selector. HTML tag names ignore case, but SVG and MathML ones don't
+	if selector.tag_name.iter().any(|name| {
+		if elem.is_html() {
+			!elem.tag_name.eq_ignore_ascii_case(name)
+		} else {
+			elem.tag_name != *name
+		}
+	}) {
 		return false;
 	}
 
@@ -66,7 +168,7 @@ fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> boo
 	}
 
 	// We didn't find any non-matching selector components
-	return true;
+	true
 }
 
 type MatchedRule<'a> = (Specificity, &'a Rule);
@@ -77,46 +179,177 @@ fn match_rule<'a>(elem: &ElementData, rule: &'a Rule) -> Option<MatchedRule<'a>>
 	rule
 		.selectors
 		.iter()
-		.find(|selector| matches(elem, *selector))
+		.find(|selector| matches(elem, selector))
 		.map(|selector| (selector.specificity(), rule))
 }
 
-// Find all CSS rules that match the given element.
-fn matching_rules<'a>(elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
-	stylesheet
-		.rules
+// Find all CSS rules that match the given element, in the order they appear.
+fn matching_rules<'a>(elem: &ElementData, stylesheets: &'a [Stylesheet]) -> Vec<MatchedRule<'a>> {
+	stylesheets
 		.iter()
+		.flat_map(|stylesheet| stylesheet.rules.iter())
 		.filter_map(|rule| match_rule(elem, rule))
 		.collect()
 }
 
 // Apply styles to a single element, returning the specified values.
-fn specified_values(elem: &ElementData, stylesheet: &Stylesheet) -> PropertyMap {
+fn specified_values(elem: &ElementData, stylesheets: &[Stylesheet]) -> PropertyMap {
 	let mut values = HashMap::new();
-	let mut rules = matching_rules(elem, stylesheet);
+	let mut rules = matching_rules(elem, stylesheets);
 
-	// Go through the rules from lowest to highest priority
-	rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+	// Go through the rules from lowest to highest priority. The sort is stable, so later rules
+	// win between rules of the same specificity.
+	rules.sort_by_key(|&(specificity, _)| specificity);
 	for (_, rule) in rules {
 		for declaration in &rule.declarations {
 			values.insert(declaration.name.clone(), declaration.value.clone());
 		}
 	}
 
-	return values;
+	values
+}
+
+// Apply the stylesheets, in order of increasing precedence, to a node in a document and its
+// descendants
+pub fn style_tree<'a>(
+	document: &'a Document,
+	root: NodeId,
+	stylesheets: &'a [Stylesheet],
+) -> StyledNode<'a> {
+	// The nodes which have been opened but not closed yet, outermost first. Each one collects its
+	// children as they close, so deep trees don't need deep recursion.
+	let mut open: Vec<StyledNode<'a>> = Vec::new();
+	for event in document.traverse(root) {
+		match event {
+			Event::Open(id) => {
+				let white_space = open.last().and_then(|parent| parent.value("white-space"));
+				open.push(style_node(document, id, stylesheets, white_space));
+			}
+			Event::Close(_) => {
+				let styled = open.pop().expect("every node is opened before it's closed");
+				match open.last_mut() {
+					Some(parent) => parent.children.push(styled),
+					None => return styled,
+				}
+			}
+		}
+	}
+	unreachable!("the traversal closes the root")
 }
 
-pub fn style_tree<'a>(root: &'a Node, stylesheet: &'a Stylesheet) -> StyledNode<'a> {
+// Style a single node, without its children
+fn style_node<'a>(
+	document: &'a Document,
+	id: NodeId,
+	stylesheets: &'a [Stylesheet],
+	white_space: Option<Value>,
+) -> StyledNode<'a> {
+	let node = document.node(id);
+	let mut values = match node.node_type {
+		NodeType::Element(ref elem) => specified_values(elem, stylesheets),
+		NodeType::Text(_)
+		| NodeType::CData(_)
+		| NodeType::Comment(_)
+		| NodeType::Doctype(_)
+		| NodeType::Document
+		| NodeType::DocumentFragment
+		| NodeType::ProcessingInstruction { .. } => HashMap::new(),
+	};
+
+	// `white-space` is inherited, so that text knows how to treat its whitespace
+	if let Some(white_space) = white_space {
+		values
+			.entry("white-space".to_string())
+			.or_insert(white_space);
+	}
+
 	StyledNode {
-		node: root,
-		specified_values: match root.node_type {
-			NodeType::Element(ref elem) => specified_values(elem, stylesheet),
-			NodeType::Text(_) | NodeType::Comment(_) => HashMap::new(),
-		},
-		children: root
-			.children
-			.iter()
-			.map(|child| style_tree(child, stylesheet))
-			.collect(),
+		id,
+		node,
+		specified_values: values,
+		children: Vec::new(),
+	}
+}
+
+#[cfg(test)]
+mod style_tests {
+	use super::*;
+	use css;
+	use dom;
+	use html;
+
+	#[test]
+	fn collapses_whitespace() {
+		let text = " a \t b \n\n c ";
+		assert_eq!(collapse_whitespace(text, WhiteSpace::Normal), " a b c ");
+		assert_eq!(collapse_whitespace(text, WhiteSpace::Pre), text);
+		assert_eq!(collapse_whitespace(text, WhiteSpace::PreLine), " a b\n\nc ");
+
+		// Only HTML's whitespace characters collapse, not non-breaking spaces
+		let text = "a\u{A0}\u{A0}b \u{A0}";
+		assert_eq!(collapse_whitespace(text, WhiteSpace::Normal), text);
+	}
+
+	#[test]
+	fn text_inherits_white_space() {
+		let (document, _) =
+			html::parse_document("<div><pre>\n a</pre> <p> </p></div>".into()).unwrap();
+		let stylesheet = css::parse("pre { white-space: pre; }".into()).unwrap();
+		let stylesheets = vec![stylesheet];
+		let styled = style_tree(&document, document.root().unwrap(), &stylesheets);
+
+		let pre = &styled.children[0];
+		assert_eq!(pre.children[0].white_space(), WhiteSpace::Pre);
+		assert!(!pre.children[0].is_collapsible_whitespace());
+		assert!(styled.children[1].is_collapsible_whitespace());
+		assert!(styled.children[2].children[0].is_collapsible_whitespace());
+	}
+
+	#[test]
+	fn type_selectors_respect_namespaces() {
+		let (document, _) =
+			html::parse_whatwg("<A></A><svg><a></a><clipPath></clipPath></svg>".into());
+		let stylesheet = css::parse(
+			"@namespace svg url(http://www.w3.org/2000/svg);
+			a { display: block; } svg|a { display: none; } clippath { display: block; }"
+				.into(),
+		)
+		.unwrap();
+		let stylesheets = vec![stylesheet];
+		let styled = style_tree(&document, document.root().unwrap(), &stylesheets);
+		let body = &styled.children[1];
+
+		// The HTML `a` matches despite its case, but the SVG one matches `a` and `svg|a`
+		assert_eq!(
+			body.children[0].value("display"),
+			Some(Value::Keyword("block".into()))
+		);
+		let svg = &body.children[1];
+		assert_eq!(
+			svg.children[0].value("display"),
+			Some(Value::Keyword("none".into()))
+		);
+		assert_eq!(svg.children[1].value("display"), None);
+	}
+
+	#[test]
+	fn skips_template_contents() {
+		let (document, _) =
+			html::parse_document("<div><template><p>a</p></template></div>".into()).unwrap();
+		let stylesheets = vec![css::parse("p { display: block; }".into()).unwrap()];
+		let styled = style_tree(&document, document.root().unwrap(), &stylesheets);
+		assert!(styled.children[0].children.is_empty());
+	}
+
+	#[test]
+	fn hides_processing_instructions() {
+		let div = dom::elem(
+			"div".into(),
+			HashMap::new(),
+			vec![dom::processing_instruction("php".into(), "echo 1;".into())],
+		);
+		let document = Document::new(vec![div], dom::QuirksMode::NoQuirks);
+		let styled = style_tree(&document, document.root().unwrap(), &[]);
+		assert!(matches!(styled.children[0].display(), Display::None));
 	}
 }
@@ -0,0 +1,938 @@
+use dom;
+use html;
+use parser::{LinePos, ParseError, ParseErrorKind, Parser};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Parsing of XML documents, such as XHTML pages and standalone SVG files. Unlike HTML there is no
+// error recovery: anything that isn't well-formed fails the parse.
+// https://www.w3.org/TR/xml/
+// https://www.w3.org/TR/xml-names/
+
+// The entities every document has without declaring them
+static PREDEFINED_ENTITIES: &[(&str, &str)] = &[
+	("amp", "&"),
+	("apos", "'"),
+	("gt", ">"),
+	("lt", "<"),
+	("quot", "\""),
+];
+
+// Declared entities can refer to each other, so a short document can expand to an enormous one.
+// These limits stop a "billion laughs" document from using up all the memory.
+const MAX_ENTITY_DEPTH: usize = 16;
+const MAX_ENTITY_EXPANSION: usize = 1 << 20;
+
+// A general entity declared in the doctype's internal subset
+struct Entity {
+	// The replacement text, with references already expanded
+	value: String,
+	// How deeply entities are nested in the value, counting this one
+	depth: usize,
+}
+
+struct XmlParser {
+	inner: Parser,
+
+	entities: HashMap<String, Entity>,
+
+	// The bytes of text that references to declared entities have expanded to so far
+	expanded: usize,
+
+	// The namespace declarations made by each open element, outermost first. The default
+	// namespace is declared with an empty prefix, and an empty namespace undeclares it.
+	scopes: Vec<HashMap<String, String>>,
+}
+
+fn is_name_start_char(c: char) -> bool {
+	c.is_alphabetic() || c == '_' || c == ':'
+}
+
+fn is_name_char(c: char) -> bool {
+	is_name_start_char(c) || c.is_numeric() || matches!(c, '-' | '.' | '\u{B7}')
+}
+
+fn is_whitespace(c: char) -> bool {
+	matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+// Split a qualified name like `svg:rect` into its prefix and local name
+fn split_name(name: &str) -> (Option<&str>, &str) {
+	match name.split_once(':') {
+		Some((prefix, local_name)) => (Some(prefix), local_name),
+		None => (None, name),
+	}
+}
+
+impl XmlParser {
+	fn new(input: String) -> XmlParser {
+		XmlParser {
+			inner: Parser::new(input),
+			entities: HashMap::new(),
+			expanded: 0,
+			scopes: Vec::new(),
+		}
+	}
+
+	// Parse an element, attribute or entity name
+	fn parse_name(&mut self) -> Result<String, ParseError> {
+		match self.inner.peek() {
+			Some(c) if is_name_start_char(c) => Ok(self.inner.consume_while(is_name_char)),
+			_ => Err(self.inner.unexpected("name")),
+		}
+	}
+
+	// Consume whitespace which the grammar requires to be there
+	fn expect_whitespace(&mut self) -> Result<(), ParseError> {
+		match self.inner.peek() {
+			Some(c) if is_whitespace(c) => {
+				self.inner.consume_while(is_whitespace);
+				Ok(())
+			}
+			_ => Err(self.inner.unexpected("whitespace")),
+		}
+	}
+
+	// Parse a single node inside an element
+	fn parse_node(&mut self) -> Result<dom::Node, ParseError> {
+		if self.inner.starts_with("<!--") {
+			self.parse_comment()
+		} else if self.inner.starts_with("<![CDATA[") {
+			self.parse_cdata()
+		} else if self.inner.starts_with("<?") {
+			self.parse_processing_instruction()
+		} else if self.inner.starts_with("<") {
+			self.parse_element()
+		} else {
+			self.parse_text()
+		}
+	}
+
+	// Parse character data, up to the next markup
+	fn parse_text(&mut self) -> Result<dom::Node, ParseError> {
+		let start = self.inner.pos();
+		let mut text = String::new();
+		while let Some(c) = self.inner.peek() {
+			match c {
+				'<' => break,
+				'&' => text.push_str(&self.parse_reference()?.0),
+				']' if self.inner.starts_with("]]>") => {
+					return Err(self.inner.unexpected("text"));
+				}
+				'\r' => {
+					// Line endings are normalized to `\n`
+					self.inner.consume_char();
+					if self.inner.peek() != Some('\n') {
+						text.push('\n');
+					}
+				}
+				_ => text.push(self.inner.consume_char()),
+			}
+		}
+		Ok(dom::text(text).with_span(self.inner.span_from(start)))
+	}
+
+	// Parse a character reference like `&#169;` or an entity reference like `&amp;`, returning the
+	// text it stands for and how deeply declared entities are nested in it
+	fn parse_reference(&mut self) -> Result<(String, usize), ParseError> {
+		let start = self.inner.pos();
+		self.inner.expect_char('&')?;
+		if self.inner.peek() == Some('#') {
+			self.inner.consume_char();
+			let radix = if self.inner.peek() == Some('x') {
+				self.inner.consume_char();
+				16
+			} else {
+				10
+			};
+			let digits = self.inner.consume_while(|c| c.is_digit(radix));
+			self.inner.expect_char(';')?;
+			return u32::from_str_radix(&digits, radix)
+				.ok()
+				.and_then(std::char::from_u32)
+				.filter(|&c| c != '\0')
+				.map(|c| (c.to_string(), 0))
+				.ok_or_else(|| {
+					self.inner.error_at(
+						start,
+						ParseErrorKind::InvalidNumber,
+						Some("character reference".to_string()),
+						Some(digits),
+					)
+				});
+		}
+
+		let name = self.parse_name()?;
+		self.inner.expect_char(';')?;
+		if let Some(&(_, value)) = PREDEFINED_ENTITIES.iter().find(|&&(n, _)| n == name) {
+			return Ok((value.to_string(), 0));
+		}
+		let entity = match self.entities.get(&name) {
+			Some(entity) => entity,
+			None => {
+				return Err(self.inner.error_at(
+					start,
+					ParseErrorKind::UndefinedEntity,
+					None,
+					Some(name),
+				))
+			}
+		};
+		self.expanded += entity.value.len();
+		if self.expanded > MAX_ENTITY_EXPANSION {
+			return Err(self
+				.inner
+				.error_at(start, ParseErrorKind::EntityLimit, None, Some(name)));
+		}
+		Ok((entity.value.clone(), entity.depth))
+	}
+
+	// Parse an element, including its start tag, contents and end tag
+	fn parse_element(&mut self) -> Result<dom::Node, ParseError> {
+		// Start tag
+		let start = self.inner.pos();
+		self.inner.expect_char('<')?;
+		let name = self.parse_name()?;
+		let attributes = self.parse_attributes()?;
+		let self_closing = self.inner.starts_with("/>");
+		if self_closing {
+			self.inner.consume_string("/>")?;
+		} else {
+			self.inner.expect_char('>')?;
+		}
+
+		self.scopes.push(namespace_declarations(&attributes));
+		let mut node = self.create_element(&name, attributes, start)?;
+
+		// Contents and end tag
+		if !self_closing {
+			while !self.inner.starts_with("</") {
+				if self.inner.eof() {
+					return Err(self.inner.unexpected(&format!("</{}>", name)));
+				}
+				let child = self.parse_node()?;
+				node.children.push(child);
+			}
+			self.parse_end_tag(&name)?;
+		}
+		self.scopes.pop();
+
+		Ok(node.with_span(self.inner.span_from(start)))
+	}
+
+	// Work out which namespaces an element and its attributes are in, using the declarations in
+	// scope, and build it
+	fn create_element(
+		&self,
+		name: &str,
+		attributes: Vec<(String, String)>,
+		start: LinePos,
+	) -> Result<dom::Node, ParseError> {
+		let (prefix, local_name) = split_name(name);
+		let namespace = self.resolve_prefix(prefix.unwrap_or(""));
+		if prefix.is_some() && namespace.is_none() {
+			return Err(self.undeclared_prefix(start, prefix));
+		}
+
+		let mut node = dom::elem_ns(
+			namespace,
+			local_name.to_string(),
+			HashMap::new(),
+			Vec::new(),
+		);
+		if let dom::NodeType::Element(ref mut data) = node.node_type {
+			data.prefix = prefix.map(str::to_string);
+			for (name, value) in attributes {
+				// Unprefixed attributes aren't in any namespace, not even the default one
+				let namespace = match split_name(&name) {
+					_ if name == "xmlns" => Some(dom::XMLNS_NAMESPACE.to_string()),
+					(Some("xmlns"), _) => Some(dom::XMLNS_NAMESPACE.to_string()),
+					(Some(prefix), _) => match self.resolve_prefix(prefix) {
+						Some(namespace) => Some(namespace),
+						None => return Err(self.undeclared_prefix(start, Some(prefix))),
+					},
+					(None, _) => None,
+				};
+				if let Some(namespace) = namespace {
+					data.set_attribute_namespace(name.clone(), namespace);
+				}
+				data.attributes.insert(name, value);
+			}
+		}
+		Ok(node)
+	}
+
+	// The namespace a prefix is bound to by the innermost declaration of it
+	fn resolve_prefix(&self, prefix: &str) -> Option<String> {
+		if prefix == "xml" {
+			return Some(dom::XML_NAMESPACE.to_string());
+		}
+		self.scopes
+			.iter()
+			.rev()
+			.find_map(|scope| scope.get(prefix))
+			.filter(|namespace| !namespace.is_empty())
+			.cloned()
+	}
+
+	fn undeclared_prefix(&self, start: LinePos, prefix: Option<&str>) -> ParseError {
+		self.inner.error_at(
+			start,
+			ParseErrorKind::UndeclaredNamespace,
+			None,
+			prefix.map(str::to_string),
+		)
+	}
+
+	// Parse an end tag, which has to close the element that's open
+	fn parse_end_tag(&mut self, expected: &str) -> Result<(), ParseError> {
+		let start = self.inner.pos();
+		self.inner.consume_string("</")?;
+		let name = self.parse_name()?;
+		if name != expected {
+			return Err(self.inner.error_at(
+				start,
+				ParseErrorKind::MismatchedTag,
+				Some(expected.to_string()),
+				Some(name),
+			));
+		}
+		self.inner.consume_while(is_whitespace);
+		self.inner.expect_char('>')?;
+		Ok(())
+	}
+
+	// Parse the attributes of a start tag, in the order they were written
+	fn parse_attributes(&mut self) -> Result<Vec<(String, String)>, ParseError> {
+		let mut attributes: Vec<(String, String)> = Vec::new();
+		loop {
+			let had_whitespace = !self.inner.consume_while(is_whitespace).is_empty();
+			match self.inner.peek() {
+				Some('>') | None => break,
+				Some('/') if self.inner.starts_with("/>") => break,
+				_ if !had_whitespace => return Err(self.inner.unexpected("whitespace")),
+				_ => {}
+			}
+
+			let start = self.inner.pos();
+			let name = self.parse_name()?;
+			self.inner.consume_while(is_whitespace);
+			self.inner.expect_char('=')?;
+			self.inner.consume_while(is_whitespace);
+			let value = self.parse_attr_value()?;
+			if attributes.iter().any(|(n, _)| *n == name) {
+				return Err(self.inner.error_at(
+					start,
+					ParseErrorKind::DuplicateAttribute,
+					None,
+					Some(name),
+				));
+			}
+			attributes.push((name, value));
+		}
+		Ok(attributes)
+	}
+
+	// Parse a quoted attribute value. Whitespace characters are normalized to spaces.
+	// https://www.w3.org/TR/xml/#AVNormalize
+	fn parse_attr_value(&mut self) -> Result<String, ParseError> {
+		let quote = match self.inner.peek() {
+			Some(c) if c == '"' || c == '\'' => self.inner.consume_char(),
+			_ => return Err(self.inner.unexpected("quote")),
+		};
+		let mut value = String::new();
+		loop {
+			match self.inner.peek() {
+				Some(c) if c == quote => break,
+				Some('<') | None => return Err(self.inner.unexpected(&quote.to_string())),
+				Some('&') => value.push_str(&self.parse_reference()?.0),
+				Some('\r') if self.inner.starts_with("\r\n") => {
+					self.inner.consume_string("\r\n")?;
+					value.push(' ');
+				}
+				Some(c) if is_whitespace(c) => {
+					self.inner.consume_char();
+					value.push(' ');
+				}
+				Some(_) => value.push(self.inner.consume_char()),
+			}
+		}
+		self.inner.consume_char();
+		Ok(value)
+	}
+
+	// Parse a comment, which can't contain `--`
+	fn parse_comment(&mut self) -> Result<dom::Node, ParseError> {
+		let start = self.inner.pos();
+		self.inner.consume_string("<!--")?;
+		let mut contents = String::new();
+		while !self.inner.starts_with("--") {
+			if self.inner.eof() {
+				return Err(self.inner.unexpected("-->"));
+			}
+			contents.push(self.inner.consume_char());
+		}
+		self.inner.consume_string("-->")?;
+		Ok(dom::comment(contents).with_span(self.inner.span_from(start)))
+	}
+
+	fn parse_cdata(&mut self) -> Result<dom::Node, ParseError> {
+		let start = self.inner.pos();
+		self.inner.consume_string("<![CDATA[")?;
+		let data = self.consume_until("]]>")?;
+		Ok(dom::cdata(data).with_span(self.inner.span_from(start)))
+	}
+
+	// Parse a processing instruction like `<?xml-stylesheet href="a.css"?>`
+	fn parse_processing_instruction(&mut self) -> Result<dom::Node, ParseError> {
+		let start = self.inner.pos();
+		self.inner.consume_string("<?")?;
+		let target = self.parse_name()?;
+		if target.eq_ignore_ascii_case("xml") {
+			// The XML declaration looks like a processing instruction, but it can only come first
+			return Err(self.inner.error_at(
+				start,
+				ParseErrorKind::UnexpectedChar,
+				Some("processing instruction target".to_string()),
+				Some(target),
+			));
+		}
+		let data = if self.inner.starts_with("?>") {
+			self.inner.consume_string("?>")?;
+			String::new()
+		} else {
+			self.expect_whitespace()?;
+			self.consume_until("?>")?
+		};
+		let span = self.inner.span_from(start);
+		Ok(dom::processing_instruction(target, data).with_span(span))
+	}
+
+	// Consume everything up to and including `end`, returning what came before it
+	fn consume_until(&mut self, end: &str) -> Result<String, ParseError> {
+		let mut contents = String::new();
+		while !self.inner.starts_with(end) {
+			if self.inner.eof() {
+				return Err(self.inner.unexpected(end));
+			}
+			contents.push(self.inner.consume_char());
+		}
+		self.inner.consume_string(end)?;
+		Ok(contents)
+	}
+
+	// Skip the XML declaration, such as `<?xml version="1.0" encoding="UTF-8"?>`. The input has
+	// already been decoded, so there's nothing in it we need.
+	fn parse_xml_declaration(&mut self) -> Result<(), ParseError> {
+		if self.inner.starts_with("<?xml") && self.inner.peek_nth(5).is_some_and(is_whitespace) {
+			self.inner.consume_string("<?xml")?;
+			self.consume_until("?>")?;
+		}
+		Ok(())
+	}
+
+	// Parse a doctype like `<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "svg11.dtd">`, reading
+	// the entity declarations in its internal subset
+	fn parse_doctype(&mut self) -> Result<dom::Node, ParseError> {
+		let start = self.inner.pos();
+		self.inner.consume_string("<!DOCTYPE")?;
+		self.expect_whitespace()?;
+		let name = self.parse_name()?;
+		self.inner.consume_while(is_whitespace);
+		let (public_id, system_id) = self.parse_external_id()?;
+		self.inner.consume_while(is_whitespace);
+		if self.inner.peek() == Some('[') {
+			self.inner.consume_char();
+			self.parse_internal_subset()?;
+			self.inner.expect_char(']')?;
+			self.inner.consume_while(is_whitespace);
+		}
+		self.inner.expect_char('>')?;
+		let span = self.inner.span_from(start);
+		Ok(dom::doctype(name, public_id, system_id).with_span(span))
+	}
+
+	// Parse an optional `SYSTEM "system id"` or `PUBLIC "public id" "system id"`
+	fn parse_external_id(&mut self) -> Result<(Option<String>, Option<String>), ParseError> {
+		if self.inner.starts_with("SYSTEM") {
+			self.inner.consume_string("SYSTEM")?;
+			self.expect_whitespace()?;
+			Ok((None, Some(self.parse_literal()?)))
+		} else if self.inner.starts_with("PUBLIC") {
+			self.inner.consume_string("PUBLIC")?;
+			self.expect_whitespace()?;
+			let public_id = self.parse_literal()?;
+			self.expect_whitespace()?;
+			Ok((Some(public_id), Some(self.parse_literal()?)))
+		} else {
+			Ok((None, None))
+		}
+	}
+
+	// Parse a quoted string, without interpreting anything in it
+	fn parse_literal(&mut self) -> Result<String, ParseError> {
+		let quote = match self.inner.peek() {
+			Some(c) if c == '"' || c == '\'' => self.inner.consume_char(),
+			_ => return Err(self.inner.unexpected("quote")),
+		};
+		self.consume_until(&quote.to_string())
+	}
+
+	// Parse the declarations between the doctype's square brackets. Only general entity
+	// declarations are used, everything else is checked for well-formedness and skipped.
+	fn parse_internal_subset(&mut self) -> Result<(), ParseError> {
+		loop {
+			self.inner.consume_while(is_whitespace);
+			if self.inner.eof() || self.inner.peek() == Some(']') {
+				return Ok(());
+			}
+			if self.inner.starts_with("<!ENTITY") {
+				self.parse_entity_declaration()?;
+			} else if self.inner.starts_with("<!--") {
+				self.parse_comment()?;
+			} else if self.inner.starts_with("<?") {
+				self.parse_processing_instruction()?;
+			} else if self.inner.starts_with("<!") {
+				self.skip_declaration()?;
+			} else if self.inner.peek() == Some('%') {
+				// A parameter entity reference, which would pull in declarations from elsewhere
+				self.inner.consume_char();
+				self.parse_name()?;
+				self.inner.expect_char(';')?;
+			} else {
+				return Err(self.inner.unexpected("markup declaration"));
+			}
+		}
+	}
+
+	// Parse `<!ENTITY name "value">`. References in the value are expanded straight away, so
+	// entities can only refer to ones declared before them and can't recurse. The expansion counts
+	// towards the document's limits.
+	fn parse_entity_declaration(&mut self) -> Result<(), ParseError> {
+		self.inner.consume_string("<!ENTITY")?;
+		self.expect_whitespace()?;
+		let parameter = self.inner.peek() == Some('%');
+		if parameter {
+			self.inner.consume_char();
+			self.expect_whitespace()?;
+		}
+		let name = self.parse_name()?;
+		self.expect_whitespace()?;
+
+		let value = match self.inner.peek() {
+			Some('"') | Some('\'') => Some(self.parse_entity_value()?),
+			_ => {
+				// An external entity, which we don't load
+				let (_, system_id) = self.parse_external_id()?;
+				if system_id.is_none() {
+					return Err(self.inner.unexpected("entity value"));
+				}
+				self.inner.consume_while(is_whitespace);
+				if self.inner.starts_with("NDATA") {
+					self.inner.consume_string("NDATA")?;
+					self.expect_whitespace()?;
+					self.parse_name()?;
+				}
+				None
+			}
+		};
+		self.inner.consume_while(is_whitespace);
+		self.inner.expect_char('>')?;
+
+		// The first declaration of an entity is the one that counts
+		if let (false, Some(value)) = (parameter, value) {
+			if let Entry::Vacant(entry) = self.entities.entry(name) {
+				entry.insert(value);
+			}
+		}
+		Ok(())
+	}
+
+	fn parse_entity_value(&mut self) -> Result<Entity, ParseError> {
+		let quote = self.inner.consume_char();
+		let mut value = String::new();
+		let mut depth = 1;
+		loop {
+			match self.inner.peek() {
+				Some(c) if c == quote => break,
+				None => return Err(self.inner.unexpected(&quote.to_string())),
+				Some('&') => {
+					let start = self.inner.pos();
+					let (text, reference_depth) = self.parse_reference()?;
+					if reference_depth >= MAX_ENTITY_DEPTH {
+						return Err(self.inner.error_at(
+							start,
+							ParseErrorKind::EntityLimit,
+							None,
+							None,
+						));
+					}
+					depth = depth.max(reference_depth + 1);
+					value.push_str(&text);
+				}
+				Some(_) => value.push(self.inner.consume_char()),
+			}
+		}
+		self.inner.consume_char();
+		Ok(Entity { value, depth })
+	}
+
+	// Skip an element, attribute list or notation declaration
+	fn skip_declaration(&mut self) -> Result<(), ParseError> {
+		self.inner.consume_string("<!")?;
+		loop {
+			match self.inner.peek() {
+				Some('>') => {
+					self.inner.consume_char();
+					return Ok(());
+				}
+				Some('"') | Some('\'') => {
+					self.parse_literal()?;
+				}
+				Some(_) => {
+					self.inner.consume_char();
+				}
+				None => return Err(self.inner.unexpected(">")),
+			}
+		}
+	}
+
+	// Parse the comments, processing instructions and whitespace allowed around the doctype and
+	// root element
+	fn parse_misc(&mut self, nodes: &mut Vec<dom::Node>) -> Result<(), ParseError> {
+		loop {
+			self.inner.consume_while(is_whitespace);
+			if self.inner.starts_with("<!--") {
+				nodes.push(self.parse_comment()?);
+			} else if self.inner.starts_with("<?") {
+				nodes.push(self.parse_processing_instruction()?);
+			} else {
+				return Ok(());
+			}
+		}
+	}
+
+	// Parse the whole input, which has to be exactly one root element with an optional prolog,
+	// returning the nodes at the top of the document
+	fn parse_top_level(&mut self) -> Result<Vec<dom::Node>, ParseError> {
+		if self.inner.peek() == Some('\u{FEFF}') {
+			self.inner.consume_char();
+		}
+		self.parse_xml_declaration()?;
+
+		let mut children = Vec::new();
+		self.parse_misc(&mut children)?;
+		if self.inner.starts_with("<!DOCTYPE") {
+			children.push(self.parse_doctype()?);
+			self.parse_misc(&mut children)?;
+		}
+		match self.inner.peek() {
+			Some('<') => children.push(self.parse_element()?),
+			_ => return Err(self.inner.unexpected("root element")),
+		}
+		self.parse_misc(&mut children)?;
+		if !self.inner.eof() {
+			return Err(self.inner.unexpected("end of input"));
+		}
+		Ok(children)
+	}
+
+	// Parse the whole input as a document, along with what was wrong with its stylesheets
+	fn parse_document(&mut self) -> Result<(dom::Document, Vec<ParseError>), ParseError> {
+		let children = self.parse_top_level()?;
+		let mut document = dom::Document::new(children, dom::QuirksMode::NoQuirks);
+		let (stylesheets, errors) = html::collect_stylesheets(&document);
+		document.stylesheets = stylesheets;
+		Ok((document, errors))
+	}
+}
+
+// The namespaces declared by `xmlns` and `xmlns:prefix` attributes, keyed by prefix
+fn namespace_declarations(attributes: &[(String, String)]) -> HashMap<String, String> {
+	attributes
+		.iter()
+		.filter_map(|(name, value)| match split_name(name) {
+			(None, "xmlns") => Some((String::new(), value.clone())),
+			(Some("xmlns"), prefix) => Some((prefix.to_string(), value.clone())),
+			_ => None,
+		})
+		.collect()
+}
+
+// Parse a well-formed XML document, returning its root element
+pub fn parse(source: String) -> Result<dom::Node, ParseError> {
+	let children = XmlParser::new(source).parse_top_level()?;
+	Ok(children
+		.into_iter()
+		.find(dom::Node::is_element)
+		.expect("a well-formed document has a root element"))
+}
+
+// Parse a well-formed XML document, keeping its doctype and the comments and processing
+// instructions around the root element. Broken stylesheets are skipped, and what was wrong with
+// them is returned alongside the document.
+pub fn parse_document(source: String) -> Result<(dom::Document, Vec<ParseError>), ParseError> {
+	XmlParser::new(source).parse_document()
+}
+
+// The node and its descendants serialized as XML. Unlike HTML, empty elements are written as
+// `<a/>` and nothing is treated as raw text.
+pub fn outer_xml(document: &dom::Document, id: dom::NodeId) -> String {
+	let mut out = String::new();
+	write_node(document, id, &mut out);
+	out
+}
+
+pub fn document_xml(document: &dom::Document) -> String {
+	outer_xml(document, document.document_node())
+}
+
+fn write_node(document: &dom::Document, id: dom::NodeId, out: &mut String) {
+	match document.node(id).node_type {
+		dom::NodeType::Element(ref data) => {
+			let name = data.qualified_name();
+			let _ = write!(out, "<{}", name);
+			let mut attributes: Vec<_> = data.attributes.iter().collect();
+			attributes.sort();
+			for (name, value) in attributes {
+				let _ = write!(out, " {}=\"{}\"", name, escape(value, true));
+			}
+			if document.first_child(id).is_none() {
+				out.push_str("/>");
+				return;
+			}
+			out.push('>');
+			for child in document.children(id) {
+				write_node(document, child, out);
+			}
+			let _ = write!(out, "</{}>", name);
+		}
+		dom::NodeType::Text(ref text) => out.push_str(&escape(text, false)),
+		dom::NodeType::CData(ref data) => {
+			let _ = write!(out, "<![CDATA[{}]]>", data);
+		}
+		dom::NodeType::Comment(ref text) => {
+			let _ = write!(out, "<!--{}-->", text);
+		}
+		dom::NodeType::ProcessingInstruction {
+			ref target,
+			ref data,
+		} => {
+			if data.is_empty() {
+				let _ = write!(out, "<?{}?>", target);
+			} else {
+				let _ = write!(out, "<?{} {}?>", target, data);
+			}
+		}
+		dom::NodeType::Doctype(ref doctype) => {
+			let _ = write!(out, "<!DOCTYPE {}", doctype.name);
+			match (&doctype.public_id, &doctype.system_id) {
+				(Some(public_id), Some(system_id)) => {
+					let _ = write!(out, " PUBLIC \"{}\" \"{}\"", public_id, system_id);
+				}
+				(None, Some(system_id)) => {
+					let _ = write!(out, " SYSTEM \"{}\"", system_id);
+				}
+				_ => {}
+			}
+			out.push('>');
+		}
+		dom::NodeType::Document | dom::NodeType::DocumentFragment => {
+			for child in document.children(id) {
+				write_node(document, child, out);
+			}
+		}
+	}
+}
+
+fn escape(text: &str, in_attribute: bool) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' if in_attribute => escaped.push_str("&quot;"),
+			// Escaped so they aren't normalized to spaces when parsed again
+			'\t' if in_attribute => escaped.push_str("&#9;"),
+			'\n' if in_attribute => escaped.push_str("&#10;"),
+			'\r' => escaped.push_str("&#13;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+#[cfg(test)]
+mod xml_tests {
+	use super::*;
+
+	fn element(node: &dom::Node) -> &dom::ElementData {
+		match node.node_type {
+			dom::NodeType::Element(ref data) => data,
+			_ => panic!("not an element: {:?}", node),
+		}
+	}
+
+	fn error_kind(source: &str) -> ParseErrorKind {
+		parse(source.into()).unwrap_err().kind
+	}
+
+	#[test]
+	fn keeps_names_case_sensitive() {
+		let root = parse("<svg><foreignObject><Div/></foreignObject></svg>".into()).unwrap();
+		let child = &root.children[0];
+		assert_eq!(element(child).tag_name, "foreignObject");
+		assert_eq!(element(&child.children[0]).tag_name, "Div");
+		assert_eq!(element(&root).namespace, None);
+
+		assert_eq!(error_kind("<a><b></B></a>"), ParseErrorKind::MismatchedTag);
+	}
+
+	#[test]
+	fn resolves_namespace_prefixes() {
+		let root = parse(
+			"<html xmlns='http://www.w3.org/1999/xhtml' xmlns:svg='http://www.w3.org/2000/svg' \
+			 xmlns:xlink='http://www.w3.org/1999/xlink'><svg:svg><svg:use xlink:href='#a' \
+			 xml:lang='en' id='b'/></svg:svg></html>"
+				.into(),
+		)
+		.unwrap();
+		assert!(element(&root).is_html());
+		assert_eq!(
+			element(&root).attribute_namespace("xmlns:svg"),
+			Some(dom::XMLNS_NAMESPACE)
+		);
+
+		let svg = element(&root.children[0]);
+		assert!(svg.is_in(dom::SVG_NAMESPACE));
+		assert_eq!(svg.tag_name, "svg");
+		assert_eq!(svg.qualified_name(), "svg:svg");
+
+		let link = element(&root.children[0].children[0]);
+		assert_eq!(link.attributes["xlink:href"], "#a");
+		assert_eq!(
+			link.attribute_namespace("xlink:href"),
+			Some(dom::XLINK_NAMESPACE)
+		);
+		assert_eq!(
+			link.attribute_namespace("xml:lang"),
+			Some(dom::XML_NAMESPACE)
+		);
+		assert_eq!(link.attribute_namespace("id"), None);
+
+		assert_eq!(
+			error_kind("<a:b xmlns:c='x'/>"),
+			ParseErrorKind::UndeclaredNamespace
+		);
+		assert_eq!(
+			error_kind("<b xmlns:c='x' d:e='f'/>"),
+			ParseErrorKind::UndeclaredNamespace
+		);
+	}
+
+	#[test]
+	fn expands_entity_references() {
+		let (document, _) = parse_document(
+			"<!DOCTYPE p [<!ELEMENT p (#PCDATA)><!ENTITY co \"ACME &amp; Co\">]>\
+			 <p title='&lt;&#65;&#x42;&gt;'>&co; &apos;&quot;</p>"
+				.into(),
+		)
+		.unwrap();
+		assert_eq!(document.doctype().unwrap().name, "p");
+		let root = document.root().unwrap();
+		assert_eq!(document.element(root).unwrap().attributes["title"], "<AB>");
+		assert_eq!(
+			outer_xml(&document, root),
+			"<p title=\"&lt;AB&gt;\">ACME &amp; Co '\"</p>"
+		);
+
+		// HTML's named references aren't defined in XML
+		assert_eq!(error_kind("<p>&nbsp;</p>"), ParseErrorKind::UndefinedEntity);
+	}
+
+	#[test]
+	fn limits_entity_expansion() {
+		// Each entity is ten of the one before, so the last would be ten million characters
+		let mut bomb = String::from("<!DOCTYPE p [<!ENTITY a0 \"x\">");
+		for level in 1..8 {
+			let value = format!("&a{};", level - 1).repeat(10);
+			bomb.push_str(&format!("<!ENTITY a{} \"{}\">", level, value));
+		}
+		bomb.push_str("]><p>&a7;</p>");
+		assert_eq!(error_kind(&bomb), ParseErrorKind::EntityLimit);
+
+		// Expanding a small entity too many times also counts
+		let mut repeated = String::from("<!DOCTYPE p [<!ENTITY a \"");
+		repeated.push_str(&"x".repeat(1000));
+		repeated.push_str("\">]><p>");
+		repeated.push_str(&"&a;".repeat(2000));
+		repeated.push_str("</p>");
+		assert_eq!(error_kind(&repeated), ParseErrorKind::EntityLimit);
+
+		// So does nesting, even when the text stays short
+		let mut deep = String::from("<!DOCTYPE p [<!ENTITY a0 \"x\">");
+		for level in 1..20 {
+			deep.push_str(&format!("<!ENTITY a{} \"&a{};\">", level, level - 1));
+		}
+		deep.push_str("]><p>&a19;</p>");
+		assert_eq!(error_kind(&deep), ParseErrorKind::EntityLimit);
+	}
+
+	#[test]
+	fn parses_cdata_and_processing_instructions() {
+		let (document, errors) = parse_document(
+			"<?xml version=\"1.0\"?>\n<?xml-stylesheet href=\"a.css\"?><svg xmlns=\"http://www.w3.org/2000/svg\"><style><![CDATA[a { display: block; }]]></style><!-- c --></svg>"
+				.into(),
+		)
+		.unwrap();
+		let first = document.first_child(document.document_node()).unwrap();
+		assert_eq!(
+			outer_xml(&document, first),
+			"<?xml-stylesheet href=\"a.css\"?>"
+		);
+		assert_eq!(document.stylesheets.len(), 1);
+		assert!(errors.is_empty());
+		let svg = document.root().unwrap();
+		let style = document.first_child(svg).unwrap();
+		assert_eq!(
+			document
+				.node(document.first_child(style).unwrap())
+				.node_type,
+			dom::NodeType::CData("a { display: block; }".into())
+		);
+		assert_eq!(
+			document.node(document.last_child(svg).unwrap()).node_type,
+			dom::NodeType::Comment(" c ".into())
+		);
+	}
+
+	#[test]
+	fn rejects_malformed_documents() {
+		assert_eq!(error_kind("<a>"), ParseErrorKind::UnexpectedEof);
+		assert_eq!(error_kind("<a b=c/>"), ParseErrorKind::UnexpectedChar);
+		assert_eq!(error_kind("<a b/>"), ParseErrorKind::UnexpectedChar);
+		assert_eq!(
+			error_kind("<a b='1' b='2'/>"),
+			ParseErrorKind::DuplicateAttribute
+		);
+		assert_eq!(error_kind("<a/><b/>"), ParseErrorKind::UnexpectedChar);
+		assert_eq!(error_kind("text"), ParseErrorKind::UnexpectedChar);
+		assert_eq!(error_kind("<a>x & y</a>"), ParseErrorKind::UnexpectedChar);
+		assert_eq!(
+			error_kind("<a><!-- a -- b --></a>"),
+			ParseErrorKind::UnexpectedChar
+		);
+	}
+
+	#[test]
+	fn round_trips_through_the_serializer() {
+		let source = "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"svg11.dtd\"><?a b?>\
+		              <svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:x=\"urn:x\"><x:g a=\"&lt;&quot;&#10;\"/>\
+		              <text>a &amp; b<![CDATA[<c>]]></text></svg>";
+		let (document, _) = parse_document(source.into()).unwrap();
+		assert_eq!(document_xml(&document), source);
+		assert_eq!(parse_document(document_xml(&document)).unwrap().0, document);
+	}
+}